@@ -11,16 +11,92 @@ _G.host_family = ""
 
 ---@param duration integer
 ---@return nil
+---In a callback, lets the main loop run other callbacks while waiting.
+---Elsewhere it blocks.
 function sleep_ms(duration) end
 
----@param hotkey string
----@param callback function   
----@return nil
-function register_hotkey(hotkey, callback) end
+---@param vendor_id integer|nil
+---@param product_id integer|nil
+---@param timeout_ms integer|nil
+---@return boolean connected false if the timeout ran out first
+---Waits in a callback until a matching USB device connects.
+function wait_for_hotplug(vendor_id, product_id, timeout_ms) end
 
----@param callback function
+---@param name string "hotkey", "screen_edge", "hotplug" or one passed to emit_event
+---@param timeout_ms integer|nil
+---@return any ... the event's arguments, or nothing if the timeout ran out
+---Waits in a callback until the event happens.
+function wait_for_event(name, timeout_ms) end
+
+---@param name string
+---@param ... any
 ---@return nil
-function register_hotplug(hotkey, callback) end
+---Wakes the callbacks waiting for `name` on the next tick.
+function emit_event(name, ...) end
+
+---@class HotkeyHandle
+local HotkeyHandle = {}
+
+---@return boolean false if the hotkey was already unregistered, or registered again since
+function HotkeyHandle:unregister() end
+
+---@class HotkeyOptions
+---@field on "press"|"release"|"hold"|nil When the callback runs, "release" by default.
+---@field hold_ms integer|nil How long to hold with on = "hold", 500 by default.
+---@field repeat_ms integer|nil Run again this often while held, with on = "press" or "hold".
+
+---@param hotkey string
+---@param callback fun(hotkey: string, state: "pressed"|"released"|"held"|"tap"|"repeat")
+---@param opts HotkeyOptions|nil
+---@return HotkeyHandle
+---Registering the same hotkey again replaces its callback.
+function register_hotkey(hotkey, callback, opts) end
+
+---@param hotkey string
+---@return boolean false if the hotkey wasn't registered
+function unregister_hotkey(hotkey) end
+
+---@return string[]
+---Includes the leader chords of key sequences.
+function list_hotkeys() end
+
+---@class KeySequenceHandle
+local KeySequenceHandle = {}
+
+---@return boolean false if the sequence was already unregistered
+---Releases the leader chord too, once no other sequence starts with it.
+function KeySequenceHandle:unregister() end
+
+---@class KeySequenceOptions
+---@field timeout_ms integer|nil How long to wait for each next step, 1000 by default.
+
+--- Only a leader chord is grabbed, the keys after it still reach the focused app.
+---@param keys string Steps separated by commas, like "ctrl+alt+m, 1" or "rctrl, rctrl".
+---@param callback fun(keys: string)
+---@param opts KeySequenceOptions|nil
+---@return KeySequenceHandle
+function register_key_sequence(keys, callback, opts) end
+
+---@class HotplugFilter
+---@field vendor_id integer|nil
+---@field product_id integer|nil
+---@field serial string|nil
+---@field on "connected"|"disconnected"|"both"|nil "both" by default.
+---@field callback fun(event: "connected"|"disconnected", vendor_id: integer, product_id: integer, serial: string|nil)|nil Instead of passing it after the filter.
+
+---@class HotplugHandle
+local HotplugHandle = {}
+
+---@return boolean false if the callback was already unregistered
+function HotplugHandle:unregister() end
+
+---@param filter HotplugFilter|fun(event: "connected"|"disconnected", vendor_id: integer, product_id: integer, serial: string|nil)
+---@param callback fun(event: "connected"|"disconnected", vendor_id: integer, product_id: integer, serial: string|nil)|nil
+---@return HotplugHandle
+---Call with just a callback for every USB device, with a filter first, or
+---with a single filter table holding the callback.
+---Any number of callbacks can be registered.
+function register_hotplug(filter, callback) end
 
 ---@param callback fun(edge: "n"|"s"|"w"|"e"|"ne"|"nw"|"se"|"sw"): nil
 ---@return nil
@@ -69,12 +145,37 @@ function register_interval(lo_interval, hi_interval, callback) end
 ---@return nil
 function unregister_interval(id) end
 
+---@param ms integer
+---@param callback function
+---@return number id Pass this to clear_timeout to cancel it.
+---Runs the callback once, after `ms` milliseconds.
+function set_timeout(ms, callback) end
+
+---@param id number The ID returned from set_timeout.
+---@return nil
+function clear_timeout(id) end
+
+---@return { id: number, kind: "interval"|"timeout", due_in_ms: integer, min_ms: integer?, max_ms: integer? }[]
+---The pending intervals and timeouts, for debugging.
+function list_timers() end
+
+---@param name string
+---@param callback fun(arg: any): any
+---@return nil
+---Register a function other programs can call through the HTTP API or
+---the local socket. It gets the JSON argument of the request, and what
+---it returns is sent back as JSON.
+function register_action(name, callback) end
+
 ---@class Device
 local Device = {}
 
 ---@param vendor_id integer
 ---@param product_id integer
 ---@return Device
+---In a callback, lets the main loop run other callbacks while the device
+---answers, as do its methods, ddc.list, the DdcMonitor methods and
+---switch_input. Elsewhere they block.
 function device_open(vendor_id, product_id) end
 
 ---@param vendor_id integer
@@ -99,3 +200,74 @@ function Device:get_input() end
 ---@param position integer
 ---@return nil
 function Device:set_input(position) end
+
+---@class DdcMonitor
+---@field description string
+---@field serial string? The serial from the EDID, if available.
+---@field manufacturer string? The PNP id from the EDID, like "MSI".
+local DdcMonitor = {}
+
+---@class ddc
+ddc = {}
+
+---@return DdcMonitor[]
+---List the monitors that can be reached over DDC/CI.
+function ddc.list() end
+
+---@param self self
+---@param code integer VCP feature code.
+---@return integer value
+---@return integer maximum
+function DdcMonitor:get_vcp(code) end
+
+---@param self self
+---@param code integer VCP feature code.
+---@param value integer
+---@return nil
+function DdcMonitor:set_vcp(code, value) end
+
+---@param self self
+---@return string
+---The raw MCCS capabilities string reported by the monitor.
+function DdcMonitor:capabilities() end
+
+---@class Capabilities
+---@field protocol string?
+---@field type string?
+---@field model string?
+---@field mccs_version string?
+---@field vcp table<integer, integer[]> Supported VCP codes and their allowed values, if listed.
+
+---@param self self
+---@return Capabilities
+---The capabilities string parsed into its parts.
+function DdcMonitor:parsed_capabilities() end
+
+---@param self self
+---@return {value: integer, name: string?}[]
+---The input sources the monitor accepts for VCP 0x60.
+function DdcMonitor:inputs() end
+
+---@param self self
+---@return integer value
+---@return string? name
+function DdcMonitor:get_input() end
+
+---@param self self
+---@param source integer|string A VCP 0x60 value, or a name like "DisplayPort-1" or "HDMI-2".
+---@return nil
+---Switch the input source. Errors if the monitor does not list it as valid.
+function DdcMonitor:set_input(source) end
+
+---@class SwitchInputOptions
+---@field vendor_id integer
+---@field product_id integer
+---@field input integer The input position used over USB, like Device:set_input.
+---@field ddc_input integer|string? The VCP 0x60 source to use over DDC/CI when USB is unavailable. Without it there is no fallback.
+---@field serial string? The EDID serial of the monitor. Only needed with several MSI monitors.
+
+---@param opts SwitchInputOptions
+---@return "usb"|"ddc" path How the input was switched.
+---Switch the input over USB, falling back to DDC/CI if the monitor cannot be
+---reached over USB, such as when only a video cable is connected.
+function switch_input(opts) end
@@ -27,11 +27,6 @@ local hotkey_callback = function(hk)
 end
 
 local hotplug_callback = function(status, vendor_id, product_id)
-  -- We only care about our specific monitor.
-  if vendor_id ~= VENDOR_ID or product_id ~= PRODUCT_ID then
-    return
-  end
-
   -- If we are connected on macos, then set it up such that the external monitor is
   -- an extension of the macbook monitor.
   if status == "connected" then
@@ -53,7 +48,8 @@ if host_os == "macos" then
   xpcall(register_hotkey, error_handler, "shift+super+alt+ArrowRight", hotkey_callback)
   -- On macos we detect when the monitor is plugged in so we can use `displayplacer` to
   -- set up our screens how we like.
-  xpcall(register_hotplug, error_handler, hotplug_callback)
+  -- We only care about our specific monitor.
+  xpcall(register_hotplug, error_handler, { vendor_id = VENDOR_ID, product_id = PRODUCT_ID }, hotplug_callback)
 else
   xpcall(register_hotkey, error_handler, "shift+control+alt+ArrowRight", hotkey_callback)
 end
@@ -0,0 +1,8 @@
+-- Switch every monitor reachable over DDC/CI to its first DisplayPort input.
+for _, monitor in ipairs(ddc.list()) do
+  print(monitor.description, monitor:get_input())
+  for _, source in ipairs(monitor:inputs()) do
+    print("", source.value, source.name)
+  end
+  monitor:set_input("DisplayPort-1")
+end
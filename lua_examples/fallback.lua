@@ -0,0 +1,10 @@
+-- Switch to Type C, even from a host that only has a video cable to the
+-- monitor. Over DDC/CI the input source values differ from the USB ones, use
+-- `monitor:inputs()` from the ddc module to see what your monitor reports.
+local path = switch_input{
+  vendor_id = 0x1462,
+  product_id = 0x3fa4,
+  input = 3,
+  ddc_input = 0x1b,
+}
+print("switched over " .. path)
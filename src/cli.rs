@@ -0,0 +1,468 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::daemon;
+use super::device;
+use super::errors::StdError;
+use super::mccs;
+use super::switch;
+
+// Bumped whenever the JSON output changes in a way that could break
+// consumers. Adding fields doesn't count.
+const JSON_VERSION: u32 = 1;
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Format {
+  Text,
+  Json,
+}
+
+#[derive(Serialize)]
+struct Versioned<'a, T: Serialize> {
+  version: u32,
+  #[serde(flatten)]
+  body: &'a T,
+}
+
+fn print_json<T: Serialize>(body: &T) -> Result<(), Box<StdError>> {
+  let json = serde_json::to_string(&Versioned {
+    version: JSON_VERSION,
+    body,
+  })?;
+  println!("{}", json);
+  Ok(())
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+  code: i32,
+  message: String,
+}
+
+#[derive(Serialize)]
+struct ErrorOutput {
+  error: ErrorBody,
+}
+
+pub(crate) fn print_error(err: &StdError, code: i32) {
+  let output = ErrorOutput {
+    error: ErrorBody {
+      code,
+      message: err.to_string(),
+    },
+  };
+  if let Err(err) = print_json(&output) {
+    eprintln!("Error: {}", err);
+  }
+}
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct DeviceArgs {
+  /// Vendor id of the monitor.
+  #[arg(long, default_value = "0x1462", value_parser = parse_u16)]
+  pub(crate) vendor_id: u16,
+  /// Product id of the monitor.
+  #[arg(long, default_value = "0x3fa4", value_parser = parse_u16)]
+  pub(crate) product_id: u16,
+}
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct GetArgs {
+  /// A setting name (input, kvm, volume) or a command code like 0x500.
+  #[arg(value_parser = parse_code)]
+  setting: u16,
+  #[command(flatten)]
+  device: DeviceArgs,
+}
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct SetArgs {
+  /// A setting name (input, kvm, volume).
+  #[arg(value_parser = parse_setting)]
+  setting: u16,
+  value: u32,
+  #[command(flatten)]
+  device: DeviceArgs,
+}
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct SwitchArgs {
+  /// The host to switch to, as INPUT:KVM (like 3:2), or just INPUT.
+  #[arg(value_parser = switch::parse_host)]
+  host: switch::Host,
+  /// The VCP 0x60 source (a number or a name like HDMI-1) to use over DDC/CI
+  /// if the monitor can't be reached over USB.
+  #[arg(long, value_parser = parse_input_source)]
+  ddc_input: Option<u8>,
+  /// The EDID serial of the monitor, to find it over DDC/CI.
+  #[arg(long)]
+  serial: Option<String>,
+  #[command(flatten)]
+  device: DeviceArgs,
+}
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct InfoArgs {
+  #[command(flatten)]
+  device: DeviceArgs,
+}
+
+pub(crate) fn parse_u16(s: &str) -> Result<u16, String> {
+  let res = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+    Some(hex) => u16::from_str_radix(hex, 16),
+    None => s.parse(),
+  };
+  res.map_err(|e| format!("invalid number '{}': {}", s, e))
+}
+
+pub(crate) fn parse_setting(s: &str) -> Result<u16, String> {
+  device::code_by_name(s).ok_or_else(|| {
+    let names = device::CODE_NAMES
+      .iter()
+      .map(|(_, name)| *name)
+      .collect::<Vec<_>>()
+      .join(", ");
+    format!("unknown setting '{}', expected one of: {}", s, names)
+  })
+}
+
+// Reads are harmless, so `get` also takes raw command codes.
+pub(crate) fn parse_code(s: &str) -> Result<u16, String> {
+  if s.starts_with("0x") || s.starts_with("0X") {
+    let code = parse_u16(s)?;
+    if code > 0xfff {
+      return Err(format!(
+        "command code '{}' is out of range, must be <= 0xfff",
+        s
+      ));
+    }
+    return Ok(code);
+  }
+  parse_setting(s)
+}
+
+pub(crate) fn parse_input_source(s: &str) -> Result<u8, String> {
+  if let Some(value) = mccs::input_source_value(s) {
+    return Ok(value);
+  }
+  let value = parse_u16(s)?;
+  u8::try_from(value).map_err(|e| format!("invalid input source '{}': {}", s, e))
+}
+
+// Returns a connection to the running main loop, if there is one. Commands
+// go through it instead of opening the monitor, see `daemon::call`. Dry runs
+// never do, since the main loop would really send them.
+fn running_instance() -> Option<daemon::Client> {
+  if device::is_dry_run() {
+    return None;
+  }
+  daemon::connect()
+}
+
+// A monitor for the commands that send raw packets, reached through the
+// running instance when there is one.
+pub(crate) enum Monitor {
+  Running {
+    client: daemon::Client,
+    vendor_id: u16,
+    product_id: u16,
+  },
+  Direct(device::MSIDevice),
+}
+
+impl Monitor {
+  pub(crate) fn open(device: &DeviceArgs) -> Result<Self, Box<StdError>> {
+    Ok(match running_instance() {
+      Some(client) => {
+        Monitor::Running {
+          client,
+          vendor_id: device.vendor_id,
+          product_id: device.product_id,
+        }
+      },
+      None => {
+        Monitor::Direct(device::MSIDevice::open(
+          device.vendor_id,
+          device.product_id,
+        )?)
+      },
+    })
+  }
+
+  pub(crate) fn query_raw(
+    &mut self,
+    code: u16,
+    timeout: Duration,
+  ) -> Result<[u8; 64], Box<StdError>> {
+    match self {
+      Monitor::Running {
+        client,
+        vendor_id,
+        product_id,
+      } => {
+        let reply: Vec<u8> = serde_json::from_value(client.send(&daemon::Request::Query {
+          vendor_id: *vendor_id,
+          product_id: *product_id,
+          code,
+          timeout_ms: timeout.as_millis() as u64,
+        })?)?;
+        Ok(device::make_packet(&reply))
+      },
+      Monitor::Direct(dev) => dev.query_raw(code, timeout),
+    }
+  }
+
+  pub(crate) fn send_raw(&mut self, packet: &[u8]) -> Result<Option<[u8; 64]>, Box<StdError>> {
+    match self {
+      Monitor::Running {
+        client,
+        vendor_id,
+        product_id,
+      } => {
+        let reply: Option<Vec<u8>> =
+          serde_json::from_value(client.send(&daemon::Request::SendRaw {
+            vendor_id: *vendor_id,
+            product_id: *product_id,
+            packet: packet.to_vec(),
+          })?)?;
+        Ok(reply.map(|reply| device::make_packet(&reply)))
+      },
+      Monitor::Direct(dev) => dev.send_raw(device::make_packet(packet)),
+    }
+  }
+}
+
+#[derive(Serialize)]
+struct ListOutput {
+  devices: Vec<device::DeviceInfo>,
+}
+
+pub(crate) fn list(format: Format) -> Result<(), Box<StdError>> {
+  let devices = device::list_devices(device::MSI_VENDOR_ID)?;
+
+  if format == Format::Json {
+    return print_json(&ListOutput {
+      devices,
+    });
+  }
+
+  for info in devices {
+    println!(
+      "{:04x}:{:04x} bus {:03} address {:03} {} {}",
+      info.vendor_id,
+      info.product_id,
+      info.bus,
+      info.address,
+      info.product.as_deref().unwrap_or("?"),
+      info.serial.as_deref().unwrap_or(""),
+    );
+  }
+  Ok(())
+}
+
+#[derive(Serialize)]
+struct SettingOutput {
+  setting: Option<&'static str>,
+  code: u16,
+  value: u32,
+}
+
+pub(crate) fn get(args: GetArgs, format: Format) -> Result<(), Box<StdError>> {
+  let value = match running_instance() {
+    Some(mut client) => {
+      serde_json::from_value(client.send(&daemon::Request::Get {
+        vendor_id: args.device.vendor_id,
+        product_id: args.device.product_id,
+        location: None,
+        code: args.setting,
+      })?)?
+    },
+    None => {
+      let mut dev = device::MSIDevice::open(args.device.vendor_id, args.device.product_id)?;
+      dev.get(args.setting)?
+    },
+  };
+
+  if format == Format::Json {
+    return print_json(&SettingOutput {
+      setting: device::code_name(args.setting),
+      code: args.setting,
+      value,
+    });
+  }
+
+  println!("{}", value);
+  Ok(())
+}
+
+pub(crate) fn set(args: SetArgs, format: Format) -> Result<(), Box<StdError>> {
+  match running_instance() {
+    Some(mut client) => {
+      client.send(&daemon::Request::Set {
+        vendor_id: args.device.vendor_id,
+        product_id: args.device.product_id,
+        location: None,
+        code: args.setting,
+        value: args.value,
+      })?;
+    },
+    None => {
+      let mut dev = device::MSIDevice::open(args.device.vendor_id, args.device.product_id)?;
+      dev.set(args.setting, args.value)?;
+    },
+  }
+
+  if format == Format::Json {
+    return print_json(&SettingOutput {
+      setting: device::code_name(args.setting),
+      code: args.setting,
+      value: args.value,
+    });
+  }
+
+  Ok(())
+}
+
+#[derive(Serialize)]
+struct SwitchOutput {
+  path: String,
+  input: u8,
+  kvm: Option<u8>,
+}
+
+pub(crate) fn switch(args: SwitchArgs, format: Format) -> Result<(), Box<StdError>> {
+  let path = match running_instance() {
+    Some(mut client) => {
+      serde_json::from_value(client.send(&daemon::Request::Switch {
+        vendor_id: args.device.vendor_id,
+        product_id: args.device.product_id,
+        input: args.host.input,
+        kvm: args.host.kvm,
+        ddc_input: args.ddc_input,
+        serial: args.serial,
+      })?)?
+    },
+    None => {
+      switch::switch_host(
+        args.device.vendor_id,
+        args.device.product_id,
+        args.host,
+        args.ddc_input,
+        args.serial,
+      )?
+      .as_str()
+      .to_string()
+    },
+  };
+
+  if format == Format::Json {
+    return print_json(&SwitchOutput {
+      path,
+      input: args.host.input,
+      kvm: args.host.kvm,
+    });
+  }
+
+  println!("switched over {}", path);
+  Ok(())
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct InfoOutput {
+  device: device::DeviceInfo,
+  // Settings that could not be read are null.
+  settings: BTreeMap<String, Option<u32>>,
+}
+
+// Reads the device strings and every setting we know of.
+pub(crate) fn read_info(dev: &mut device::MSIDevice) -> InfoOutput {
+  InfoOutput {
+    device: dev.info(),
+    settings: device::CODE_NAMES
+      .iter()
+      .map(|(code, name)| (name.to_string(), dev.get(*code).ok()))
+      .collect(),
+  }
+}
+
+pub(crate) fn info(args: InfoArgs, format: Format) -> Result<(), Box<StdError>> {
+  let output: InfoOutput = match running_instance() {
+    Some(mut client) => {
+      serde_json::from_value(client.send(&daemon::Request::Info {
+        vendor_id: args.device.vendor_id,
+        product_id: args.device.product_id,
+      })?)?
+    },
+    None => {
+      let mut dev = device::MSIDevice::open(args.device.vendor_id, args.device.product_id)?;
+      read_info(&mut dev)
+    },
+  };
+
+  if format == Format::Json {
+    return print_json(&output);
+  }
+
+  let info = &output.device;
+  println!("device: {:04x}:{:04x}", info.vendor_id, info.product_id);
+  println!("bus: {:03} address: {:03}", info.bus, info.address);
+  println!(
+    "manufacturer: {}",
+    info.manufacturer.as_deref().unwrap_or("?")
+  );
+  println!("product: {}", info.product.as_deref().unwrap_or("?"));
+  println!("serial: {}", info.serial.as_deref().unwrap_or("?"));
+  for (name, value) in &output.settings {
+    match value {
+      Some(value) => println!("{}: {}", name, value),
+      None => println!("{}: ?", name),
+    }
+  }
+
+  Ok(())
+}
+
+fn connect_running() -> Result<daemon::Client, Box<StdError>> {
+  daemon::connect().ok_or_else(|| "no running instance to talk to".into())
+}
+
+#[derive(Serialize)]
+struct HotkeysOutput {
+  hotkeys: Vec<String>,
+}
+
+pub(crate) fn hotkeys(format: Format) -> Result<(), Box<StdError>> {
+  let hotkeys: Vec<String> =
+    serde_json::from_value(connect_running()?.send(&daemon::Request::ListHotkeys)?)?;
+
+  if format == Format::Json {
+    return print_json(&HotkeysOutput {
+      hotkeys,
+    });
+  }
+
+  for hotkey in hotkeys {
+    println!("{}", hotkey);
+  }
+  Ok(())
+}
+
+#[derive(Serialize)]
+struct ReloadOutput {
+  reloaded: bool,
+}
+
+pub(crate) fn reload(format: Format) -> Result<(), Box<StdError>> {
+  connect_running()?.send(&daemon::Request::Reload)?;
+
+  if format == Format::Json {
+    return print_json(&ReloadOutput {
+      reloaded: true,
+    });
+  }
+
+  Ok(())
+}
@@ -0,0 +1,403 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Arc;
+
+use mlua::Function;
+use mlua::Lua;
+use serde::Deserialize;
+use tracing::Level;
+use tracing::event;
+
+use super::cli;
+use super::device;
+use super::errors::StdError;
+use super::sandbox;
+use super::switch;
+
+const SCREEN_EDGES: &[&str] = &["n", "s", "w", "e", "ne", "nw", "se", "sw"];
+
+// A declarative alternative to a Lua script for the common setups: triggers
+// mapped to built-in actions. It is installed through the same Lua functions
+// a script would call, so both run on the same event loop.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Config {
+  #[serde(default)]
+  device: DeviceConfig,
+  // Named hosts that switch actions can refer to.
+  #[serde(default)]
+  hosts: HashMap<String, HostConfig>,
+  #[serde(default)]
+  hotkeys: Vec<HotkeyTrigger>,
+  #[serde(default)]
+  screen_edges: Vec<ScreenEdgeTrigger>,
+  #[serde(default)]
+  hotplug: Vec<HotplugTrigger>,
+  #[serde(default)]
+  intervals: Vec<IntervalTrigger>,
+  // Start with the same arguments on login.
+  #[serde(default)]
+  autorun: bool,
+  // Runs the actions in a sandbox with these permissions, see sandbox.rs.
+  sandbox: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DeviceConfig {
+  #[serde(default = "default_vendor_id")]
+  vendor_id: u16,
+  #[serde(default = "default_product_id")]
+  product_id: u16,
+  // The EDID serial of the monitor, to find it over DDC/CI.
+  serial: Option<String>,
+}
+
+impl Default for DeviceConfig {
+  fn default() -> Self {
+    Self {
+      vendor_id: default_vendor_id(),
+      product_id: default_product_id(),
+      serial: None,
+    }
+  }
+}
+
+fn default_vendor_id() -> u16 {
+  0x1462
+}
+
+fn default_product_id() -> u16 {
+  0x3fa4
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HostConfig {
+  input: u8,
+  kvm: Option<u8>,
+  // The VCP 0x60 source to use over DDC/CI if USB is unavailable.
+  ddc_input: Option<InputSource>,
+}
+
+// A VCP 0x60 source, either as a number or a name like "HDMI-1".
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum InputSource {
+  Value(u8),
+  Name(String),
+}
+
+impl InputSource {
+  pub(crate) fn value(&self) -> Result<u8, String> {
+    match self {
+      InputSource::Value(value) => Ok(*value),
+      InputSource::Name(name) => cli::parse_input_source(name),
+    }
+  }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HotkeyTrigger {
+  keys: String,
+  action: Action,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScreenEdgeTrigger {
+  edge: String,
+  action: Action,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HotplugTrigger {
+  // Leaving out an id matches any device.
+  vendor_id: Option<u16>,
+  product_id: Option<u16>,
+  #[serde(default)]
+  on: HotplugOn,
+  action: Action,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum HotplugOn {
+  #[default]
+  Connected,
+  Disconnected,
+  Both,
+}
+
+impl HotplugOn {
+  // The same names register_hotplug takes.
+  fn as_str(self) -> &'static str {
+    match self {
+      HotplugOn::Connected => "connected",
+      HotplugOn::Disconnected => "disconnected",
+      HotplugOn::Both => "both",
+    }
+  }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IntervalTrigger {
+  every_ms: u64,
+  // Picks a random interval between every_ms and max_ms each time.
+  max_ms: Option<u64>,
+  action: Action,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum Action {
+  // A host name from [hosts], or INPUT:KVM like "3:2".
+  Switch(String),
+  Set { setting: String, value: u32 },
+  // A program and its arguments. It is not run through a shell.
+  Run(Vec<String>),
+}
+
+// An action with its names looked up, so mistakes show up when the config is
+// loaded instead of when the trigger fires.
+#[derive(Debug)]
+enum Resolved {
+  Switch {
+    host: switch::Host,
+    ddc_input: Option<u8>,
+  },
+  Set {
+    code: u16,
+    value: u32,
+  },
+  Run(Vec<String>),
+}
+
+pub(crate) fn load(path: &Path) -> Result<Config, Box<StdError>> {
+  let source = std::fs::read_to_string(path)
+    .map_err(|e| format!("could not read '{}': {}", path.display(), e))?;
+  let config: Config =
+    toml::from_str(&source).map_err(|e| format!("invalid config '{}': {}", path.display(), e))?;
+  Ok(config)
+}
+
+impl Config {
+  pub(crate) fn sandbox(&self) -> Result<Option<HashSet<sandbox::Permission>>, Box<StdError>> {
+    self.sandbox.as_deref().map(sandbox::parse).transpose()
+  }
+
+  fn resolve(&self, action: &Action) -> Result<Resolved, Box<StdError>> {
+    match action {
+      Action::Switch(name) => {
+        if let Some(host) = self.hosts.get(name) {
+          let ddc_input = host
+            .ddc_input
+            .as_ref()
+            .map(InputSource::value)
+            .transpose()?;
+          return Ok(Resolved::Switch {
+            host: switch::Host {
+              input: host.input,
+              kvm: host.kvm,
+            },
+            ddc_input,
+          });
+        }
+        let host = switch::parse_host(name)
+          .map_err(|_| format!("unknown host '{}', add it to [hosts]", name))?;
+        Ok(Resolved::Switch {
+          host,
+          ddc_input: None,
+        })
+      },
+      Action::Set {
+        setting,
+        value,
+      } => {
+        Ok(Resolved::Set {
+          code: cli::parse_setting(setting)?,
+          value: *value,
+        })
+      },
+      Action::Run(command) => {
+        if command.is_empty() {
+          return Err("run needs at least a program".into());
+        }
+        Ok(Resolved::Run(command.clone()))
+      },
+    }
+  }
+}
+
+impl Resolved {
+  // What a sandboxed config needs to be allowed to do this.
+  fn permission(&self) -> (&'static str, sandbox::Permission) {
+    match self {
+      Resolved::Switch {
+        ..
+      } => ("switch", sandbox::Permission::Device),
+      Resolved::Set {
+        ..
+      } => ("set", sandbox::Permission::Device),
+      Resolved::Run(_) => ("run", sandbox::Permission::ProcessSpawn),
+    }
+  }
+
+  // Performs the action from Lua, after checking the sandbox.
+  fn call(&self, lua: &Lua, device: &DeviceConfig) -> Result<(), mlua::Error> {
+    let (what, permission) = self.permission();
+    sandbox::check(lua, what, permission)?;
+    self.perform(device).map_err(mlua::Error::external)
+  }
+
+  fn perform(&self, device: &DeviceConfig) -> Result<(), Box<StdError>> {
+    match self {
+      Resolved::Switch {
+        host,
+        ddc_input,
+      } => {
+        event!(Level::INFO, "switching to {:?}", host);
+        switch::switch_host(
+          device.vendor_id,
+          device.product_id,
+          *host,
+          *ddc_input,
+          device.serial.clone(),
+        )?;
+      },
+      Resolved::Set {
+        code,
+        value,
+      } => {
+        event!(Level::INFO, "setting {:03x} to {}", code, value);
+        let mut dev = device::MSIDevice::open(device.vendor_id, device.product_id)?;
+        dev.set(*code, *value)?;
+      },
+      Resolved::Run(command) => {
+        event!(Level::INFO, "running {:?}", command);
+        let mut child = std::process::Command::new(&command[0])
+          .args(&command[1..])
+          .spawn()?;
+        // Reap the child without holding up the event loop.
+        std::thread::spawn(move || child.wait());
+      },
+    }
+    Ok(())
+  }
+}
+
+fn action_function(
+  lua: &Lua,
+  action: Resolved,
+  device: &Arc<DeviceConfig>,
+) -> Result<Function, Box<StdError>> {
+  let device = device.clone();
+  let f =
+    lua.create_function(move |lua, ()| -> Result<(), mlua::Error> { action.call(lua, &device) })?;
+  Ok(f)
+}
+
+// Registers every trigger in the config with the Lua runtime, using the same
+// globals a script would, and starts the main loop if there is anything to
+// wait for.
+pub(crate) fn install(lua: &Lua, config: Config) -> Result<(), Box<StdError>> {
+  let globals = lua.globals();
+  let device = Arc::new(config.device.clone());
+
+  // Resolve everything before registering anything, so a bad config doesn't
+  // leave half of its hotkeys behind.
+  let hotkeys = config
+    .hotkeys
+    .iter()
+    .map(|t| Ok((t.keys.clone(), config.resolve(&t.action)?)))
+    .collect::<Result<Vec<_>, Box<StdError>>>()?;
+  let screen_edges = config
+    .screen_edges
+    .iter()
+    .map(|t| {
+      if !SCREEN_EDGES.contains(&t.edge.as_str()) {
+        return Err(
+          format!(
+            "unknown screen edge '{}', expected one of: {}",
+            t.edge,
+            SCREEN_EDGES.join(", ")
+          )
+          .into(),
+        );
+      }
+      Ok((t.edge.clone(), config.resolve(&t.action)?))
+    })
+    .collect::<Result<Vec<_>, Box<StdError>>>()?;
+  let hotplug = config
+    .hotplug
+    .iter()
+    .map(|t| {
+      Ok((
+        (t.vendor_id, t.product_id, t.on),
+        config.resolve(&t.action)?,
+      ))
+    })
+    .collect::<Result<Vec<_>, Box<StdError>>>()?;
+  let intervals = config
+    .intervals
+    .iter()
+    .map(|t| {
+      let max_ms = t.max_ms.unwrap_or(t.every_ms);
+      if max_ms < t.every_ms {
+        return Err("max_ms must be >= every_ms".into());
+      }
+      Ok(((t.every_ms, max_ms), config.resolve(&t.action)?))
+    })
+    .collect::<Result<Vec<_>, Box<StdError>>>()?;
+
+  let waits =
+    !(hotkeys.is_empty() && screen_edges.is_empty() && hotplug.is_empty() && intervals.is_empty());
+
+  let register_hotkey: Function = globals.get("register_hotkey")?;
+  for (keys, action) in hotkeys {
+    register_hotkey.call::<()>((keys, action_function(lua, action, &device)?))?;
+  }
+
+  // There is only one screen edge callback, so it dispatches to the
+  // matching triggers itself.
+  if !screen_edges.is_empty() {
+    let device = device.clone();
+    let dispatch = lua.create_function(move |lua, edge: String| -> Result<(), mlua::Error> {
+      for (_, action) in screen_edges.iter().filter(|(e, _)| *e == edge) {
+        action.call(lua, &device)?;
+      }
+      Ok(())
+    })?;
+    globals
+      .get::<Function>("register_screen_edge")?
+      .call::<()>(dispatch)?;
+  }
+
+  let register_hotplug: Function = globals.get("register_hotplug")?;
+  for ((vendor_id, product_id, on), action) in hotplug {
+    let filter = lua.create_table()?;
+    filter.set("vendor_id", vendor_id)?;
+    filter.set("product_id", product_id)?;
+    filter.set("on", on.as_str())?;
+    register_hotplug.call::<()>((filter, action_function(lua, action, &device)?))?;
+  }
+
+  let register_interval: Function = globals.get("register_interval")?;
+  for ((lo, hi), action) in intervals {
+    register_interval.call::<usize>((lo, hi, action_function(lua, action, &device)?))?;
+  }
+
+  if config.autorun {
+    globals.get::<Function>("autorun")?.call::<()>(())?;
+  }
+
+  if waits {
+    globals.get::<Function>("main_loop")?.call::<()>(())?;
+  }
+
+  Ok(())
+}
@@ -0,0 +1,332 @@
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::thread;
+
+use crossbeam_channel::Sender;
+#[cfg(not(target_os = "windows"))]
+use interprocess::local_socket::GenericFilePath;
+#[cfg(target_os = "windows")]
+use interprocess::local_socket::GenericNamespaced;
+use interprocess::local_socket::ListenerOptions;
+use interprocess::local_socket::Name;
+use interprocess::local_socket::prelude::*;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::Level;
+use tracing::event;
+
+use super::device;
+use super::errors;
+use super::errors::StdError;
+
+// The main loop listens on a local socket (a named pipe on windows) so other
+// processes can ask it to do things, see `call`. The protocol is one JSON
+// request per line, answered by one JSON response per line.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "request", rename_all = "snake_case")]
+pub(crate) enum Request {
+  Switch {
+    vendor_id: u16,
+    product_id: u16,
+    input: u8,
+    kvm: Option<u8>,
+    ddc_input: Option<u8>,
+    serial: Option<String>,
+  },
+  // `location` is the (bus, address) of the monitor when several have the
+  // same ids, see `device::MSIDevice::open_at`.
+  Get {
+    vendor_id: u16,
+    product_id: u16,
+    #[serde(default)]
+    location: Option<(u8, u8)>,
+    code: u16,
+  },
+  Set {
+    vendor_id: u16,
+    product_id: u16,
+    #[serde(default)]
+    location: Option<(u8, u8)>,
+    code: u16,
+    value: u32,
+  },
+  // The device strings and every known setting.
+  Info {
+    vendor_id: u16,
+    product_id: u16,
+  },
+  // A read request for any command code, answered with the raw reply.
+  Query {
+    vendor_id: u16,
+    product_id: u16,
+    code: u16,
+    timeout_ms: u64,
+  },
+  // Sends a packet as it is, answered with the raw reply if there was one.
+  SendRaw {
+    vendor_id: u16,
+    product_id: u16,
+    packet: Vec<u8>,
+  },
+  ListDevices,
+  ListHotkeys,
+  ListActions,
+  // Calls a function registered with register_action.
+  RunAction {
+    name: String,
+    #[serde(default)]
+    arg: serde_json::Value,
+  },
+  Reload,
+  // Runs Lua code in the script's state, for `repl --attach`.
+  Eval {
+    code: String,
+  },
+  // Which script is running.
+  Status,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Response {
+  Ok(serde_json::Value),
+  Error {
+    kind: errors::ErrorKind,
+    message: String,
+  },
+}
+
+impl Response {
+  pub(crate) fn error(err: &StdError) -> Self {
+    Response::Error {
+      kind: errors::kind(err),
+      message: err.to_string(),
+    }
+  }
+
+  fn into_result(self) -> Result<serde_json::Value, Box<StdError>> {
+    match self {
+      Response::Ok(value) => Ok(value),
+      Response::Error {
+        kind,
+        message,
+      } => Err(errors::RequestError::new(kind, message).into()),
+    }
+  }
+}
+
+// A request waiting for the main loop to answer it.
+pub(crate) struct Call {
+  pub(crate) request: Request,
+  pub(crate) reply: Sender<Response>,
+}
+
+// Asks the main loop to answer `request` and waits for it. Everything that
+// talks to the monitor while a main loop runs goes through here, from the
+// services in this process or through the socket from other ones. Opening
+// the monitor next to the main loop would have both of us reading the
+// replies meant for the other.
+pub(crate) fn call(
+  tx: &Sender<Call>,
+  request: Request,
+) -> Result<serde_json::Value, Box<StdError>> {
+  let (reply_tx, reply_rx) = crossbeam_channel::bounded(1);
+  tx.send(Call {
+    request,
+    reply: reply_tx,
+  })
+  .map_err(|_| "the main loop has stopped")?;
+  reply_rx
+    .recv()
+    .map_err(|_| "the main loop has stopped")?
+    .into_result()
+}
+
+// Things the main loop tells the services about.
+#[derive(Debug, Clone)]
+pub(crate) enum Event {
+  Hotplug {
+    connected: bool,
+    vendor_id: u16,
+    product_id: u16,
+  },
+  // A setting was written, by whoever.
+  SettingChanged(device::SettingChange),
+}
+
+#[cfg(target_os = "windows")]
+fn socket_name() -> Result<Name<'static>, Box<StdError>> {
+  // Pipe names are shared by every user on the machine, so the name has the
+  // user's SID in it, the way the socket elsewhere is in a per-user dir.
+  let name =
+    format!("{}-{}", env!("CARGO_CRATE_NAME"), user_sid()?).to_ns_name::<GenericNamespaced>()?;
+  Ok(name)
+}
+
+// The current user's SID, like S-1-5-21-...
+#[cfg(target_os = "windows")]
+fn user_sid() -> Result<String, Box<StdError>> {
+  use windows::Win32::Foundation::CloseHandle;
+  use windows::Win32::Foundation::HANDLE;
+  use windows::Win32::Foundation::HLOCAL;
+  use windows::Win32::Foundation::LocalFree;
+  use windows::Win32::Security::Authorization::ConvertSidToStringSidW;
+  use windows::Win32::Security::GetTokenInformation;
+  use windows::Win32::Security::TOKEN_QUERY;
+  use windows::Win32::Security::TOKEN_USER;
+  use windows::Win32::Security::TokenUser;
+  use windows::Win32::System::Threading::GetCurrentProcess;
+  use windows::Win32::System::Threading::OpenProcessToken;
+  use windows::core::PWSTR;
+
+  unsafe {
+    let mut token = HANDLE::default();
+    OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token)?;
+
+    // The SID follows the TOKEN_USER pointing to it, so ask for the size
+    // first. That call fails with ERROR_INSUFFICIENT_BUFFER.
+    let mut len = 0;
+    let _ = GetTokenInformation(token, TokenUser, None, 0, &mut len);
+    let mut buf = vec![0u64; (len as usize).div_ceil(8)];
+    let res = GetTokenInformation(
+      token,
+      TokenUser,
+      Some(buf.as_mut_ptr().cast()),
+      len,
+      &mut len,
+    );
+    let _ = CloseHandle(token);
+    res?;
+
+    let user = &*(buf.as_ptr() as *const TOKEN_USER);
+    let mut sid = PWSTR::null();
+    ConvertSidToStringSidW(user.User.Sid, &mut sid)?;
+    let text = sid.to_string();
+    let _ = LocalFree(Some(HLOCAL(sid.0.cast())));
+    Ok(text?)
+  }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn socket_name() -> Result<Name<'static>, Box<StdError>> {
+  Ok(socket_path()?.to_fs_name::<GenericFilePath>()?)
+}
+
+#[cfg(not(target_os = "windows"))]
+fn socket_path() -> Result<std::path::PathBuf, Box<StdError>> {
+  let dirs = super::project_dirs()?;
+  // The runtime dir only exists on linux.
+  let dir = dirs.runtime_dir().unwrap_or(dirs.data_local_dir());
+  std::fs::create_dir_all(dir)?;
+  Ok(dir.join("daemon.sock"))
+}
+
+// Connects to the running main loop, if there is one.
+pub(crate) fn connect() -> Option<Client> {
+  let stream = LocalSocketStream::connect(socket_name().ok()?).ok()?;
+  Some(Client {
+    stream: BufReader::new(stream),
+  })
+}
+
+pub(crate) struct Client {
+  stream: BufReader<LocalSocketStream>,
+}
+
+impl Client {
+  pub(crate) fn send(&mut self, request: &Request) -> Result<serde_json::Value, Box<StdError>> {
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    self.stream.get_mut().write_all(line.as_bytes())?;
+
+    let mut line = String::new();
+    if self.stream.read_line(&mut line)? == 0 {
+      return Err("the running instance closed the connection".into());
+    }
+    serde_json::from_str::<Response>(&line)?.into_result()
+  }
+}
+
+// Starts listening for requests and sends them to `tx` for the main loop to
+// answer. Failing to listen isn't fatal, the script runs without it.
+pub(crate) fn serve(tx: Sender<Call>) {
+  if connect().is_some() {
+    event!(
+      Level::WARN,
+      "another instance is listening for requests, not listening"
+    );
+    return;
+  }
+
+  let listener = match listen() {
+    Ok(listener) => listener,
+    Err(err) => {
+      event!(Level::ERROR, "could not listen for requests: {}", err);
+      return;
+    },
+  };
+
+  thread::spawn(move || {
+    for stream in listener.incoming() {
+      match stream {
+        Ok(stream) => {
+          let tx = tx.clone();
+          thread::spawn(move || {
+            if let Err(err) = handle_client(stream, tx) {
+              event!(Level::DEBUG, "daemon client: {}", err);
+            }
+          });
+        },
+        Err(err) => event!(Level::ERROR, "daemon accept: {}", err),
+      }
+    }
+  });
+}
+
+fn listen() -> Result<LocalSocketListener, Box<StdError>> {
+  // Nobody answered on the socket, so a file left behind is from an
+  // instance that didn't exit cleanly.
+  #[cfg(not(target_os = "windows"))]
+  {
+    let path = socket_path()?;
+    if path.exists() {
+      std::fs::remove_file(&path)?;
+    }
+  }
+
+  let listener = ListenerOptions::new().name(socket_name()?).create_sync()?;
+  event!(Level::INFO, "listening for requests");
+  Ok(listener)
+}
+
+fn handle_client(stream: LocalSocketStream, tx: Sender<Call>) -> Result<(), Box<StdError>> {
+  let mut stream = BufReader::new(stream);
+  let mut line = String::new();
+
+  loop {
+    line.clear();
+    if stream.read_line(&mut line)? == 0 {
+      return Ok(());
+    }
+
+    let response = match serde_json::from_str::<Request>(&line) {
+      Ok(request) => {
+        match call(&tx, request) {
+          Ok(value) => Response::Ok(value),
+          Err(err) => Response::error(&*err),
+        }
+      },
+      Err(err) => {
+        Response::error(&errors::RequestError::new(
+          errors::ErrorKind::InvalidArgument,
+          format!("invalid request: {}", err),
+        ))
+      },
+    };
+
+    let mut out = serde_json::to_string(&response)?;
+    out.push('\n');
+    stream.get_mut().write_all(out.as_bytes())?;
+  }
+}
@@ -0,0 +1,471 @@
+use std::thread;
+
+use crossbeam_channel::Sender;
+use tracing::Level;
+use tracing::event;
+use zbus::blocking::object_server::InterfaceRef;
+use zbus::fdo;
+use zbus::interface;
+use zbus::object_server::SignalEmitter;
+
+use super::cli;
+use super::daemon;
+use super::device;
+use super::errors::StdError;
+use super::switch;
+
+const BUS_NAME: &str = "com.kdar.MsiMonitorCtrl";
+const OBJECT_PATH: &str = "/com/kdar/MsiMonitorCtrl";
+
+// Exposes the monitor on the session bus. Like the other services, requests
+// are sent to `tx` for the main loop to answer.
+struct Service {
+  tx: Sender<daemon::Call>,
+  // The monitor the methods and properties talk to.
+  vendor_id: u16,
+  product_id: u16,
+}
+
+impl Service {
+  // Waiting for the main loop happens on a thread of its own, so the
+  // connection's executor goes on with other messages meanwhile.
+  async fn call(&self, request: daemon::Request) -> fdo::Result<serde_json::Value> {
+    let tx = self.tx.clone();
+    blocking::unblock(move || daemon::call(&tx, request).map_err(|err| err.to_string()))
+      .await
+      .map_err(fdo::Error::Failed)
+  }
+
+  async fn get_code(&self, code: u16) -> fdo::Result<u32> {
+    let value = self
+      .call(daemon::Request::Get {
+        vendor_id: self.vendor_id,
+        product_id: self.product_id,
+        location: None,
+        code,
+      })
+      .await?;
+    value
+      .as_u64()
+      .map(|v| v as u32)
+      .ok_or_else(|| fdo::Error::Failed(format!("unexpected value {}", value)))
+  }
+}
+
+#[interface(name = "com.kdar.MsiMonitorCtrl1")]
+impl Service {
+  // Switches to INPUT:KVM, like "3:2", or just INPUT. Returns how it
+  // switched, "usb" or "ddc".
+  async fn switch(&self, host: &str) -> fdo::Result<String> {
+    let host = switch::parse_host(host).map_err(fdo::Error::InvalidArgs)?;
+    let path = self
+      .call(daemon::Request::Switch {
+        vendor_id: self.vendor_id,
+        product_id: self.product_id,
+        input: host.input,
+        kvm: host.kvm,
+        ddc_input: None,
+        serial: None,
+      })
+      .await?;
+    Ok(path.as_str().unwrap_or_default().to_string())
+  }
+
+  // Reads a setting by name or code.
+  async fn get(&self, setting: &str) -> fdo::Result<u32> {
+    let code = cli::parse_code(setting).map_err(fdo::Error::InvalidArgs)?;
+    self.get_code(code).await
+  }
+
+  async fn set(&self, setting: &str, value: u32) -> fdo::Result<()> {
+    let code = cli::parse_setting(setting).map_err(fdo::Error::InvalidArgs)?;
+    self
+      .call(daemon::Request::Set {
+        vendor_id: self.vendor_id,
+        product_id: self.product_id,
+        location: None,
+        code,
+        value,
+      })
+      .await?;
+    Ok(())
+  }
+
+  // (vendor_id, product_id, manufacturer, product, serial) of every
+  // connected monitor. Missing strings are empty.
+  async fn list_monitors(&self) -> fdo::Result<Vec<(u16, u16, String, String, String)>> {
+    let devices: Vec<device::DeviceInfo> =
+      serde_json::from_value(self.call(daemon::Request::ListDevices).await?)
+        .map_err(|e| fdo::Error::Failed(e.to_string()))?;
+    Ok(
+      devices
+        .into_iter()
+        .filter(device::DeviceInfo::is_monitor)
+        .map(|info| {
+          (
+            info.vendor_id,
+            info.product_id,
+            info.manufacturer.unwrap_or_default(),
+            info.product.unwrap_or_default(),
+            info.serial.unwrap_or_default(),
+          )
+        })
+        .collect(),
+    )
+  }
+
+  #[zbus(property)]
+  async fn input(&self) -> fdo::Result<u32> {
+    self.get_code(device::CODE_INPUT).await
+  }
+
+  #[zbus(property)]
+  async fn kvm(&self) -> fdo::Result<u32> {
+    self.get_code(device::CODE_KVM).await
+  }
+
+  // A USB device was "connected" or "disconnected".
+  #[zbus(signal)]
+  async fn hotplug(
+    emitter: &SignalEmitter<'_>,
+    event: &str,
+    vendor_id: u16,
+    product_id: u16,
+  ) -> zbus::Result<()>;
+
+  // A setting was changed, through the bus or anywhere else: the script, the
+  // CLI, HTTP or MQTT.
+  #[zbus(signal)]
+  async fn setting_changed(
+    emitter: &SignalEmitter<'_>,
+    setting: &str,
+    value: u32,
+  ) -> zbus::Result<()>;
+}
+
+// Registers the service on the session bus. Honors DBUS_SESSION_BUS_ADDRESS,
+// so it can be pointed at a private dbus-daemon. Failing to connect isn't
+// fatal, the script runs without it. `monitor` is the (vendor_id, product_id)
+// the methods and properties talk to. Returns where to send events.
+pub(crate) fn serve(
+  tx: Sender<daemon::Call>,
+  monitor: (u16, u16),
+) -> Option<Sender<daemon::Event>> {
+  let builder = zbus::blocking::connection::Builder::session();
+  let connection = match builder
+    .map_err(Into::into)
+    .and_then(|b| connect(b, tx, monitor))
+  {
+    Ok(connection) => connection,
+    Err(err) => {
+      event!(
+        Level::WARN,
+        "could not register {} on the session bus: {}",
+        BUS_NAME,
+        err
+      );
+      return None;
+    },
+  };
+  event!(Level::INFO, "registered {} on the session bus", BUS_NAME);
+  Some(forward_events(connection))
+}
+
+// Turns the events from the main loop into signals, for as long as the
+// returned sender is around.
+fn forward_events(connection: zbus::blocking::Connection) -> Sender<daemon::Event> {
+  let (events_tx, events_rx) = crossbeam_channel::unbounded();
+  thread::spawn(move || {
+    let iface = match connection
+      .object_server()
+      .interface::<_, Service>(OBJECT_PATH)
+    {
+      Ok(iface) => iface,
+      Err(err) => {
+        event!(Level::ERROR, "dbus: {}", err);
+        return;
+      },
+    };
+    for event in events_rx {
+      let res = match event {
+        daemon::Event::Hotplug {
+          connected,
+          vendor_id,
+          product_id,
+        } => {
+          zbus::block_on(Service::hotplug(
+            iface.signal_emitter(),
+            if connected {
+              "connected"
+            } else {
+              "disconnected"
+            },
+            vendor_id,
+            product_id,
+          ))
+        },
+        daemon::Event::SettingChanged(change) => setting_changed(&iface, &change),
+      };
+      if let Err(err) = res {
+        event!(Level::ERROR, "dbus: {}", err);
+      }
+    }
+  });
+
+  events_tx
+}
+
+// Tells about a change and, for the monitor the properties are about, that
+// the property changed.
+fn setting_changed(
+  iface: &InterfaceRef<Service>,
+  change: &device::SettingChange,
+) -> zbus::Result<()> {
+  let emitter = iface.signal_emitter();
+  let name = device::code_name(change.code)
+    .map(str::to_string)
+    .unwrap_or_else(|| format!("{:#x}", change.code));
+  zbus::block_on(Service::setting_changed(emitter, &name, change.value))?;
+
+  let service = iface.get();
+  if (change.vendor_id, change.product_id) != (service.vendor_id, service.product_id) {
+    return Ok(());
+  }
+  match change.code {
+    device::CODE_INPUT => zbus::block_on(service.input_changed(emitter)),
+    device::CODE_KVM => zbus::block_on(service.kvm_changed(emitter)),
+    _ => Ok(()),
+  }
+}
+
+fn connect(
+  builder: zbus::blocking::connection::Builder<'_>,
+  tx: Sender<daemon::Call>,
+  (vendor_id, product_id): (u16, u16),
+) -> Result<zbus::blocking::Connection, Box<StdError>> {
+  let connection = builder
+    .name(BUS_NAME)?
+    .serve_at(
+      OBJECT_PATH,
+      Service {
+        tx,
+        vendor_id,
+        product_id,
+      },
+    )?
+    .build()?;
+  Ok(connection)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::BufRead;
+  use std::io::BufReader;
+  use std::process::Child;
+  use std::process::Command;
+  use std::process::Stdio;
+  use std::time::Duration;
+  use std::time::Instant;
+
+  use serde_json::json;
+
+  use super::*;
+
+  // The monitor the service talks to unless told otherwise.
+  const PRODUCT_ID: u16 = 0x3fa4;
+
+  // A dbus-daemon of our own, so the tests neither need a session bus nor
+  // take the name on it.
+  struct Bus {
+    daemon: Child,
+    address: String,
+  }
+
+  impl Bus {
+    // None, and the test is skipped, without dbus-daemon.
+    fn start() -> Option<Self> {
+      let mut daemon = match Command::new("dbus-daemon")
+        .args(["--session", "--nofork", "--print-address"])
+        .stdout(Stdio::piped())
+        .spawn()
+      {
+        Ok(daemon) => daemon,
+        Err(err) => {
+          eprintln!("skipped, could not start dbus-daemon: {}", err);
+          return None;
+        },
+      };
+      let mut address = String::new();
+      BufReader::new(daemon.stdout.take().unwrap())
+        .read_line(&mut address)
+        .unwrap();
+      Some(Bus {
+        daemon,
+        address: address.trim().to_string(),
+      })
+    }
+
+    fn builder(&self) -> zbus::blocking::connection::Builder<'_> {
+      zbus::blocking::connection::Builder::address(self.address.as_str()).unwrap()
+    }
+
+    // Registers the service with `main_loop` answering for it.
+    fn serve(&self) -> Sender<daemon::Event> {
+      self.serve_monitor((device::MSI_VENDOR_ID, PRODUCT_ID))
+    }
+
+    fn serve_monitor(&self, monitor: (u16, u16)) -> Sender<daemon::Event> {
+      forward_events(connect(self.builder(), main_loop(), monitor).unwrap())
+    }
+
+    fn client(&self) -> zbus::blocking::Connection {
+      self.builder().build().unwrap()
+    }
+  }
+
+  impl Drop for Bus {
+    fn drop(&mut self) {
+      let _ = self.daemon.kill();
+      let _ = self.daemon.wait();
+    }
+  }
+
+  fn device_info(product_id: u16) -> serde_json::Value {
+    json!({
+      "vendor_id": device::MSI_VENDOR_ID,
+      "product_id": product_id,
+      "bus": 1,
+      "address": 2,
+      "manufacturer": null,
+      "product": null,
+      "serial": null,
+    })
+  }
+
+  // Answers like the main loop would, taking its time over the volume. Other
+  // monitors than the usual one answer 7.
+  fn main_loop() -> Sender<daemon::Call> {
+    let (tx, rx) = crossbeam_channel::unbounded::<daemon::Call>();
+    thread::spawn(move || {
+      for call in rx {
+        let value = match call.request {
+          daemon::Request::Get {
+            code: device::CODE_VOLUME,
+            ..
+          } => {
+            thread::sleep(Duration::from_secs(2));
+            json!(40)
+          },
+          daemon::Request::Get {
+            product_id, ..
+          } if product_id != PRODUCT_ID => json!(7),
+          daemon::Request::Get {
+            ..
+          } => json!(3),
+          // A monitor and an RGB controller.
+          daemon::Request::ListDevices => json!([device_info(PRODUCT_ID), device_info(0x7b93)]),
+          _ => serde_json::Value::Null,
+        };
+        let _ = call.reply.send(daemon::Response::Ok(value));
+      }
+    });
+    tx
+  }
+
+  fn proxy(client: &zbus::blocking::Connection) -> zbus::blocking::Proxy<'_> {
+    zbus::blocking::Proxy::new(client, BUS_NAME, OBJECT_PATH, "com.kdar.MsiMonitorCtrl1").unwrap()
+  }
+
+  #[test]
+  fn reads_settings() {
+    let Some(bus) = Bus::start() else {
+      return;
+    };
+    let _events = bus.serve();
+    let client = bus.client();
+    let value: u32 = proxy(&client).call("Get", &("input",)).unwrap();
+    assert_eq!(value, 3);
+  }
+
+  #[test]
+  fn talks_to_the_chosen_monitor() {
+    let Some(bus) = Bus::start() else {
+      return;
+    };
+    let _events = bus.serve_monitor((device::MSI_VENDOR_ID, 0x3fa5));
+    let client = bus.client();
+    let value: u32 = proxy(&client).call("Get", &("input",)).unwrap();
+    assert_eq!(value, 7);
+  }
+
+  #[test]
+  fn lists_only_monitors() {
+    let Some(bus) = Bus::start() else {
+      return;
+    };
+    let _events = bus.serve();
+    let client = bus.client();
+    let monitors: Vec<(u16, u16, String, String, String)> =
+      proxy(&client).call("ListMonitors", &()).unwrap();
+    assert_eq!(
+      monitors
+        .iter()
+        .map(|(vid, pid, ..)| (*vid, *pid))
+        .collect::<Vec<_>>(),
+      vec![(device::MSI_VENDOR_ID, PRODUCT_ID)]
+    );
+  }
+
+  #[test]
+  fn answers_while_waiting_for_the_main_loop() {
+    let Some(bus) = Bus::start() else {
+      return;
+    };
+    let _events = bus.serve();
+    let client = bus.client();
+
+    let slow = {
+      let client = client.clone();
+      thread::spawn(move || proxy(&client).call::<_, _, u32>("Get", &("volume",)))
+    };
+    thread::sleep(Duration::from_millis(200));
+
+    let start = Instant::now();
+    let introspectable = zbus::blocking::fdo::IntrospectableProxy::builder(&client)
+      .destination(BUS_NAME)
+      .unwrap()
+      .path(OBJECT_PATH)
+      .unwrap()
+      .build()
+      .unwrap();
+    introspectable.introspect().unwrap();
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    assert_eq!(slow.join().unwrap().unwrap(), 40);
+  }
+
+  #[test]
+  fn signals_setting_changes() {
+    let Some(bus) = Bus::start() else {
+      return;
+    };
+    let events = bus.serve();
+    let client = bus.client();
+    let mut signals = proxy(&client).receive_signal("SettingChanged").unwrap();
+
+    // Changes come from the main loop, whoever made them.
+    events
+      .send(daemon::Event::SettingChanged(device::SettingChange {
+        vendor_id: device::MSI_VENDOR_ID,
+        product_id: PRODUCT_ID,
+        location: None,
+        code: device::CODE_KVM,
+        value: 2,
+      }))
+      .unwrap();
+
+    let message = signals.next().unwrap();
+    let (setting, value): (String, u32) = message.body().deserialize().unwrap();
+    assert_eq!((setting.as_str(), value), ("kvm", 2));
+  }
+}
@@ -0,0 +1,447 @@
+use tracing::Level;
+use tracing::event;
+
+use super::errors::StdError;
+use super::mccs;
+use super::mccs::Capabilities;
+
+// A monitor reached over DDC/CI. Each platform has its own backend crate, so
+// they are hidden behind the `Backend` trait.
+pub(crate) struct DdcMonitor {
+  description: String,
+  // Not every platform gives us the EDID.
+  edid: Option<Vec<u8>>,
+  backend: Box<dyn Backend>,
+  // Reading the capabilities string is slow, so we only do it once.
+  capabilities: Option<Capabilities>,
+}
+
+trait Backend: Send {
+  // Returns the current and maximum value of a VCP feature.
+  fn get_vcp(&mut self, code: u8) -> Result<(u16, u16), Box<StdError>>;
+  fn set_vcp(&mut self, code: u8, value: u16) -> Result<(), Box<StdError>>;
+  fn capabilities(&mut self) -> Result<String, Box<StdError>>;
+}
+
+impl DdcMonitor {
+  pub(crate) fn list() -> Result<Vec<Self>, Box<StdError>> {
+    platform::enumerate()
+  }
+
+  pub(crate) fn description(&self) -> &str {
+    &self.description
+  }
+
+  pub(crate) fn serial(&self) -> Option<String> {
+    edid_serial(self.edid.as_deref()?)
+  }
+
+  pub(crate) fn manufacturer(&self) -> Option<String> {
+    edid_manufacturer(self.edid.as_deref()?)
+  }
+
+  pub(crate) fn get_vcp(&mut self, code: u8) -> Result<(u16, u16), Box<StdError>> {
+    self.backend.get_vcp(code)
+  }
+
+  pub(crate) fn set_vcp(&mut self, code: u8, value: u16) -> Result<(), Box<StdError>> {
+    self.backend.set_vcp(code, value)
+  }
+
+  pub(crate) fn capabilities(&mut self) -> Result<String, Box<StdError>> {
+    self.backend.capabilities()
+  }
+
+  pub(crate) fn parsed_capabilities(&mut self) -> Result<&Capabilities, Box<StdError>> {
+    if self.capabilities.is_none() {
+      let caps = self.backend.capabilities()?;
+      self.capabilities = Some(Capabilities::parse(&caps)?);
+    }
+    Ok(self.capabilities.as_ref().unwrap())
+  }
+
+  pub(crate) fn get_input(&mut self) -> Result<u8, Box<StdError>> {
+    let (value, _) = self.get_vcp(mccs::VCP_INPUT_SOURCE)?;
+    Ok(value as u8)
+  }
+
+  // Switches the input source after checking the monitor lists it as one of
+  // the allowed values for VCP 0x60.
+  pub(crate) fn set_input(&mut self, source: u8) -> Result<(), Box<StdError>> {
+    let sources = self.parsed_capabilities()?.input_sources();
+    if sources.is_empty() {
+      event!(
+        Level::WARN,
+        "{} does not list its input sources, switching to {:#04x} anyway",
+        self.description,
+        source
+      );
+    } else if !sources.iter().any(|(value, _)| *value == source) {
+      let valid = sources
+        .iter()
+        .map(|(value, name)| {
+          match name {
+            Some(name) => format!("{:#04x} ({})", value, name),
+            None => format!("{:#04x}", value),
+          }
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+      return Err(
+        format!(
+          "input source {:#04x} is not supported by {}, valid sources are: {}",
+          source, self.description, valid
+        )
+        .into(),
+      );
+    }
+
+    self.set_vcp(mccs::VCP_INPUT_SOURCE, source as u16)
+  }
+}
+
+// Finds a monitor by the serial in its EDID. Without a serial, we look for
+// the only MSI monitor connected.
+pub(crate) fn find_monitor(serial: Option<&str>) -> Result<DdcMonitor, Box<StdError>> {
+  let monitors = DdcMonitor::list()?;
+  let mut matches = match serial {
+    Some(serial) => {
+      monitors
+        .into_iter()
+        .filter(|m| m.serial().as_deref() == Some(serial))
+        .collect::<Vec<_>>()
+    },
+    None => {
+      monitors
+        .into_iter()
+        .filter(|m| m.manufacturer().as_deref() == Some("MSI"))
+        .collect::<Vec<_>>()
+    },
+  };
+
+  match (matches.len(), serial) {
+    (1, _) => Ok(matches.remove(0)),
+    (0, Some(serial)) => Err(format!("no DDC/CI monitor with serial '{}'", serial).into()),
+    (0, None) => Err("no MSI monitor found over DDC/CI".into()),
+    (..) => Err("found several MSI monitors over DDC/CI, a serial is needed to pick one".into()),
+  }
+}
+
+// The serial from the EDID. Monitors either put a string in a display
+// descriptor, or only fill in the 32-bit serial number.
+pub(crate) fn edid_serial(edid: &[u8]) -> Option<String> {
+  for offset in [54, 72, 90, 108] {
+    let descriptor = edid.get(offset..offset + 18)?;
+    if descriptor[0..3] == [0, 0, 0] && descriptor[3] == 0xff {
+      let text = &descriptor[5..];
+      let end = text.iter().position(|b| *b == 0x0a).unwrap_or(text.len());
+      return Some(String::from_utf8_lossy(&text[..end]).trim().to_string());
+    }
+  }
+
+  let serial = u32::from_le_bytes(edid.get(12..16)?.try_into().ok()?);
+  (serial != 0).then(|| serial.to_string())
+}
+
+// The three letter PNP id of the manufacturer, like "MSI".
+pub(crate) fn edid_manufacturer(edid: &[u8]) -> Option<String> {
+  let id = u16::from_be_bytes(edid.get(8..10)?.try_into().ok()?);
+  [10, 5, 0]
+    .iter()
+    .map(|shift| {
+      match ((id >> shift) & 0x1f) as u8 {
+        n @ 1..=26 => Some((b'@' + n) as char),
+        _ => None,
+      }
+    })
+    .collect()
+}
+
+// The backend crates don't agree on an error type, so we only rely on them
+// being printable.
+fn ddc_err<E: std::fmt::Display>(err: E) -> Box<StdError> {
+  err.to_string().into()
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+  use std::path::Path;
+
+  use ddc_compat::Ddc;
+  use tracing::Level;
+  use tracing::event;
+
+  use super::Backend;
+  use super::DdcMonitor;
+  use super::StdError;
+  use super::ddc_err;
+
+  impl Backend for ddc_i2c::I2cDeviceDdc {
+    fn get_vcp(&mut self, code: u8) -> Result<(u16, u16), Box<StdError>> {
+      let value = self.get_vcp_feature(code).map_err(ddc_err)?;
+      Ok((value.value(), value.maximum()))
+    }
+
+    fn set_vcp(&mut self, code: u8, value: u16) -> Result<(), Box<StdError>> {
+      self.set_vcp_feature(code, value).map_err(ddc_err)
+    }
+
+    fn capabilities(&mut self) -> Result<String, Box<StdError>> {
+      let caps = self.capabilities_string().map_err(ddc_err)?;
+      Ok(String::from_utf8_lossy(&caps).into_owned())
+    }
+  }
+
+  // Every connected DRM connector with a DDC bus, such as
+  // /sys/class/drm/card1-DP-1/ddc -> i2c-5.
+  pub(super) fn enumerate() -> Result<Vec<DdcMonitor>, Box<StdError>> {
+    let mut monitors = Vec::new();
+
+    let mut connectors = std::fs::read_dir("/sys/class/drm")?
+      .filter_map(|entry| entry.ok())
+      .map(|entry| entry.path())
+      .collect::<Vec<_>>();
+    connectors.sort();
+
+    for connector in connectors {
+      let status = std::fs::read_to_string(connector.join("status")).unwrap_or_default();
+      if status.trim() != "connected" {
+        continue;
+      }
+
+      let Ok(ddc) = std::fs::read_link(connector.join("ddc")) else {
+        continue;
+      };
+      let Some(bus) = ddc.file_name() else {
+        continue;
+      };
+
+      // One adapter we can't open, for lack of permission or because it
+      // isn't really DDC, shouldn't hide the monitors on the others.
+      let device = Path::new("/dev").join(bus);
+      let backend = match ddc_i2c::from_i2c_device(&device) {
+        Ok(backend) => backend,
+        Err(err) => {
+          event!(Level::WARN, "could not open {}: {}", device.display(), err);
+          continue;
+        },
+      };
+      let name = connector
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+      let edid = std::fs::read(connector.join("edid"))
+        .ok()
+        .filter(|edid| !edid.is_empty());
+
+      monitors.push(DdcMonitor {
+        description: format!("{} ({})", name, device.display()),
+        edid,
+        backend: Box::new(backend),
+        capabilities: None,
+      });
+    }
+
+    Ok(monitors)
+  }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+  use ddc_compat::Ddc;
+
+  use super::Backend;
+  use super::DdcMonitor;
+  use super::StdError;
+  use super::ddc_err;
+
+  // We wrap Monitor so we can send it across threads. It holds a handle to
+  // the display service, which is safe to use from any thread.
+  struct WrappedMonitor(ddc_macos::Monitor);
+
+  unsafe impl Send for WrappedMonitor {}
+
+  impl Backend for WrappedMonitor {
+    fn get_vcp(&mut self, code: u8) -> Result<(u16, u16), Box<StdError>> {
+      let value = self.0.get_vcp_feature(code).map_err(ddc_err)?;
+      Ok((value.value(), value.maximum()))
+    }
+
+    fn set_vcp(&mut self, code: u8, value: u16) -> Result<(), Box<StdError>> {
+      self.0.set_vcp_feature(code, value).map_err(ddc_err)
+    }
+
+    fn capabilities(&mut self) -> Result<String, Box<StdError>> {
+      let caps = self.0.capabilities_string().map_err(ddc_err)?;
+      Ok(String::from_utf8_lossy(&caps).into_owned())
+    }
+  }
+
+  pub(super) fn enumerate() -> Result<Vec<DdcMonitor>, Box<StdError>> {
+    Ok(
+      ddc_macos::Monitor::enumerate()
+        .map_err(ddc_err)?
+        .into_iter()
+        .map(|monitor| {
+          DdcMonitor {
+            description: monitor.description(),
+            edid: monitor.edid(),
+            backend: Box::new(WrappedMonitor(monitor)),
+            capabilities: None,
+          }
+        })
+        .collect(),
+    )
+  }
+}
+
+#[cfg(target_os = "windows")]
+mod platform {
+  use ddc::Ddc;
+  use windows::Win32::Foundation::ERROR_SUCCESS;
+  use windows::Win32::Graphics::Gdi;
+  use windows::Win32::System::Registry;
+  use windows::core::PCWSTR;
+
+  use super::Backend;
+  use super::DdcMonitor;
+  use super::StdError;
+  use super::ddc_err;
+
+  // Makes EnumDisplayDevicesW return device interface names, which lead to
+  // the registry key of the monitor.
+  const EDD_GET_DEVICE_INTERFACE_NAME: u32 = 0x1;
+
+  // We wrap Monitor so we can send it across threads. This is safe to do
+  // since the physical monitor HANDLE is unique globally.
+  struct WrappedMonitor(ddc_winapi::Monitor);
+
+  unsafe impl Send for WrappedMonitor {}
+
+  impl Backend for WrappedMonitor {
+    fn get_vcp(&mut self, code: u8) -> Result<(u16, u16), Box<StdError>> {
+      let value = self.0.get_vcp_feature(code).map_err(ddc_err)?;
+      Ok((value.value(), value.maximum()))
+    }
+
+    fn set_vcp(&mut self, code: u8, value: u16) -> Result<(), Box<StdError>> {
+      self.0.set_vcp_feature(code, value).map_err(ddc_err)
+    }
+
+    fn capabilities(&mut self) -> Result<String, Box<StdError>> {
+      let caps = self.0.capabilities_string().map_err(ddc_err)?;
+      Ok(String::from_utf8_lossy(&caps).into_owned())
+    }
+  }
+
+  pub(super) fn enumerate() -> Result<Vec<DdcMonitor>, Box<StdError>> {
+    let mut monitors = Vec::new();
+    for hmonitor in ddc_winapi::enumerate_monitors().map_err(ddc_err)? {
+      // A display shows the same picture on each of its physical monitors,
+      // listed in the same order as its monitor devices.
+      let mut edids = display_edids(Gdi::HMONITOR(hmonitor as _)).into_iter();
+      for physical in ddc_winapi::get_physical_monitors_from_hmonitor(hmonitor).map_err(ddc_err)? {
+        let monitor = unsafe { ddc_winapi::Monitor::new(physical) };
+        monitors.push(DdcMonitor {
+          description: monitor.description(),
+          edid: edids.next().flatten(),
+          backend: Box::new(WrappedMonitor(monitor)),
+          capabilities: None,
+        });
+      }
+    }
+    Ok(monitors)
+  }
+
+  // The EDIDs of the monitors showing a display. Windows keeps a copy of the
+  // EDID in the registry, under the device key of each monitor.
+  fn display_edids(hmonitor: Gdi::HMONITOR) -> Vec<Option<Vec<u8>>> {
+    let mut info = Gdi::MONITORINFOEXW::default();
+    info.monitorInfo.cbSize = size_of::<Gdi::MONITORINFOEXW>() as u32;
+    let ok =
+      unsafe { Gdi::GetMonitorInfoW(hmonitor, &mut info as *mut _ as *mut Gdi::MONITORINFO) };
+    if !ok.as_bool() {
+      return Vec::new();
+    }
+
+    let mut edids = Vec::new();
+    for i in 0.. {
+      let mut device = Gdi::DISPLAY_DEVICEW {
+        cb: size_of::<Gdi::DISPLAY_DEVICEW>() as u32,
+        ..Default::default()
+      };
+      let ok = unsafe {
+        Gdi::EnumDisplayDevicesW(
+          PCWSTR(info.szDevice.as_ptr()),
+          i,
+          &mut device,
+          EDD_GET_DEVICE_INTERFACE_NAME,
+        )
+      };
+      if !ok.as_bool() {
+        break;
+      }
+      if device.StateFlags.contains(Gdi::DISPLAY_DEVICE_ACTIVE) {
+        edids.push(read_edid(&from_wide(&device.DeviceID)));
+      }
+    }
+    edids
+  }
+
+  // Turns a device interface like
+  // \\?\DISPLAY#MSI3FA4#5&1a2b3c4&0&UID4352#{e6f07b5f-ee97-4a90-b076-33f57bf4eaa7}
+  // into the device's registry key and reads the EDID kept there.
+  fn read_edid(interface: &str) -> Option<Vec<u8>> {
+    let mut parts = interface.strip_prefix(r"\\?\")?.split('#');
+    let (class, model, instance) = (parts.next()?, parts.next()?, parts.next()?);
+    let key = to_wide(&format!(
+      r"SYSTEM\CurrentControlSet\Enum\{}\{}\{}\Device Parameters",
+      class, model, instance
+    ));
+    let value = to_wide("EDID");
+
+    let mut len = 0u32;
+    let err = unsafe {
+      Registry::RegGetValueW(
+        Registry::HKEY_LOCAL_MACHINE,
+        PCWSTR(key.as_ptr()),
+        PCWSTR(value.as_ptr()),
+        Registry::RRF_RT_REG_BINARY,
+        None,
+        None,
+        Some(&mut len),
+      )
+    };
+    if err != ERROR_SUCCESS {
+      return None;
+    }
+
+    let mut edid = vec![0u8; len as usize];
+    let err = unsafe {
+      Registry::RegGetValueW(
+        Registry::HKEY_LOCAL_MACHINE,
+        PCWSTR(key.as_ptr()),
+        PCWSTR(value.as_ptr()),
+        Registry::RRF_RT_REG_BINARY,
+        None,
+        Some(edid.as_mut_ptr() as *mut _),
+        Some(&mut len),
+      )
+    };
+    if err != ERROR_SUCCESS {
+      return None;
+    }
+    edid.truncate(len as usize);
+    Some(edid).filter(|edid| !edid.is_empty())
+  }
+
+  fn to_wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(Some(0)).collect()
+  }
+
+  fn from_wide(s: &[u16]) -> String {
+    let end = s.iter().position(|c| *c == 0).unwrap_or(s.len());
+    String::from_utf16_lossy(&s[..end])
+  }
+}
@@ -1,10 +1,21 @@
-use std::{thread, time::Duration};
-
-use rusb::{
-  Device, DeviceDescriptor, DeviceHandle, Direction, GlobalContext, TransferType, UsbContext,
-};
-
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_channel::Receiver;
+use crossbeam_channel::Sender;
+use rusb::Device;
+use rusb::DeviceDescriptor;
+use rusb::DeviceHandle;
+use rusb::Direction;
+use rusb::GlobalContext;
+use rusb::TransferType;
+use rusb::UsbContext;
+
+use super::errors::DeviceNotFound;
 use super::errors::StdError;
+use super::pcap;
 
 // This is the monitor index which I think increments
 // when you have multiple of these monitors.
@@ -14,19 +25,135 @@ const INDEX: u8 = 0x1;
 const RETURN_VALUE_NUM: usize = 3;
 // This is the index of the end of a generic return from a command.
 const ON_END_INDEX: usize = 10;
+// This is the R/W byte of a packet that reads a value.
+const READ: u8 = 0x38;
+// This is the R/W byte of a packet that writes a value.
+const WRITE: u8 = 0x62;
+// This is the marker at the end of every command.
+const END: u8 = 0x0d;
+
+// Command codes are three characters, each being 0x30 plus a nibble of the
+// code. So the input code 0x500 is sent as 0x35, 0x30, 0x30.
+pub(crate) const CODE_INPUT: u16 = 0x500;
+pub(crate) const CODE_KVM: u16 = 0x8e0;
+pub(crate) const CODE_VOLUME: u16 = 0x870;
+
+// Names for the command codes we know about.
+pub(crate) const CODE_NAMES: &[(u16, &str)] = &[
+  (CODE_INPUT, "input"),
+  (CODE_KVM, "kvm"),
+  (CODE_VOLUME, "volume"),
+];
+
+// The last value written for each (vendor id, product id, code).
+type DryRunValues = HashMap<(u16, u16, u16), u32>;
+
+// When set, devices are not opened and written values are kept here instead.
+static DRY_RUN: Mutex<Option<DryRunValues>> = Mutex::new(None);
+
+pub(crate) fn enable_dry_run() {
+  *DRY_RUN.lock().unwrap() = Some(HashMap::new());
+}
+
+pub(crate) fn is_dry_run() -> bool {
+  DRY_RUN.lock().unwrap().is_some()
+}
+
+pub(crate) const MSI_VENDOR_ID: u16 = 0x1462;
+
+// A setting that was written to a monitor.
+#[derive(Debug, Clone)]
+pub(crate) struct SettingChange {
+  pub(crate) vendor_id: u16,
+  pub(crate) product_id: u16,
+  // (bus, address) when it went over USB.
+  pub(crate) location: Option<(u8, u8)>,
+  pub(crate) code: u16,
+  pub(crate) value: u32,
+}
+
+// Where written settings are reported, once the main loop asks for them.
+static CHANGES: Mutex<Option<Sender<SettingChange>>> = Mutex::new(None);
+
+// Reports every setting written from now on, whether by the script, a
+// service or a request from another process.
+pub(crate) fn watch_changes() -> Receiver<SettingChange> {
+  let (tx, rx) = crossbeam_channel::unbounded();
+  *CHANGES.lock().unwrap() = Some(tx);
+  rx
+}
+
+pub(crate) fn setting_changed(change: SettingChange) {
+  if let Some(tx) = &*CHANGES.lock().unwrap() {
+    let _ = tx.send(change);
+  }
+}
+
+// Product ids of the MSI monitors known to speak this protocol. MSI puts its
+// vendor id on motherboard and RGB controllers too, which we leave alone.
+pub(crate) const MONITOR_PRODUCT_IDS: &[u16] = &[0x3fa4];
+
+pub(crate) fn code_name(code: u16) -> Option<&'static str> {
+  CODE_NAMES
+    .iter()
+    .find(|(c, _)| *c == code)
+    .map(|(_, name)| *name)
+}
+
+pub(crate) fn code_by_name(name: &str) -> Option<u16> {
+  CODE_NAMES
+    .iter()
+    .find(|(_, n)| n.eq_ignore_ascii_case(name))
+    .map(|(code, _)| *code)
+}
 
 // usb.idVendor == 0x1462 && usb.idProduct == 0x3fa4
 
 pub(crate) struct MSIDevice {
-  device_handle: DeviceHandle<GlobalContext>,
-  in_endpoint: Endpoint,
-  out_endpoint: Endpoint,
+  transport: Transport,
+}
+
+enum Transport {
+  Usb {
+    device_handle: DeviceHandle<GlobalContext>,
+    bus: u8,
+    address: u8,
+    in_endpoint: Endpoint,
+    out_endpoint: Endpoint,
+  },
+  // Stands in for the monitor in dry run mode. Read requests are answered
+  // with the last value written.
+  DryRun {
+    vendor_id: u16,
+    product_id: u16,
+    reply: Option<[u8; 64]>,
+  },
 }
 
 impl MSIDevice {
   pub(crate) fn open(vendor_id: u16, product_id: u16) -> Result<Self, Box<StdError>> {
-    let Some(mut device) = get_device(vendor_id, product_id)? else {
-      return Err("unable to find device".into());
+    Self::open_at(vendor_id, product_id, None)
+  }
+
+  // Opens the device at (bus, address), for when several are connected with
+  // the same ids. Without a location, the first one found is opened.
+  pub(crate) fn open_at(
+    vendor_id: u16,
+    product_id: u16,
+    location: Option<(u8, u8)>,
+  ) -> Result<Self, Box<StdError>> {
+    if is_dry_run() {
+      return Ok(Self {
+        transport: Transport::DryRun {
+          vendor_id,
+          product_id,
+          reply: None,
+        },
+      });
+    }
+
+    let Some(mut device) = get_device(vendor_id, product_id, location)? else {
+      return Err(DeviceNotFound.into());
     };
     let mut device_handle = device.open()?;
     let device_desc = device.device_descriptor()?;
@@ -52,13 +179,21 @@ impl MSIDevice {
     configure_endpoint(&mut device_handle, &in_endpoint)?;
 
     return Ok(Self {
-      device_handle,
-      in_endpoint,
-      out_endpoint,
+      transport: Transport::Usb {
+        device_handle,
+        bus: device.bus_number(),
+        address: device.address(),
+        in_endpoint,
+        out_endpoint,
+      },
     });
   }
 
   pub(crate) fn is_connected(vendor_id: u16, product_id: u16) -> Result<bool, Box<StdError>> {
+    if is_dry_run() {
+      return Ok(true);
+    }
+
     for _ in 0..3 {
       for device in rusb::devices()?.iter() {
         let device_desc = device.device_descriptor()?;
@@ -73,7 +208,59 @@ impl MSIDevice {
     Ok(false)
   }
 
-  fn get_uart_cmd(&mut self, packet: [u8; 64]) -> Result<([u8; 64], u32), Box<StdError>> {
+  // Sends a read request for `code` and returns the raw reply, once it looks
+  // like an answer to that request. This never writes anything to the monitor
+  // besides the read request itself, so it is safe to use for probing unknown
+  // codes.
+  pub(crate) fn query_raw(
+    &mut self,
+    code: u16,
+    timeout: Duration,
+  ) -> Result<[u8; 64], Box<StdError>> {
+    let [c1, c2, c3] = encode_code(code);
+    let packet = make_packet(&[INDEX, 0x35, READ, 0x30, 0x30, c1, c2, c3, END]);
+    let buf = self.get_uart_cmd(packet, timeout)?;
+
+    if buf[0] != INDEX || buf[5..8] != [c1, c2, c3] || buf[ON_END_INDEX + 1] != END {
+      return Err(format!("malformed reply: {:x?}", buf).into());
+    }
+
+    Ok(buf)
+  }
+
+  // Like query_raw, along with the value of the reply.
+  pub(crate) fn query(
+    &mut self,
+    code: u16,
+    timeout: Duration,
+  ) -> Result<([u8; 64], u32), Box<StdError>> {
+    let buf = self.query_raw(code, timeout)?;
+    Ok((buf, reply_value(&buf)?))
+  }
+
+  // Writes a raw packet, such as one taken from a capture, and returns the
+  // reply if the monitor sent one.
+  pub(crate) fn send_raw(&mut self, packet: [u8; 64]) -> Result<Option<[u8; 64]>, Box<StdError>> {
+    let mut buf = [0x00; 64];
+    for _ in 0..10 {
+      self.read_interrupt(&mut buf, Duration::from_millis(1)).ok();
+    }
+
+    let timeout = Duration::from_secs(1);
+    self.write_interrupt(&packet, timeout)?;
+
+    match self.read_interrupt(&mut buf, timeout) {
+      Ok(_) => Ok(Some(buf)),
+      Err(rusb::Error::Timeout) => Ok(None),
+      Err(err) => Err(err.into()),
+    }
+  }
+
+  fn get_uart_cmd(
+    &mut self,
+    packet: [u8; 64],
+    timeout: Duration,
+  ) -> Result<[u8; 64], Box<StdError>> {
     let mut buf = [0x00; 64];
 
     // Clear out anything so we can read properly.
@@ -81,21 +268,12 @@ impl MSIDevice {
     // continually read interrupts. We then would only store data for interrupts
     // we are waiting for. Then this function can grab that data when it becomes available.
     for _ in 0..10 {
-      self
-        .device_handle
-        .read_interrupt(self.in_endpoint.address, &mut buf, Duration::from_millis(1))
-        .ok();
+      self.read_interrupt(&mut buf, Duration::from_millis(1)).ok();
     }
 
-    let timeout = Duration::from_secs(1);
-
-    self
-      .device_handle
-      .write_interrupt(self.out_endpoint.address, &packet, timeout)?;
+    self.write_interrupt(&packet, timeout)?;
 
-    self
-      .device_handle
-      .read_interrupt(self.in_endpoint.address, &mut buf, timeout)?;
+    self.read_interrupt(&mut buf, timeout)?;
     // 0x1, 0x35, 0x62, 0x30, 0x30, 0x35, 0x30, 0x30, 0x30, 0x30, 0x32, 0xd
 
     if buf[1] != 0x35 {
@@ -116,19 +294,90 @@ impl MSIDevice {
     // 8-10 is ascii string of the return value.
     // 11 is end of command marker (0xd)
 
-    // const BASE_CMD_VALUE: u8 = 48;
-    // let mut num = 0;
-    // for i in 0..RETURN_VALUE_NUM {
-    //   num +=
-    //     10u32.pow(i.try_into().unwrap()) * ((buf[ON_END_INDEX - i as usize] - BASE_CMD_VALUE) as u32);
-    // }
+    Ok(buf)
+  }
 
-    let num =
-      std::str::from_utf8(&buf[(ON_END_INDEX - (RETURN_VALUE_NUM - 1))..=ON_END_INDEX])?.parse()?;
+  // All transfers go through these two so they can be traced.
+  fn write_interrupt(&mut self, packet: &[u8; 64], timeout: Duration) -> rusb::Result<usize> {
+    match &mut self.transport {
+      Transport::Usb {
+        device_handle,
+        bus,
+        address,
+        out_endpoint,
+        ..
+      } => {
+        let n = device_handle.write_interrupt(out_endpoint.address, packet, timeout)?;
+        pcap::record(*bus, *address, out_endpoint.address, &packet[..n]);
+        Ok(n)
+      },
+      Transport::DryRun {
+        vendor_id,
+        product_id,
+        reply,
+      } => {
+        pcap::record(0, 0, 0x01, packet);
+        let Some(frame) = Frame::parse(packet) else {
+          return Ok(packet.len());
+        };
+
+        let mut values = DRY_RUN.lock().unwrap();
+        let values = values.get_or_insert_default();
+        let key = (*vendor_id, *product_id, frame.code);
+        if let Some(value) = frame.value {
+          values.insert(key, value);
+        }
 
-    // println!("{:?}", buf);
+        // The monitor answers both reads and writes with the current value.
+        let value = values.get(&key).copied().unwrap_or(0);
+        let [c1, c2, c3] = encode_code(frame.code);
+        let [v1, v2, v3]: [u8; 3] = format!("{:03}", value.min(999))
+          .as_bytes()
+          .try_into()
+          .unwrap();
+        *reply = Some(make_packet(&[
+          frame.index,
+          0x35,
+          WRITE,
+          0x30,
+          0x30,
+          c1,
+          c2,
+          c3,
+          v1,
+          v2,
+          v3,
+          END,
+        ]));
+        Ok(packet.len())
+      },
+    }
+  }
 
-    Ok((buf, num))
+  fn read_interrupt(&mut self, buf: &mut [u8; 64], timeout: Duration) -> rusb::Result<usize> {
+    match &mut self.transport {
+      Transport::Usb {
+        device_handle,
+        bus,
+        address,
+        in_endpoint,
+        ..
+      } => {
+        let n = device_handle.read_interrupt(in_endpoint.address, buf, timeout)?;
+        pcap::record(*bus, *address, in_endpoint.address, &buf[..n]);
+        Ok(n)
+      },
+      Transport::DryRun {
+        reply, ..
+      } => {
+        let Some(reply) = reply.take() else {
+          return Err(rusb::Error::Timeout);
+        };
+        pcap::record(0, 0, 0x81, &reply);
+        *buf = reply;
+        Ok(buf.len())
+      },
+    }
   }
 
   // pub(crate) fn get_volume(&mut self) -> Result<u32, Box<StdError>> {
@@ -144,14 +393,12 @@ impl MSIDevice {
   // }
 
   pub(crate) fn get_kvm(&mut self) -> Result<u32, Box<StdError>> {
-    let packet = make_packet(&[INDEX, 0x35, 0x38, 0x30, 0x30, 0x38, 0x3e, 0x30, 0xd]);
-    let (_, value) = self.get_uart_cmd(packet)?;
+    let (_, value) = self.query(CODE_KVM, Duration::from_secs(1))?;
     Ok(value)
   }
 
   pub(crate) fn get_input(&mut self) -> Result<u32, Box<StdError>> {
-    let packet = make_packet(&[INDEX, 0x35, 0x38, 0x30, 0x30, 0x35, 0x30, 0x30, 0xd]);
-    let (_, value) = self.get_uart_cmd(packet)?;
+    let (_, value) = self.query(CODE_INPUT, Duration::from_secs(1))?;
     Ok(value)
   }
 
@@ -192,61 +439,48 @@ impl MSIDevice {
   // }
 
   pub(crate) fn set_input(&mut self, position: u8) -> Result<(), Box<StdError>> {
-    let timeout = Duration::from_secs(1);
+    self.set_position(CODE_INPUT, position)
+  }
 
-    let buf = make_packet(&[
-      INDEX,
-      0x35,
-      0x62,
-      0x30,
-      0x30,
-      0x35,
-      0x30,
-      0x30,
-      0x30,
-      0x30,
-      0x30 + position,
-      0x0d,
-    ]);
-    self
-      .device_handle
-      .write_interrupt(self.out_endpoint.address, &buf, timeout)?;
+  pub(crate) fn set_kvm(&mut self, position: u8) -> Result<(), Box<StdError>> {
+    self.set_position(CODE_KVM, position)
+  }
 
-    // There is a response but we don't care about it. This is more here
-    // for the delay so you can set input and kvm one after another. Without
-    // this delay, setting the kvm or input right after another could fail.
-    // Another option is to retry on failure.
-    let mut buf = [0x00; 64];
-    for _ in 0..5 {
-      self
-        .device_handle
-        .read_interrupt(self.in_endpoint.address, &mut buf, Duration::from_millis(1))
-        .ok();
+  // Input and kvm positions are sent as "00" followed by 0x30 + position,
+  // like codes are, rather than as decimal digits. The two only differ from
+  // position 10 on, where the monitor has only ever been sent the former.
+  fn set_position(&mut self, code: u16, position: u8) -> Result<(), Box<StdError>> {
+    let Some(last) = 0x30u8.checked_add(position) else {
+      return Err(format!("position {} is out of range", position).into());
+    };
+    self.write_value(code, [0x30, 0x30, last], position as u32)
+  }
+
+  pub(crate) fn get(&mut self, code: u16) -> Result<u32, Box<StdError>> {
+    let (_, value) = self.query(code, Duration::from_secs(1))?;
+    Ok(value)
+  }
+
+  pub(crate) fn set(&mut self, code: u16, value: u32) -> Result<(), Box<StdError>> {
+    if value > 999 {
+      return Err(format!("value {} is out of range, must be <= 999", value).into());
     }
 
-    Ok(())
+    let digits = format!("{:03}", value).as_bytes().try_into()?;
+    self.write_value(code, digits, value)
   }
 
-  pub(crate) fn set_kvm(&mut self, position: u8) -> Result<(), Box<StdError>> {
+  fn write_value(
+    &mut self,
+    code: u16,
+    [v1, v2, v3]: [u8; 3],
+    value: u32,
+  ) -> Result<(), Box<StdError>> {
     let timeout = Duration::from_secs(1);
 
-    let buf = make_packet(&[
-      INDEX,
-      0x35,
-      0x62,
-      0x30,
-      0x30,
-      0x38,
-      0x3e,
-      0x30,
-      0x30,
-      0x30,
-      0x30 + position,
-      0x0d,
-    ]);
-    self
-      .device_handle
-      .write_interrupt(self.out_endpoint.address, &buf, timeout)?;
+    let [c1, c2, c3] = encode_code(code);
+    let buf = make_packet(&[INDEX, 0x35, WRITE, 0x30, 0x30, c1, c2, c3, v1, v2, v3, END]);
+    self.write_interrupt(&buf, timeout)?;
 
     // There is a response but we don't care about it. This is more here
     // for the delay so you can set input and kvm one after another. Without
@@ -254,14 +488,177 @@ impl MSIDevice {
     // Another option is to retry on failure.
     let mut buf = [0x00; 64];
     for _ in 0..5 {
-      self
-        .device_handle
-        .read_interrupt(self.in_endpoint.address, &mut buf, Duration::from_millis(1))
-        .ok();
+      self.read_interrupt(&mut buf, Duration::from_millis(1)).ok();
     }
 
+    setting_changed(self.change(code, value));
     Ok(())
   }
+
+  fn change(&self, code: u16, value: u32) -> SettingChange {
+    match &self.transport {
+      Transport::Usb {
+        device_handle,
+        bus,
+        address,
+        ..
+      } => {
+        let (vendor_id, product_id) = device_handle
+          .device()
+          .device_descriptor()
+          .map_or((0, 0), |d| (d.vendor_id(), d.product_id()));
+        SettingChange {
+          vendor_id,
+          product_id,
+          location: Some((*bus, *address)),
+          code,
+          value,
+        }
+      },
+      Transport::DryRun {
+        vendor_id,
+        product_id,
+        ..
+      } => {
+        SettingChange {
+          vendor_id: *vendor_id,
+          product_id: *product_id,
+          location: None,
+          code,
+          value,
+        }
+      },
+    }
+  }
+
+  pub(crate) fn info(&self) -> DeviceInfo {
+    match &self.transport {
+      Transport::Usb {
+        device_handle, ..
+      } => device_info(&device_handle.device(), Some(device_handle)),
+      Transport::DryRun {
+        vendor_id,
+        product_id,
+        ..
+      } => {
+        DeviceInfo {
+          vendor_id: *vendor_id,
+          product_id: *product_id,
+          bus: 0,
+          address: 0,
+          ports: Vec::new(),
+          manufacturer: None,
+          product: None,
+          serial: None,
+        }
+      },
+    }
+  }
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) struct DeviceInfo {
+  pub(crate) vendor_id: u16,
+  pub(crate) product_id: u16,
+  pub(crate) bus: u8,
+  pub(crate) address: u8,
+  // The hub ports leading to the device, which unlike the address stay the
+  // same when it's plugged in again.
+  #[serde(default)]
+  pub(crate) ports: Vec<u8>,
+  pub(crate) manufacturer: Option<String>,
+  pub(crate) product: Option<String>,
+  pub(crate) serial: Option<String>,
+}
+
+// Lists the connected USB devices from a vendor. The strings are only filled
+// in if we are allowed to open the device.
+pub(crate) fn list_devices(vendor_id: u16) -> Result<Vec<DeviceInfo>, Box<StdError>> {
+  let mut devices = Vec::new();
+  for device in rusb::devices()?.iter() {
+    if device.device_descriptor()?.vendor_id() != vendor_id {
+      continue;
+    }
+    let handle = device.open().ok();
+    devices.push(device_info(&device, handle.as_ref()));
+  }
+  Ok(devices)
+}
+
+fn device_info<T: UsbContext>(device: &Device<T>, handle: Option<&DeviceHandle<T>>) -> DeviceInfo {
+  let desc = device.device_descriptor().ok();
+  let string = |read: fn(&DeviceHandle<T>, &DeviceDescriptor) -> rusb::Result<String>| {
+    handle.zip(desc.as_ref()).and_then(|(h, d)| read(h, d).ok())
+  };
+
+  DeviceInfo {
+    vendor_id: desc.as_ref().map_or(0, |d| d.vendor_id()),
+    product_id: desc.as_ref().map_or(0, |d| d.product_id()),
+    bus: device.bus_number(),
+    address: device.address(),
+    ports: device.port_numbers().unwrap_or_default(),
+    manufacturer: string(DeviceHandle::read_manufacturer_string_ascii),
+    product: string(DeviceHandle::read_product_string_ascii),
+    serial: string(DeviceHandle::read_serial_number_string_ascii),
+  }
+}
+
+impl DeviceInfo {
+  pub(crate) fn is_monitor(&self) -> bool {
+    self.vendor_id == MSI_VENDOR_ID && MONITOR_PRODUCT_IDS.contains(&self.product_id)
+  }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Access {
+  Read,
+  Write,
+}
+
+// A decoded command packet or reply.
+#[derive(Debug)]
+pub(crate) struct Frame {
+  pub(crate) index: u8,
+  pub(crate) access: Access,
+  pub(crate) code: u16,
+  // The value being written, or the value in a reply. Read requests have none.
+  pub(crate) value: Option<u32>,
+}
+
+impl Frame {
+  pub(crate) fn parse(buf: &[u8]) -> Option<Self> {
+    if buf.len() < 9 || buf[1] != 0x35 {
+      return None;
+    }
+
+    let access = match buf[2] {
+      READ => Access::Read,
+      WRITE => Access::Write,
+      _ => return None,
+    };
+    let code = decode_code(buf[5..8].try_into().ok()?)?;
+
+    let value = match buf[8] {
+      END => None,
+      _ => {
+        if buf.get(11) != Some(&END) {
+          return None;
+        }
+        Some(std::str::from_utf8(&buf[8..11]).ok()?.parse().ok()?)
+      },
+    };
+
+    Some(Self {
+      index: buf[0],
+      access,
+      code,
+      value,
+    })
+  }
+
+  pub(crate) fn name(&self) -> Option<&'static str> {
+    code_name(self.code)
+  }
 }
 
 #[derive(Debug)]
@@ -273,6 +670,36 @@ struct Endpoint {
   // interval: u8,
 }
 
+// The value in a reply, which is sent as decimal digits.
+pub(crate) fn reply_value(buf: &[u8; 64]) -> Result<u32, Box<StdError>> {
+  // const BASE_CMD_VALUE: u8 = 48;
+  // let mut num = 0;
+  // for i in 0..RETURN_VALUE_NUM {
+  //   num +=
+  //     10u32.pow(i.try_into().unwrap()) * ((buf[ON_END_INDEX - i as usize] - BASE_CMD_VALUE) as u32);
+  // }
+
+  Ok(std::str::from_utf8(&buf[(ON_END_INDEX - (RETURN_VALUE_NUM - 1))..=ON_END_INDEX])?.parse()?)
+}
+
+// Turns a command code such as 0x8e0 into the three bytes sent on the wire.
+pub(crate) fn encode_code(code: u16) -> [u8; 3] {
+  [
+    0x30 + ((code >> 8) & 0xf) as u8,
+    0x30 + ((code >> 4) & 0xf) as u8,
+    0x30 + (code & 0xf) as u8,
+  ]
+}
+
+pub(crate) fn decode_code(bytes: [u8; 3]) -> Option<u16> {
+  bytes.iter().try_fold(0u16, |code, b| {
+    match b {
+      0x30..=0x3f => Some((code << 4) | (b - 0x30) as u16),
+      _ => None,
+    }
+  })
+}
+
 pub fn make_packet(slice: &[u8]) -> [u8; 64] {
   let mut buffer = [0x00; 64];
   let n = std::cmp::min(buffer.len(), slice.len());
@@ -283,10 +710,14 @@ pub fn make_packet(slice: &[u8]) -> [u8; 64] {
 fn get_device(
   vendor_id: u16,
   product_id: u16,
+  location: Option<(u8, u8)>,
 ) -> Result<Option<Device<GlobalContext>>, Box<StdError>> {
   for _ in 0..3 {
     for device in rusb::devices()?.iter() {
       let device_desc = device.device_descriptor()?;
+      if location.is_some_and(|at| at != (device.bus_number(), device.address())) {
+        continue;
+      }
 
       if device_desc.vendor_id() == vendor_id && device_desc.product_id() == product_id {
         return Ok(Some(device));
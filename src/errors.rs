@@ -1,3 +1,80 @@
 use std::error::Error;
+use std::fmt;
+
+use serde::Deserialize;
+use serde::Serialize;
 
 pub(crate) type StdError = dyn Error + Send + Sync;
+
+// Exit codes for the CLI. 2 is what clap uses for usage errors.
+pub(crate) const EXIT_FAILURE: i32 = 1;
+pub(crate) const EXIT_DEVICE_NOT_FOUND: i32 = 3;
+pub(crate) const EXIT_DEVICE_ERROR: i32 = 4;
+
+#[derive(Debug)]
+pub(crate) struct DeviceNotFound;
+
+impl fmt::Display for DeviceNotFound {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "unable to find device")
+  }
+}
+
+impl Error for DeviceNotFound {}
+
+// What a request to the main loop failed with. It travels with the error
+// over the socket, so callers can still tell a missing monitor from a bad
+// argument, see `kind`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ErrorKind {
+  DeviceNotFound,
+  Device,
+  // Something named in the request, like an action, doesn't exist.
+  NotFound,
+  InvalidArgument,
+  Failed,
+}
+
+#[derive(Debug)]
+pub(crate) struct RequestError {
+  pub(crate) kind: ErrorKind,
+  pub(crate) message: String,
+}
+
+impl RequestError {
+  pub(crate) fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+    RequestError {
+      kind,
+      message: message.into(),
+    }
+  }
+}
+
+impl fmt::Display for RequestError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.message)
+  }
+}
+
+impl Error for RequestError {}
+
+pub(crate) fn kind(err: &StdError) -> ErrorKind {
+  if let Some(err) = err.downcast_ref::<RequestError>() {
+    err.kind
+  } else if err.is::<DeviceNotFound>() {
+    ErrorKind::DeviceNotFound
+  } else if err.is::<rusb::Error>() {
+    ErrorKind::Device
+  } else {
+    ErrorKind::Failed
+  }
+}
+
+pub(crate) fn exit_code(err: &StdError) -> i32 {
+  match kind(err) {
+    ErrorKind::DeviceNotFound => EXIT_DEVICE_NOT_FOUND,
+    ErrorKind::Device => EXIT_DEVICE_ERROR,
+    _ => EXIT_FAILURE,
+  }
+}
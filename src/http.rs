@@ -0,0 +1,417 @@
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::io::Write;
+use std::net::IpAddr;
+use std::thread;
+
+use crossbeam_channel::Sender;
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::json;
+use tiny_http::Header;
+use tiny_http::Method;
+use tiny_http::Response;
+use tiny_http::Server;
+use tracing::Level;
+use tracing::event;
+
+use super::cli;
+use super::config::InputSource;
+use super::daemon;
+use super::device;
+use super::errors;
+use super::errors::StdError;
+use super::switch;
+
+#[derive(Debug, Clone)]
+pub(crate) struct Options {
+  pub(crate) address: String,
+  // Requests must send "Authorization: Bearer <token>" when set.
+  pub(crate) token: Option<String>,
+}
+
+// An error along with the HTTP status to answer with.
+struct HttpError(u16, String);
+
+impl From<Box<StdError>> for HttpError {
+  fn from(err: Box<StdError>) -> Self {
+    let status = match errors::kind(&*err) {
+      errors::ErrorKind::DeviceNotFound | errors::ErrorKind::NotFound => 404,
+      errors::ErrorKind::InvalidArgument => 400,
+      errors::ErrorKind::Device | errors::ErrorKind::Failed => 500,
+    };
+    HttpError(status, err.to_string())
+  }
+}
+
+impl From<std::io::Error> for HttpError {
+  fn from(err: std::io::Error) -> Self {
+    HttpError(500, err.to_string())
+  }
+}
+
+fn bad_request(message: impl Into<String>) -> HttpError {
+  HttpError(400, message.into())
+}
+
+// Starts the HTTP API. Requests are sent to `tx` for the main loop to answer,
+// the same as requests on the local socket.
+pub(crate) fn serve(mut opts: Options, tx: Sender<daemon::Call>) {
+  if opts.token.is_none() {
+    match stored_token() {
+      Ok(token) => opts.token = Some(token),
+      Err(err) => {
+        event!(
+          Level::ERROR,
+          "could not set up an http token, not serving http: {}",
+          err
+        );
+        return;
+      },
+    }
+  }
+
+  let server = match Server::http(&opts.address) {
+    Ok(server) => server,
+    Err(err) => {
+      event!(
+        Level::ERROR,
+        "could not start http server on {}: {}",
+        opts.address,
+        err
+      );
+      return;
+    },
+  };
+  event!(Level::INFO, "http server listening on {}", opts.address);
+
+  thread::spawn(move || {
+    for mut request in server.incoming_requests() {
+      let opts = opts.clone();
+      let tx = tx.clone();
+      thread::spawn(move || {
+        let (status, body) = match handle(&opts, &tx, &mut request) {
+          Ok(body) => (200, body),
+          Err(HttpError(status, message)) => (status, json!({ "error": message })),
+        };
+        let response = Response::from_string(body.to_string())
+          .with_status_code(status)
+          .with_header(Header::from_bytes("Content-Type", "application/json").unwrap());
+        if let Err(err) = request.respond(response) {
+          event!(Level::DEBUG, "http response: {}", err);
+        }
+      });
+    }
+  });
+}
+
+// The token used when none was given. It's kept in the data dir, readable
+// only by the user, so clients keep working across restarts.
+fn stored_token() -> Result<String, Box<StdError>> {
+  let dir = super::project_dirs()?.data_local_dir().to_path_buf();
+  std::fs::create_dir_all(&dir)?;
+  let path = dir.join("http-token");
+
+  match std::fs::read_to_string(&path) {
+    Ok(token) if !token.trim().is_empty() => {
+      event!(Level::INFO, "using the http token in {}", path.display());
+      return Ok(token.trim().to_string());
+    },
+    Ok(_) => {},
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => {},
+    Err(err) => return Err(err.into()),
+  }
+
+  let token = rand::random::<[u8; 32]>()
+    .iter()
+    .map(|b| format!("{:02x}", b))
+    .collect::<String>();
+  let mut options = OpenOptions::new();
+  options.write(true).create(true).truncate(true);
+  #[cfg(not(target_os = "windows"))]
+  {
+    use std::os::unix::fs::OpenOptionsExt;
+    options.mode(0o600);
+  }
+  options.open(&path)?.write_all(token.as_bytes())?;
+  event!(Level::INFO, "generated an http token in {}", path.display());
+  Ok(token)
+}
+
+fn header<'a>(request: &'a tiny_http::Request, name: &'static str) -> Option<&'a str> {
+  request
+    .headers()
+    .iter()
+    .find(|h| h.field.equiv(name))
+    .map(|h| h.value.as_str())
+}
+
+// The name part of a Host header or an address, without the port or the
+// brackets around an IPv6 address.
+fn host_name(host: &str) -> &str {
+  match host.strip_prefix('[') {
+    Some(rest) => rest.split(']').next().unwrap_or(rest),
+    None => host.split(':').next().unwrap_or(host),
+  }
+}
+
+// Web pages can send requests here too. DNS rebinding gets one past the
+// browser's same origin checks by pointing a name the page controls at us,
+// so only names that can't be rebound are accepted: localhost, IP addresses
+// and the name we were told to listen on.
+fn allowed_host(opts: &Options, request: &tiny_http::Request) -> bool {
+  let Some(host) = header(request, "Host") else {
+    return false;
+  };
+  let name = host_name(host);
+  name.eq_ignore_ascii_case("localhost")
+    || name.parse::<IpAddr>().is_ok()
+    || name.eq_ignore_ascii_case(host_name(&opts.address))
+}
+
+// Browsers send Origin with cross site requests. Anything but a page served
+// from this very address is someone else's.
+fn allowed_origin(request: &tiny_http::Request) -> bool {
+  let Some(origin) = header(request, "Origin") else {
+    return true;
+  };
+  let host = header(request, "Host").unwrap_or_default();
+  origin.eq_ignore_ascii_case(&format!("http://{}", host))
+}
+
+// Forms can POST without asking first, but not with a JSON content type.
+fn json_content_type(request: &tiny_http::Request) -> bool {
+  header(request, "Content-Type").is_some_and(|value| {
+    value
+      .split(';')
+      .next()
+      .unwrap_or_default()
+      .trim()
+      .eq_ignore_ascii_case("application/json")
+  })
+}
+
+fn authorized(opts: &Options, request: &tiny_http::Request) -> bool {
+  let Some(token) = &opts.token else {
+    return true;
+  };
+  let expected = format!("Bearer {}", token);
+  request
+    .headers()
+    .iter()
+    .filter(|h| h.field.equiv("Authorization"))
+    .any(|h| constant_time_eq(h.value.as_str().as_bytes(), expected.as_bytes()))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn call(
+  tx: &Sender<daemon::Call>,
+  request: daemon::Request,
+) -> Result<serde_json::Value, HttpError> {
+  Ok(daemon::call(tx, request)?)
+}
+
+// Decodes %XX escapes in a path segment or query value.
+fn percent_decode(s: &str) -> Result<String, HttpError> {
+  let invalid = || bad_request(format!("invalid escape in '{}'", s));
+  let bytes = s.as_bytes();
+  let mut out = Vec::with_capacity(bytes.len());
+  let mut i = 0;
+  while i < bytes.len() {
+    if bytes[i] == b'%' {
+      let hex = bytes.get(i + 1..i + 3).ok_or_else(invalid)?;
+      let hex = std::str::from_utf8(hex).map_err(|_| invalid())?;
+      out.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+      i += 3;
+    } else {
+      out.push(bytes[i]);
+      i += 1;
+    }
+  }
+  String::from_utf8(out).map_err(|_| invalid())
+}
+
+// The largest body read. Requests are small JSON objects, this is only so a
+// client can't make us buffer anything it likes.
+const MAX_BODY: u64 = 64 * 1024;
+
+// Reads the JSON body of a request, if there is one.
+fn body<T: DeserializeOwned>(request: &mut tiny_http::Request) -> Result<T, HttpError> {
+  let too_large = || HttpError(413, format!("the body can be at most {} bytes", MAX_BODY));
+  if request
+    .body_length()
+    .is_some_and(|len| len as u64 > MAX_BODY)
+  {
+    return Err(too_large());
+  }
+  let mut body = String::new();
+  request
+    .as_reader()
+    .take(MAX_BODY + 1)
+    .read_to_string(&mut body)?;
+  if body.len() as u64 > MAX_BODY {
+    return Err(too_large());
+  }
+  let body = if body.trim().is_empty() {
+    "null"
+  } else {
+    &body
+  };
+  serde_json::from_str(body).map_err(|e| bad_request(format!("invalid body: {}", e)))
+}
+
+// The monitor to talk to, from ?vendor_id=&product_id=. Defaults to the
+// same monitor as the CLI.
+fn device_ids(query: &HashMap<String, String>) -> Result<(u16, u16), HttpError> {
+  let id = |name: &str, default: u16| {
+    match query.get(name) {
+      Some(value) => cli::parse_u16(value).map_err(bad_request),
+      None => Ok(default),
+    }
+  };
+  Ok((
+    id("vendor_id", device::MSI_VENDOR_ID)?,
+    id("product_id", 0x3fa4)?,
+  ))
+}
+
+#[derive(Deserialize)]
+struct SetBody {
+  value: u32,
+}
+
+#[derive(Deserialize)]
+struct SwitchBody {
+  // INPUT:KVM, like "3:2", or just INPUT.
+  host: String,
+  ddc_input: Option<InputSource>,
+  serial: Option<String>,
+}
+
+fn handle(
+  opts: &Options,
+  tx: &Sender<daemon::Call>,
+  request: &mut tiny_http::Request,
+) -> Result<serde_json::Value, HttpError> {
+  if !allowed_host(opts, request) {
+    return Err(HttpError(403, "unexpected Host header".into()));
+  }
+  if !allowed_origin(request) {
+    return Err(HttpError(
+      403,
+      "cross origin requests are not allowed".into(),
+    ));
+  }
+  if !authorized(opts, request) {
+    return Err(HttpError(401, "missing or wrong bearer token".into()));
+  }
+  let method = request.method().clone();
+  if matches!(method, Method::Post | Method::Put) && !json_content_type(request) {
+    return Err(HttpError(
+      415,
+      "requests with a body need Content-Type: application/json".into(),
+    ));
+  }
+
+  let url = request.url().to_string();
+  let (path, query) = url.split_once('?').unwrap_or((url.as_str(), ""));
+  let query = query
+    .split('&')
+    .filter_map(|pair| pair.split_once('='))
+    .map(|(key, value)| Ok((percent_decode(key)?, percent_decode(value)?)))
+    .collect::<Result<HashMap<_, _>, HttpError>>()?;
+  let segments = path
+    .trim_matches('/')
+    .split('/')
+    .map(percent_decode)
+    .collect::<Result<Vec<_>, _>>()?;
+  let segments = segments.iter().map(String::as_str).collect::<Vec<_>>();
+
+  match (&method, segments.as_slice()) {
+    (Method::Get, ["monitors"]) => {
+      let devices: Vec<device::DeviceInfo> =
+        serde_json::from_value(call(tx, daemon::Request::ListDevices)?)
+          .map_err(|e| HttpError(500, e.to_string()))?;
+      let monitors = devices
+        .into_iter()
+        .filter(device::DeviceInfo::is_monitor)
+        .collect::<Vec<_>>();
+      Ok(json!({ "devices": monitors }))
+    },
+    (Method::Get, ["settings", setting]) => {
+      let code = cli::parse_code(setting).map_err(bad_request)?;
+      let (vendor_id, product_id) = device_ids(&query)?;
+      let value = call(
+        tx,
+        daemon::Request::Get {
+          vendor_id,
+          product_id,
+          location: None,
+          code,
+        },
+      )?;
+      Ok(json!({ "setting": device::code_name(code), "code": code, "value": value }))
+    },
+    (Method::Put, ["settings", setting]) => {
+      let code = cli::parse_setting(setting).map_err(bad_request)?;
+      let (vendor_id, product_id) = device_ids(&query)?;
+      let SetBody {
+        value,
+      } = body(request)?;
+      call(
+        tx,
+        daemon::Request::Set {
+          vendor_id,
+          product_id,
+          location: None,
+          code,
+          value,
+        },
+      )?;
+      Ok(json!({ "setting": device::code_name(code), "code": code, "value": value }))
+    },
+    (Method::Post, ["switch"]) => {
+      let (vendor_id, product_id) = device_ids(&query)?;
+      let switch_body: SwitchBody = body(request)?;
+      let host = switch::parse_host(&switch_body.host).map_err(bad_request)?;
+      let ddc_input = switch_body
+        .ddc_input
+        .as_ref()
+        .map(InputSource::value)
+        .transpose()
+        .map_err(bad_request)?;
+      let path = call(
+        tx,
+        daemon::Request::Switch {
+          vendor_id,
+          product_id,
+          input: host.input,
+          kvm: host.kvm,
+          ddc_input,
+          serial: switch_body.serial,
+        },
+      )?;
+      Ok(json!({ "path": path, "input": host.input, "kvm": host.kvm }))
+    },
+    (Method::Get, ["actions"]) => {
+      let actions = call(tx, daemon::Request::ListActions)?;
+      Ok(json!({ "actions": actions }))
+    },
+    (Method::Post, ["actions", name]) => {
+      let arg = body(request)?;
+      let result = call(
+        tx,
+        daemon::Request::RunAction {
+          name: name.to_string(),
+          arg,
+        },
+      )?;
+      Ok(json!({ "result": result }))
+    },
+    _ => Err(HttpError(404, format!("no route for {} {}", method, path))),
+  }
+}
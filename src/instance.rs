@@ -0,0 +1,92 @@
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::fs::TryLockError;
+use std::path::Path;
+
+use serde::Deserialize;
+use tracing::Level;
+use tracing::event;
+
+use super::daemon;
+use super::errors::StdError;
+
+// What to do when the script is already running.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub(crate) enum IfRunning {
+  // Exit with an error.
+  Exit,
+  // Ask the running instance to reload its script, then exit.
+  Reload,
+}
+
+// Holds the lock for a script until it is dropped or we exit.
+pub(crate) struct Guard {
+  _file: File,
+}
+
+// Identifies a script across launches. Files are identified by their full
+// path, so starting the same script from another directory still counts.
+pub(crate) fn key(cmd: &str) -> String {
+  let id = match Path::new(cmd).canonicalize() {
+    Ok(path) if path.is_file() => path.to_string_lossy().into_owned(),
+    _ => cmd.to_string(),
+  };
+  // FNV-1a, which unlike DefaultHasher is the same in every build.
+  let hash = id.bytes().fold(0xcbf29ce484222325u64, |hash, b| {
+    (hash ^ b as u64).wrapping_mul(0x100000001b3)
+  });
+  format!("{:016x}", hash)
+}
+
+// Takes the lock for a script. Returns None if another instance holds it.
+pub(crate) fn lock(cmd: &str) -> Result<Option<Guard>, Box<StdError>> {
+  let dir = super::project_dirs()?.data_local_dir().join("instances");
+  std::fs::create_dir_all(&dir)?;
+  let path = dir.join(format!("{}.lock", key(cmd)));
+
+  let file = OpenOptions::new()
+    .create(true)
+    .truncate(false)
+    .write(true)
+    .open(&path)?;
+  match file.try_lock() {
+    Ok(()) => {
+      Ok(Some(Guard {
+        _file: file,
+      }))
+    },
+    Err(TryLockError::WouldBlock) => Ok(None),
+    Err(TryLockError::Error(err)) => Err(err.into()),
+  }
+}
+
+#[derive(Deserialize)]
+struct Status {
+  key: String,
+}
+
+// Called when the lock for `cmd` is taken.
+pub(crate) fn already_running(cmd: &str, if_running: IfRunning) -> Result<(), Box<StdError>> {
+  if if_running == IfRunning::Exit {
+    return Err(format!("{} is already running", cmd).into());
+  }
+
+  // Only the first instance listens for requests, so make sure it's the one
+  // running this script before asking it to reload.
+  let mut client = daemon::connect()
+    .ok_or_else(|| format!("{} is already running, but not listening for requests", cmd))?;
+  let status: Status = serde_json::from_value(client.send(&daemon::Request::Status)?)?;
+  if status.key != key(cmd) {
+    return Err(
+      format!(
+        "{} is already running, but the instance listening for requests runs another script",
+        cmd
+      )
+      .into(),
+    );
+  }
+
+  client.send(&daemon::Request::Reload)?;
+  event!(Level::INFO, "{} is already running, reloaded it", cmd);
+  Ok(())
+}
@@ -3,37 +3,16 @@
   windows_subsystem = "windows"
 )]
 
-use std::collections::HashMap;
 use std::io::IsTerminal;
-use std::str::FromStr;
-use std::sync::Arc;
-use std::sync::Mutex;
-use std::sync::atomic::AtomicBool;
-use std::sync::atomic::AtomicUsize;
-use std::sync::atomic::Ordering;
-use std::thread;
-use std::time::Duration;
 
 use clap::Parser;
-use device_query::DeviceQuery;
+use clap::Subcommand;
 use directories::ProjectDirs;
-use display_info::DisplayInfo;
 use errors::StdError;
-use global_hotkey::GlobalHotKeyEvent;
-use global_hotkey::GlobalHotKeyManager;
-use global_hotkey::HotKeyState;
-use global_hotkey::hotkey::HotKey;
-use mlua::ExternalError;
-use mlua::Function;
 use mlua::Lua;
-use mouse_position::mouse_position::Mouse;
-use nusb::MaybeFuture;
-use nusb::hotplug::HotplugEvent;
 use rfd::MessageButtons;
 use rfd::MessageDialog;
 use rfd::MessageLevel;
-use rustautogui::RustAutoGui;
-use tao::event_loop::ControlFlow;
 use tao::event_loop::EventLoop;
 use tracing::Level;
 use tracing::event;
@@ -42,81 +21,169 @@ use tracing_subscriber::EnvFilter;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 
+mod cli;
+mod config;
+mod daemon;
+#[cfg(target_os = "linux")]
+mod dbus;
+mod ddcci;
 mod device;
 mod errors;
+mod http;
+mod instance;
+mod mccs;
+mod mqtt;
+mod pcap;
+mod repl;
+mod replay;
+mod runtime;
+mod sandbox;
+mod scan;
+mod sequence;
+mod switch;
+mod tasks;
+mod watch;
 
-static INTERVAL_COUNTER: AtomicUsize = AtomicUsize::new(1);
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None, subcommand_negates_reqs = true)]
+struct Args {
+  /// A Lua script, a TOML config, or a string of Lua code. Defaults to
+  /// init.lua or config.toml in the config directory.
+  #[arg(short, long)]
+  cmd: Option<String>,
+  #[arg(long)]
+  console: bool,
+  #[arg(long)]
+  cwd: Option<String>,
+  /// What to do if the same script is already running.
+  #[arg(long, value_enum, default_value_t = instance::IfRunning::Exit)]
+  if_running: instance::IfRunning,
+  /// Serve the HTTP API while in the main loop, on 127.0.0.1:7878 or the
+  /// address given with --http=ADDRESS.
+  #[arg(
+    long,
+    num_args = 0..=1,
+    require_equals = true,
+    default_missing_value = "127.0.0.1:7878"
+  )]
+  http: Option<String>,
+  /// The token HTTP requests must send as "Authorization: Bearer <TOKEN>".
+  /// Without it, one is generated and kept in the data directory.
+  #[arg(long, requires = "http")]
+  http_token: Option<String>,
+  #[command(flatten)]
+  mqtt: mqtt::MqttArgs,
+  /// Don't register com.kdar.MsiMonitorCtrl on the D-Bus session bus
+  /// (linux only).
+  #[arg(long)]
+  no_dbus: bool,
+  /// Vendor id of the monitor the D-Bus service talks to.
+  #[arg(long, default_value = "0x1462", value_parser = cli::parse_u16)]
+  dbus_vendor_id: u16,
+  /// Product id of the monitor the D-Bus service talks to.
+  #[arg(long, default_value = "0x3fa4", value_parser = cli::parse_u16)]
+  dbus_product_id: u16,
+  /// Reload the script when it or a module it required changes. Keeps the
+  /// process in the main loop.
+  #[arg(long)]
+  watch: bool,
+  /// Always run the script in the sandbox, with at most these comma
+  /// separated permissions. What the script declares narrows them, a script
+  /// that declares nothing gets none.
+  #[arg(
+    long,
+    value_name = "PERMISSIONS",
+    num_args = 0..=1,
+    require_equals = true,
+    default_missing_value = ""
+  )]
+  sandbox: Option<String>,
+  /// Record every USB transfer to the monitor into a pcapng file.
+  #[arg(long, global = true)]
+  trace_packets: Option<std::path::PathBuf>,
+  /// Log device commands instead of sending them to the monitor.
+  #[arg(long, global = true)]
+  dry_run: bool,
+  /// Output format for one-shot commands. JSON output, including errors, is
+  /// versioned with a top-level "version" field.
+  #[arg(long, global = true, value_enum, default_value_t = cli::Format::Text)]
+  format: cli::Format,
+  #[command(subcommand)]
+  command: Option<Command>,
+}
 
-fn get_interval_id() -> usize {
-  INTERVAL_COUNTER.fetch_add(1, Ordering::Relaxed)
+#[derive(Subcommand, Debug)]
+enum Command {
+  /// Send read requests across a range of command codes and record which
+  /// ones answer. Never writes to the monitor.
+  Scan(scan::ScanArgs),
+  /// List the commands sent to MSI devices in a usbmon or USBPcap capture,
+  /// and optionally send some of them to the monitor.
+  Replay(replay::ReplayArgs),
+  /// List the connected MSI devices.
+  List,
+  /// Print the value of a setting.
+  Get(cli::GetArgs),
+  /// Change a setting.
+  Set(cli::SetArgs),
+  /// Switch the monitor to another host by setting the input, then the KVM.
+  Switch(cli::SwitchArgs),
+  /// Print information about the monitor and its current settings.
+  Info(cli::InfoArgs),
+  /// List the hotkeys registered by the running main loop.
+  Hotkeys,
+  /// Ask the running main loop to run its script again.
+  Reload,
+  /// An interactive Lua prompt with the same API as scripts.
+  Repl(repl::ReplArgs),
+  /// Run a Lua script, a TOML config, or a string of Lua code. Same as
+  /// --cmd.
+  Run { script: String },
 }
 
-// Returns "n", "s", "w", "e", "ne", "nw", "se", "sw" if the mouse is at a
-// screen edge/corner where it cannot move further in that direction across
-// any of the displays. Returns None otherwise.
-fn find_screen_edge(displays: &[DisplayInfo], x: i32, y: i32) -> Option<&'static str> {
-  let in_display = |px: i32, py: i32| -> bool {
-    displays.iter().any(|d| {
-      let dw = d.width as i32;
-      let dh = d.height as i32;
-      px >= d.x && px < d.x + dw && py >= d.y && py < d.y + dh
+// Returns where in the script the currently running Rust function was
+// called from.
+fn lua_location(lua: &Lua) -> String {
+  // Device calls go through the wrappers in tasks.rs, the script is further
+  // up.
+  (1..)
+    .map_while(|level| {
+      lua.inspect_stack(level, |debug| {
+        let source = debug.source();
+        (source.source.as_deref() != Some(tasks::CHUNK_NAME)).then(|| {
+          format!(
+            "{}:{}",
+            source.short_src.as_deref().unwrap_or("?"),
+            debug.current_line().unwrap_or(0)
+          )
+        })
+      })
     })
-  };
-
-  if !in_display(x, y) {
-    return None;
-  }
+    .flatten()
+    .next()
+    .unwrap_or_else(|| "?".into())
+}
 
-  let at_left = !in_display(x - 1, y);
-  let at_right = !in_display(x + 1, y);
-  let at_top = !in_display(x, y - 1);
-  let at_bottom = !in_display(x, y + 1);
-
-  match (at_top, at_bottom, at_left, at_right) {
-    (true, _, true, _) => Some("nw"),
-    (true, _, _, true) => Some("ne"),
-    (_, true, true, _) => Some("sw"),
-    (_, true, _, true) => Some("se"),
-    (true, ..) => Some("n"),
-    (_, true, ..) => Some("s"),
-    (_, _, true, _) => Some("w"),
-    (_, _, _, true) => Some("e"),
-    _ => None,
+// Logs a device command in dry run mode, along with the script location that
+// triggered it.
+fn log_dry_run(lua: &Lua, command: std::fmt::Arguments) {
+  if device::is_dry_run() {
+    event!(
+      Level::INFO,
+      location = lua_location(lua),
+      "dry run: {}",
+      command
+    );
   }
 }
 
-// We wrap GlobalHotKeyManager so we can send it across threads. This is
-// safe to do for specific windows pointers like HWND since
-// it is unique globally.
-struct WrappedHotKeyManager(GlobalHotKeyManager);
-
-#[cfg(target_os = "windows")]
-unsafe impl Send for WrappedHotKeyManager {}
-#[cfg(target_os = "windows")]
-unsafe impl Sync for WrappedHotKeyManager {}
-
-// We wrap RustAutoGui so we can send it across threads. This is
-// safe to do for specific windows pointers like HWND since
-// it is unique globally.
-struct WrappedRustAutoGui(rustautogui::RustAutoGui);
-
-#[cfg(target_os = "windows")]
-unsafe impl Send for WrappedRustAutoGui {}
-#[cfg(target_os = "windows")]
-unsafe impl Sync for WrappedRustAutoGui {}
-
-#[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
-struct Args {
-  #[arg(short, long)]
-  cmd: String,
-  #[arg(long)]
-  console: bool,
-  #[arg(long)]
-  cwd: Option<String>,
+// Where to log a dry run command from once its job is done, which is too
+// late to find the script location.
+fn dry_run_location(lua: &Lua) -> Option<String> {
+  device::is_dry_run().then(|| lua_location(lua))
 }
 
-impl mlua::UserData for device::MSIDevice {
+impl mlua::UserData for tasks::Shared<device::MSIDevice> {
   // fn add_fields<F: mlua::UserDataFields<Self>>(fields: &mut F) {
   //   // fields.add_field_method_get("val", |_, this| Ok(this.0));
   //   fields.add_field_method_get("code", |_, this| Ok(Code));
@@ -130,17 +197,37 @@ impl mlua::UserData for device::MSIDevice {
     //   Ok(val)
     // });
 
-    methods.add_method_mut("get_kvm", |_, this, ()| -> Result<u32, mlua::Error> {
-      let val = this.get_kvm().map_err(mlua::ExternalError::into_lua_err)?;
-      Ok(val)
-    });
+    methods.add_method(
+      "get_kvm",
+      |lua, this, ()| -> Result<tasks::Job, mlua::Error> {
+        let location = dry_run_location(lua);
+        Ok(this.job_map(
+          |dev| dev.get_kvm(),
+          move |_, val| {
+            if let Some(location) = location {
+              event!(Level::INFO, location, "dry run: get_kvm() -> {}", val);
+            }
+            Ok(val)
+          },
+        ))
+      },
+    );
 
-    methods.add_method_mut("get_input", |_, this, ()| -> Result<u32, mlua::Error> {
-      let val = this
-        .get_input()
-        .map_err(mlua::ExternalError::into_lua_err)?;
-      Ok(val)
-    });
+    methods.add_method(
+      "get_input",
+      |lua, this, ()| -> Result<tasks::Job, mlua::Error> {
+        let location = dry_run_location(lua);
+        Ok(this.job_map(
+          |dev| dev.get_input(),
+          move |_, val| {
+            if let Some(location) = location {
+              event!(Level::INFO, location, "dry run: get_input() -> {}", val);
+            }
+            Ok(val)
+          },
+        ))
+      },
+    );
 
     // methods.add_method_mut(
     //   "set_volume",
@@ -152,51 +239,151 @@ impl mlua::UserData for device::MSIDevice {
     //   },
     // );
 
-    methods.add_method_mut(
+    methods.add_method(
       "set_kvm",
-      |_, this, position: u8| -> Result<(), mlua::Error> {
-        this
-          .set_kvm(position)
-          .map_err(mlua::ExternalError::into_lua_err)?;
-        Ok(())
+      |lua, this, position: u8| -> Result<tasks::Job, mlua::Error> {
+        log_dry_run(lua, format_args!("set_kvm({})", position));
+        Ok(this.job(move |dev| dev.set_kvm(position)))
       },
     );
 
-    methods.add_method_mut(
+    methods.add_method(
       "set_input",
-      |_, this, position: u8| -> Result<(), mlua::Error> {
-        this
-          .set_input(position)
-          .map_err(mlua::ExternalError::into_lua_err)?;
-        Ok(())
+      |lua, this, position: u8| -> Result<tasks::Job, mlua::Error> {
+        log_dry_run(lua, format_args!("set_input({})", position));
+        Ok(this.job(move |dev| dev.set_input(position)))
       },
     );
   }
 }
 
-fn run() -> Result<(), Box<StdError>> {
+// Takes a VCP 0x60 input source as either a number or a name like "HDMI-1".
+fn lua_input_source(lua: &Lua, source: mlua::Value) -> Result<u8, mlua::Error> {
+  match source {
+    mlua::Value::String(name) => {
+      let name = name.to_str()?;
+      mccs::input_source_value(&name)
+        .ok_or_else(|| mlua::Error::external(format!("unknown input source: {}", name)))
+    },
+    other => lua.unpack::<u8>(other),
+  }
+}
+
+impl mlua::UserData for tasks::Shared<ddcci::DdcMonitor> {
+  fn add_fields<F: mlua::UserDataFields<Self>>(fields: &mut F) {
+    fields.add_field_method_get("description", |_, this| {
+      Ok(this.lock().description().to_string())
+    });
+    fields.add_field_method_get("serial", |_, this| Ok(this.lock().serial()));
+    fields.add_field_method_get("manufacturer", |_, this| Ok(this.lock().manufacturer()));
+  }
+
+  fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+    methods.add_method(
+      "get_vcp",
+      |_, this, code: u8| -> Result<tasks::Job, mlua::Error> {
+        Ok(this.job(move |monitor| monitor.get_vcp(code)))
+      },
+    );
+
+    methods.add_method(
+      "set_vcp",
+      |lua, this, (code, value): (u8, u16)| -> Result<tasks::Job, mlua::Error> {
+        let dry_run = device::is_dry_run();
+        log_dry_run(lua, format_args!("set_vcp({:#04x}, {})", code, value));
+        Ok(this.job(move |monitor| {
+          if dry_run {
+            return Ok(());
+          }
+          monitor.set_vcp(code, value)
+        }))
+      },
+    );
+
+    methods.add_method(
+      "capabilities",
+      |_, this, ()| -> Result<tasks::Job, mlua::Error> {
+        Ok(this.job(|monitor| monitor.capabilities()))
+      },
+    );
+
+    methods.add_method(
+      "parsed_capabilities",
+      |_, this, ()| -> Result<tasks::Job, mlua::Error> {
+        Ok(this.job_map(
+          |monitor| Ok(monitor.parsed_capabilities()?.clone()),
+          |lua, caps| -> Result<mlua::Table, mlua::Error> {
+            let table = lua.create_table()?;
+            table.set("protocol", caps.protocol)?;
+            table.set("type", caps.display_type)?;
+            table.set("model", caps.model)?;
+            table.set("mccs_version", caps.mccs_version)?;
+            let vcp = lua.create_table()?;
+            for (code, values) in caps.vcp {
+              vcp.set(code, values)?;
+            }
+            table.set("vcp", vcp)?;
+            Ok(table)
+          },
+        ))
+      },
+    );
+
+    methods.add_method("inputs", |_, this, ()| -> Result<tasks::Job, mlua::Error> {
+      Ok(this.job_map(
+        |monitor| Ok(monitor.parsed_capabilities()?.input_sources()),
+        |lua, sources| -> Result<Vec<mlua::Table>, mlua::Error> {
+          sources
+            .into_iter()
+            .map(|(value, name)| {
+              let source = lua.create_table()?;
+              source.set("value", value)?;
+              source.set("name", name)?;
+              Ok(source)
+            })
+            .collect()
+        },
+      ))
+    });
+
+    methods.add_method(
+      "get_input",
+      |_, this, ()| -> Result<tasks::Job, mlua::Error> {
+        Ok(this.job(|monitor| {
+          let value = monitor.get_input()?;
+          Ok((value, mccs::input_source_name(value)))
+        }))
+      },
+    );
+
+    methods.add_method(
+      "set_input",
+      |lua, this, source: mlua::Value| -> Result<tasks::Job, mlua::Error> {
+        let source = lua_input_source(lua, source)?;
+        let dry_run = device::is_dry_run();
+        log_dry_run(lua, format_args!("set_input({:#04x})", source));
+        Ok(this.job(move |monitor| {
+          if dry_run {
+            return Ok(());
+          }
+          monitor.set_input(source)
+        }))
+      },
+    );
+  }
+}
+
+fn run(args: Args) -> Result<(), Box<StdError>> {
   // let _ = std::process::Command::new("cmd.exe")
   //   .arg("/c")
   //   .arg("pause")
   //   .status();
 
-  // use ddc::Ddc;
-  // use ddc_winapi::Monitor;
-  // for mut ddc in Monitor::enumerate().unwrap() {
-  //   println!("{}", ddc.description());
-  //   let s = ddc.capabilities_string().unwrap();
-  //   println!("{}", String::from_utf8_lossy(&s));
-  //   let v = ddc.get_vcp_feature(0xe3).unwrap();
-  //   println!("{:?}", v);
-  // }
-
   // let mut dev = device::MSIDevice::open(0x1462, 0x3fa4)?;
   // dev.test()?;
 
   // return Ok(());
 
-  let args = Args::try_parse()?;
-
   if let Some(cwd) = args.cwd {
     std::env::set_current_dir(cwd)?;
   }
@@ -208,443 +395,106 @@ fn run() -> Result<(), Box<StdError>> {
     }
   }
 
-  let event_loop = EventLoop::new();
-
-  let lua = Lua::new();
-
-  let hotkeys_manager = GlobalHotKeyManager::new()?;
-  let global_hotkey_channel = GlobalHotKeyEvent::receiver();
-  let hotplug = Arc::new(Mutex::new(None));
-
-  let (hotplug_tx, hotplug_rx) = crossbeam_channel::unbounded();
-  std::thread::spawn(move || {
-    let watch = nusb::watch_devices().unwrap();
-    for event in futures_lite::stream::block_on(watch) {
-      hotplug_tx.send(event).unwrap();
-    }
-  });
-
-  let device_open = lua.create_function(
-    |_, (vendor_id, product_id): (u16, u16)| -> Result<device::MSIDevice, mlua::Error> {
-      let dev = device::MSIDevice::open(vendor_id, product_id)
-        .map_err(mlua::ExternalError::into_lua_err)?;
-      Ok(dev)
-    },
-  )?;
-
-  let device_is_connected = lua.create_function(
-    |_, (vendor_id, product_id): (u16, u16)| -> Result<bool, mlua::Error> {
-      let connected = device::MSIDevice::is_connected(vendor_id, product_id)
-        .map_err(mlua::ExternalError::into_lua_err)?;
-      Ok(connected)
-    },
-  )?;
-
-  let sleep_ms = lua.create_function(|_, duration: u64| -> Result<(), mlua::Error> {
-    thread::sleep(Duration::from_millis(duration));
-    Ok(())
-  })?;
-
-  let hotkeys = Arc::new(Mutex::new(HashMap::new()));
-
-  let hotkeys_clone = hotkeys.clone();
-  let hk_manager = Arc::new(Mutex::new(WrappedHotKeyManager(hotkeys_manager)));
-  let register_hotkey = lua.create_function(
-    move |_, (keybind, callback): (String, Function)| -> Result<(), mlua::Error> {
-      let hotkey = HotKey::from_str(&keybind).map_err(mlua::ExternalError::into_lua_err)?;
-
-      let hk_manager = hk_manager.lock().unwrap();
-      hk_manager
-        .0
-        .register(hotkey)
-        .map_err(mlua::ExternalError::into_lua_err)?;
+  if let Some(path) = &args.trace_packets {
+    pcap::start_trace(path)?;
+  }
 
-      let mut hk = hotkeys_clone.lock().unwrap();
-      hk.insert(hotkey, callback);
-      Ok(())
-    },
-  )?;
+  if args.dry_run {
+    device::enable_dry_run();
+  }
 
-  let hotplug_clone = hotplug.clone();
-  let register_hotplug =
-    lua.create_function(move |_, callback: Function| -> Result<(), mlua::Error> {
-      let mut hp = hotplug_clone.lock().unwrap();
-      *hp = Some(callback);
-      Ok(())
-    })?;
-
-  let screen_edge: Arc<Mutex<Option<Function>>> = Arc::new(Mutex::new(None));
-  let screen_edge_clone = screen_edge.clone();
-  let register_screen_edge =
-    lua.create_function(move |_, callback: Function| -> Result<(), mlua::Error> {
-      let mut se = screen_edge_clone.lock().unwrap();
-      *se = Some(callback);
-      Ok(())
-    })?;
-
-  let interval_callbacks = Arc::new(Mutex::new(HashMap::new()));
-  let interval_callbacks_clone = interval_callbacks.clone();
-  let register_interval = lua.create_function(
-    move |_,
-          (lo_interval, hi_interval, callback): (u64, u64, Function)|
-          -> Result<usize, mlua::Error> {
-      if lo_interval > hi_interval {
-        return Err(mlua::Error::external("lo_interval must be <= hi_interval"));
+  let cmd = match args.command {
+    Some(Command::Scan(scan_args)) => return scan::run(scan_args),
+    Some(Command::Replay(replay_args)) => return replay::run(replay_args),
+    Some(Command::List) => return cli::list(args.format),
+    Some(Command::Get(get_args)) => return cli::get(get_args, args.format),
+    Some(Command::Set(set_args)) => return cli::set(set_args, args.format),
+    Some(Command::Switch(switch_args)) => return cli::switch(switch_args, args.format),
+    Some(Command::Info(info_args)) => return cli::info(info_args, args.format),
+    Some(Command::Hotkeys) => return cli::hotkeys(args.format),
+    Some(Command::Reload) => return cli::reload(args.format),
+    Some(Command::Repl(repl_args)) => return repl::run(repl_args),
+    Some(Command::Run {
+      script,
+    }) => script,
+    None => {
+      match args.cmd {
+        Some(cmd) => cmd,
+        None => default_script()?,
       }
-
-      let mut ic = interval_callbacks_clone.lock().unwrap();
-      let id = get_interval_id();
-      let interval = rand::random_range(lo_interval..=hi_interval);
-      let next = std::time::Instant::now() + Duration::from_millis(interval);
-      ic.insert(id, (callback, (lo_interval, hi_interval), next));
-      Ok(id)
-    },
-  )?;
-
-  let interval_callbacks_clone = interval_callbacks.clone();
-  let unregister_interval =
-    lua.create_function(move |_, id: usize| -> Result<(), mlua::Error> {
-      let mut ic = interval_callbacks_clone.lock().unwrap();
-      ic.remove(&id);
-      Ok(())
-    })?;
-
-  let msgbox = lua.create_function(
-    move |_,
-          (title, message, level, buttoncfg): (
-      String,
-      String,
-      String,
-      HashMap<String, Vec<String>>,
-    )|
-          -> Result<(), mlua::Error> {
-      let level = match level.to_lowercase().as_str() {
-        "info" | "" => MessageLevel::Info,
-        "warning" => MessageLevel::Warning,
-        "error" => MessageLevel::Error,
-        _ => {
-          return Err(mlua::Error::external(format!(
-            "unknown msgbox level: {}",
-            level
-          )));
-        },
-      };
-
-      let mut buttons = buttoncfg.into_iter();
-      let button_opt = buttons.next().unwrap_or(("Ok".into(), vec![]));
-      let button_opt_len = button_opt.1.len();
-      let mut x = button_opt.1.into_iter();
-      let btns = match (button_opt.0.as_str(), button_opt_len) {
-        ("Ok", 0) => MessageButtons::Ok,
-        ("Ok", 1) => MessageButtons::OkCustom(x.next().unwrap()),
-        ("OkCancel", 0) => MessageButtons::OkCancel,
-        ("OkCancel", 1) => MessageButtons::OkCancelCustom(x.next().unwrap(), "Cancel".into()),
-        ("OkCancel", 2) => MessageButtons::OkCancelCustom(x.next().unwrap(), x.next().unwrap()),
-        ("YesNo", 0) => MessageButtons::YesNo,
-        ("YesNoCancel", 0) => MessageButtons::YesNoCancel,
-        ("YesNoCancel", 1) => {
-          MessageButtons::YesNoCancelCustom(x.next().unwrap(), "No".into(), "Cancel".into())
-        },
-        ("YesNoCancel", 2) => {
-          MessageButtons::YesNoCancelCustom(x.next().unwrap(), x.next().unwrap(), "Cancel".into())
-        },
-        ("YesNoCancel", 3) => {
-          MessageButtons::YesNoCancelCustom(x.next().unwrap(), x.next().unwrap(), x.next().unwrap())
-        },
-        (type_, _) => {
-          return Err(mlua::Error::external(format!(
-            "unknown msgbox buttons: {}={}",
-            type_,
-            x.as_slice().join(","),
-          )));
-        },
-      };
-
-      let dialog = MessageDialog::new()
-        .set_title(title)
-        .set_description(message)
-        .set_buttons(btns)
-        .set_level(level);
-      dialog.show();
-      Ok(())
     },
-  )?;
-
-  let devices: Arc<Mutex<HashMap<nusb::DeviceId, nusb::DeviceInfo>>> = Arc::new(Mutex::new(
-    nusb::list_devices()
-      .wait()
-      .unwrap()
-      .map(|d| (d.id(), d))
-      .collect(),
-  ));
-
-  let autorun = lua.create_function(
-    |_, (app_path, args): (Option<String>, Option<Vec<String>>)| -> Result<(), mlua::Error> {
-      let mut autolaunch = auto_launch::AutoLaunchBuilder::new();
-
-      autolaunch
-        .set_app_name(env!("CARGO_CRATE_NAME"))
-        .set_macos_launch_mode(auto_launch::MacOSLaunchMode::LaunchAgent);
-
-      match (app_path, args) {
-        (Some(app_path), Some(args)) => {
-          autolaunch
-            .set_app_path(app_path.as_ref())
-            .set_args(args.as_ref());
-        },
-        (Some(app_path), None) => {
-          autolaunch.set_app_path(app_path.as_ref()).set_args(
-            &std::env::args()
-              .skip(1)
-              .map(|v| format!(r#""{}""#, v))
-              .collect::<Vec<_>>(),
-          );
-        },
-        (None, Some(args)) => {
-          autolaunch
-            .set_app_path(std::env::current_exe()?.to_str().unwrap())
-            .set_args(args.as_ref());
-        },
-        (None, None) => {
-          autolaunch
-            .set_app_path(std::env::current_exe()?.to_str().unwrap())
-            .set_args(
-              &std::env::args()
-                .skip(1)
-                .map(|v| format!(r#""{}""#, v))
-                .collect::<Vec<_>>(),
-            );
-        },
-      };
+  };
 
-      let autolaunch = autolaunch.build().map_err(|e| e.into_lua_err())?;
-      autolaunch.enable().map_err(|e| e.into_lua_err())?;
+  // Two copies of a script would both run it, writing to the monitor twice
+  // and registering the same hotkeys, so the lock is taken before any of it
+  // runs. Scripts that turn out not to need the main loop give it back once
+  // they are done loading.
+  let Some(guard) = instance::lock(&cmd)? else {
+    return instance::already_running(&cmd, args.if_running);
+  };
 
-      Ok(())
-    },
-  )?;
+  let event_loop = EventLoop::new();
 
-  let rustautogui = Arc::new(Mutex::new(WrappedRustAutoGui(
-    RustAutoGui::new(false).map_err(|e| e.into_lua_err())?,
-  )));
-  let rustautogui_clone = rustautogui.clone();
-  let screen_size = lua.create_function(move |_, ()| -> Result<(i32, i32), mlua::Error> {
-    let mut rag = rustautogui_clone.lock().unwrap();
-    Ok(rag.0.get_screen_size())
-  })?;
-  let rustautogui_clone = rustautogui.clone();
-  let move_mouse = lua.create_function(
-    move |_, (x, y, moving_time, mode): (i64, i64, f32, String)| -> Result<(), mlua::Error> {
-      // Anything > 10.0 is VERY slow and can lock your computer.
-      let moving_time = if moving_time > 10.0 {
-        10.0
-      } else {
-        moving_time
-      };
-
-      let rag = rustautogui_clone.lock().unwrap();
-      match mode.as_str() {
-        "rel" => {
-          rag
-            .0
-            .move_mouse(
-              i32::try_from(x).map_err(|e| e.into_lua_err())?,
-              i32::try_from(y).map_err(|e| e.into_lua_err())?,
-              moving_time,
-            )
-            .map_err(|e| e.into_lua_err())?;
-        },
-        "abs" => {
-          rag
-            .0
-            .move_mouse_to_pos(
-              u32::try_from(x).map_err(|e| e.into_lua_err())?,
-              u32::try_from(y).map_err(|e| e.into_lua_err())?,
-              moving_time,
-            )
-            .map_err(|e| e.into_lua_err())?;
-        },
-        _ => {
-          return Err(mlua::Error::external(format!(
-            "unknown move_mouse mode: {}",
-            mode
-          )));
-        },
-      };
-      Ok(())
-    },
+  let http = args.http.map(|address| {
+    http::Options {
+      address,
+      token: args.http_token,
+    }
+  });
+  let watch = args.watch;
+  let ctx = runtime::Context::new(
+    cmd.clone(),
+    http,
+    args.mqtt.options()?,
+    (!args.no_dbus).then_some((args.dbus_vendor_id, args.dbus_product_id)),
+    watch,
+    args
+      .sandbox
+      .as_deref()
+      .map(sandbox::parse_list)
+      .transpose()?,
   )?;
+  let runtime = runtime::Runtime::load(&ctx)?;
 
-  static DO_MAIN_LOOP: AtomicBool = AtomicBool::new(false);
-
-  let main_loop = lua.create_function(move |_, ()| -> Result<(), mlua::Error> {
-    DO_MAIN_LOOP.swap(true, Ordering::Relaxed);
-    Ok(())
-  })?;
-
-  let globals = lua.globals();
-  globals.set("device_open", &device_open)?;
-  globals.set("device_is_connected", &device_is_connected)?;
-  globals.set("msgbox", &msgbox)?;
-  globals.set("sleep_ms", &sleep_ms)?;
-  globals.set("register_hotkey", &register_hotkey)?;
-  globals.set("register_hotplug", &register_hotplug)?;
-  globals.set("register_screen_edge", &register_screen_edge)?;
-  globals.set("main_loop", &main_loop)?;
-  globals.set("host_os", std::env::consts::OS)?;
-  globals.set("host_arch", std::env::consts::ARCH)?;
-  globals.set("host_family", std::env::consts::FAMILY)?;
-  globals.set("autorun", autorun)?;
-  globals.set("register_interval", &register_interval)?;
-
-  globals.set("unregister_interval", &unregister_interval)?;
-  globals.set("move_mouse", &move_mouse)?;
-  globals.set("screen_size", &screen_size)?;
-
-  let cmd_path = std::path::Path::new(&args.cmd);
-  if cmd_path.is_file() {
-    let source = std::fs::read_to_string(cmd_path)
-      .map_err(|e| mlua::Error::RuntimeError(format!("could not read '{}': {}", args.cmd, e)))?;
-    lua.load(&source).set_name(&args.cmd).exec()?;
-  } else {
-    lua.load(&args.cmd).exec()?;
+  if watch || runtime::wants_main_loop() {
+    runtime::run_main_loop(event_loop, ctx, runtime);
   }
+  drop(guard);
 
-  if DO_MAIN_LOOP.load(Ordering::Relaxed) {
-    event!(Level::INFO, "starting main loop");
-    let hotkeys_clone = hotkeys.clone();
-    let hotplug_clone = hotplug.clone();
-    let devices_clone = devices.clone();
-    let interval_callbacks_clone = interval_callbacks.clone();
-    let screen_edge_clone = screen_edge.clone();
-    let rx = hotplug_rx.clone();
-    let mut last_screen_edge: Option<&'static str> = None;
-    let mut last_edge_check = std::time::Instant::now();
-    let mut cached_displays: Vec<DisplayInfo> = Vec::new();
-    let mut last_displays_refresh: Option<std::time::Instant> = None;
-    event_loop.run(move |_, _, control_flow| {
-      *control_flow = ControlFlow::Poll;
-      // *control_flow = ControlFlow::WaitUntil(Instant::now() + Duration::from_millis(100));
-
-      if screen_edge_clone.lock().unwrap().is_some()
-        && last_edge_check.elapsed() >= Duration::from_millis(50)
-      {
-        last_edge_check = std::time::Instant::now();
-
-        let needs_refresh =
-          last_displays_refresh.map_or(true, |t| t.elapsed() >= Duration::from_secs(5));
-        if needs_refresh {
-          if let Ok(d) = DisplayInfo::all() {
-            cached_displays = d;
-          }
-          last_displays_refresh = Some(std::time::Instant::now());
-        }
-
-        let edge = match Mouse::get_mouse_position() {
-          Mouse::Position {
-            x,
-            y,
-          } => find_screen_edge(&cached_displays, x, y),
-          Mouse::Error => None,
-        };
-        if edge != last_screen_edge {
-          if let Some(e) = edge
-            && let Some(cb) = screen_edge_clone.lock().unwrap().as_ref()
-          {
-            if let Err(err) = cb.call::<()>((e,)) {
-              event!(Level::ERROR, "screen_edge callback: {}", err);
-            }
-          }
-          last_screen_edge = edge;
-        }
-      }
-
-      if let Ok(mut ic) = interval_callbacks_clone.lock() {
-        for (_k, v) in &mut *ic {
-          if std::time::Instant::now() >= v.2 {
-            if let Err(err) = v.0.call::<()>(()) {
-              event!(Level::ERROR, "interval callback: {}", err);
-            }
-            let interval = rand::random_range(v.1.0..=v.1.1);
-            v.2 = std::time::Instant::now() + std::time::Duration::from_millis(interval);
-          }
-        }
-      }
+  Ok(())
+}
 
-      if let Ok(hk_event) = global_hotkey_channel.try_recv() {
-        let hk = hotkeys_clone.lock().unwrap();
-        for (hk, callback) in hk.iter() {
-          if hk.id() == hk_event.id() && hk_event.state == HotKeyState::Released {
-            if let Err(err) = callback.call::<()>((hk.to_string(),)) {
-              event!(Level::ERROR, "hotkey callback: {}", err);
-            }
-          }
-        }
-      }
+fn project_dirs() -> Result<ProjectDirs, Box<StdError>> {
+  let dirs = ProjectDirs::from("com", "kdar", env!("CARGO_CRATE_NAME"))
+    .ok_or("could not find project dir")?;
+  Ok(dirs)
+}
 
-      if let Ok(hotplug_event) = rx.try_recv() {
-        let hp = hotplug_clone.lock().unwrap();
-        if let HotplugEvent::Connected(_) = hotplug_event {
-          // When we connect again, make sure all our modifier keys are not pressed down.
-          let device_state = device_query::DeviceState::new();
-          let keys = device_state.get_keys();
-          if keys.contains(&device_query::Keycode::LAlt) {
-            rdev::simulate(&rdev::EventType::KeyRelease(rdev::Key::Alt)).unwrap();
-          }
-          if keys.contains(&device_query::Keycode::RAlt) {
-            rdev::simulate(&rdev::EventType::KeyRelease(rdev::Key::AltGr)).unwrap();
-          }
-          if keys.contains(&device_query::Keycode::LControl) {
-            rdev::simulate(&rdev::EventType::KeyRelease(rdev::Key::ControlLeft)).unwrap();
-          }
-          if keys.contains(&device_query::Keycode::RControl) {
-            rdev::simulate(&rdev::EventType::KeyRelease(rdev::Key::ControlRight)).unwrap();
-          }
-          if keys.contains(&device_query::Keycode::LShift) {
-            rdev::simulate(&rdev::EventType::KeyRelease(rdev::Key::ShiftLeft)).unwrap();
-          }
-          if keys.contains(&device_query::Keycode::RShift) {
-            rdev::simulate(&rdev::EventType::KeyRelease(rdev::Key::ShiftRight)).unwrap();
-          }
-          if keys.contains(&device_query::Keycode::LMeta) {
-            rdev::simulate(&rdev::EventType::KeyRelease(rdev::Key::MetaLeft)).unwrap();
-          }
-          if keys.contains(&device_query::Keycode::RMeta) {
-            rdev::simulate(&rdev::EventType::KeyRelease(rdev::Key::MetaRight)).unwrap();
-          }
-        }
-
-        if let Some(cb) = hp.clone() {
-          match hotplug_event {
-            HotplugEvent::Connected(d) => {
-              if let Err(err) = cb.call::<()>(("connected", d.vendor_id(), d.product_id())) {
-                event!(Level::ERROR, "hotplug connected callback: {}", err);
-              }
-              let mut devices = devices_clone.lock().unwrap();
-              devices.insert(d.id(), d);
-            },
-            HotplugEvent::Disconnected(id) => {
-              let mut devices = devices_clone.lock().unwrap();
-              if let Some(d) = devices.get(&id) {
-                if let Err(err) = cb.call::<()>(("disconnected", d.vendor_id(), d.product_id())) {
-                  event!(Level::ERROR, "hotplug disconnected callback: {}", err);
-                }
-                devices.remove(&id);
-              }
-            },
-          };
-        };
-      }
-    });
+// Names looked for in the config directory when no script is given, in
+// order.
+const DEFAULT_SCRIPTS: &[&str] = &["init.lua", "config.toml"];
+
+fn default_script() -> Result<String, Box<StdError>> {
+  let config_dir = project_dirs()?.config_dir().to_path_buf();
+  for name in DEFAULT_SCRIPTS {
+    let path = config_dir.join(name);
+    if path.is_file() {
+      event!(Level::INFO, "using {}", path.display());
+      return Ok(path.to_string_lossy().into_owned());
+    }
   }
-
-  Ok(())
+  Err(
+    format!(
+      "no --cmd given and no {} in {}",
+      DEFAULT_SCRIPTS.join(" or "),
+      config_dir.display()
+    )
+    .into(),
+  )
 }
 
 fn setup_logging() -> Result<(), Box<StdError>> {
-  let project_dirs = ProjectDirs::from("com", "kdar", env!("CARGO_CRATE_NAME"))
-    .ok_or("could not find project dir")?;
-  let config_dir = project_dirs.data_local_dir().to_path_buf();
+  let config_dir = project_dirs()?.data_local_dir().to_path_buf();
 
   let file_appender = tracing_appender::rolling::never(&config_dir, "app.log");
 
@@ -656,7 +506,11 @@ fn setup_logging() -> Result<(), Box<StdError>> {
         .with_line_number(true)
         .with_ansi(false),
     )
-    .with(tracing_subscriber::fmt::layer().pretty())
+    .with(
+      tracing_subscriber::fmt::layer()
+        .pretty()
+        .with_writer(std::io::stderr),
+    )
     .with(
       EnvFilter::builder()
         .with_default_directive(LevelFilter::DEBUG.into())
@@ -686,13 +540,40 @@ fn main() {
     }
   }
 
-  if let Err(err) = run() {
-    let dialog = MessageDialog::new()
-      .set_title("Error")
-      .set_description(format!("Error: {}", err))
-      .set_buttons(MessageButtons::Ok)
-      .set_level(MessageLevel::Error);
-    dialog.show();
-    event!(Level::ERROR, error = err.to_string());
+  let args = match Args::try_parse() {
+    Ok(args) => args,
+    Err(err) if std::io::stdout().is_terminal() => err.exit(),
+    Err(err) => {
+      let dialog = MessageDialog::new()
+        .set_title("Error")
+        .set_description(format!("Error: {}", err))
+        .set_buttons(MessageButtons::Ok)
+        .set_level(MessageLevel::Error);
+      dialog.show();
+      std::process::exit(err.exit_code());
+    },
+  };
+
+  // One-shot commands report errors on stderr with an exit code, scripts
+  // show a dialog since they usually run without a terminal.
+  let is_script = matches!(args.command, None | Some(Command::Run { .. }));
+  let format = args.format;
+
+  if let Err(err) = run(args) {
+    let code = errors::exit_code(&*err);
+    if is_script {
+      event!(Level::ERROR, error = err.to_string());
+      let dialog = MessageDialog::new()
+        .set_title("Error")
+        .set_description(format!("Error: {}", err))
+        .set_buttons(MessageButtons::Ok)
+        .set_level(MessageLevel::Error);
+      dialog.show();
+    } else if format == cli::Format::Json {
+      cli::print_error(&*err, code);
+    } else {
+      eprintln!("Error: {}", err);
+    }
+    std::process::exit(code);
   }
 }
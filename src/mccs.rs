@@ -0,0 +1,270 @@
+use std::collections::BTreeMap;
+
+use super::errors::StdError;
+
+// VCP code for the input source.
+pub(crate) const VCP_INPUT_SOURCE: u8 = 0x60;
+
+// Names of the input sources defined by MCCS for VCP 0x60. Monitors are free
+// to use other values, those just won't have a name.
+const INPUT_SOURCES: &[(u8, &str)] = &[
+  (0x01, "VGA-1"),
+  (0x02, "VGA-2"),
+  (0x03, "DVI-1"),
+  (0x04, "DVI-2"),
+  (0x05, "Composite-1"),
+  (0x06, "Composite-2"),
+  (0x07, "S-Video-1"),
+  (0x08, "S-Video-2"),
+  (0x09, "Tuner-1"),
+  (0x0a, "Tuner-2"),
+  (0x0b, "Tuner-3"),
+  (0x0c, "Component-1"),
+  (0x0d, "Component-2"),
+  (0x0e, "Component-3"),
+  (0x0f, "DisplayPort-1"),
+  (0x10, "DisplayPort-2"),
+  (0x11, "HDMI-1"),
+  (0x12, "HDMI-2"),
+];
+
+pub(crate) fn input_source_name(value: u8) -> Option<&'static str> {
+  INPUT_SOURCES
+    .iter()
+    .find(|(v, _)| *v == value)
+    .map(|(_, name)| *name)
+}
+
+pub(crate) fn input_source_value(name: &str) -> Option<u8> {
+  INPUT_SOURCES
+    .iter()
+    .find(|(_, n)| n.eq_ignore_ascii_case(name))
+    .map(|(v, _)| *v)
+}
+
+// The parts of a MCCS capabilities string we use, such as
+// "(prot(monitor)type(lcd)model(MAG274QRF)cmds(01 02 03 0C E3 F3)vcp(10 12 60(0F 11 12))mccs_ver(2.1))".
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Capabilities {
+  pub(crate) protocol: Option<String>,
+  pub(crate) display_type: Option<String>,
+  pub(crate) model: Option<String>,
+  pub(crate) mccs_version: Option<String>,
+  // Supported VCP codes. Codes with a list of allowed values, like 0x60,
+  // have them here. Continuous codes have none.
+  pub(crate) vcp: BTreeMap<u8, Vec<u8>>,
+}
+
+impl Capabilities {
+  pub(crate) fn parse(caps: &str) -> Result<Self, Box<StdError>> {
+    let caps = caps.trim_matches(|c: char| c.is_whitespace() || c == '\0');
+    // Some monitors leave out the outer parentheses.
+    let caps = match caps.strip_prefix('(') {
+      Some(inner) if matching_paren(caps) == Some(caps.len() - 1) => &inner[..inner.len() - 1],
+      _ => caps,
+    };
+
+    let mut parsed = Self::default();
+    for (key, value) in entries(caps)? {
+      match key.to_ascii_lowercase().as_str() {
+        "prot" => parsed.protocol = Some(value.trim().to_string()),
+        "type" => parsed.display_type = Some(value.trim().to_string()),
+        "model" => parsed.model = Some(value.trim().to_string()),
+        "mccs_ver" => parsed.mccs_version = Some(value.trim().to_string()),
+        "vcp" => parsed.vcp = parse_vcp(value)?,
+        _ => {},
+      }
+    }
+
+    Ok(parsed)
+  }
+
+  pub(crate) fn supports(&self, code: u8) -> bool {
+    self.vcp.contains_key(&code)
+  }
+
+  // Allowed values for VCP 0x60 along with their names, if known.
+  pub(crate) fn input_sources(&self) -> Vec<(u8, Option<&'static str>)> {
+    self
+      .vcp
+      .get(&VCP_INPUT_SOURCE)
+      .map(|values| values.iter().map(|v| (*v, input_source_name(*v))).collect())
+      .unwrap_or_default()
+  }
+}
+
+// Returns the index of the parenthesis closing the one at the start of `s`.
+fn matching_paren(s: &str) -> Option<usize> {
+  let mut depth = 0;
+  for (i, c) in s.char_indices() {
+    match c {
+      '(' => depth += 1,
+      ')' => {
+        depth -= 1;
+        if depth == 0 {
+          return Some(i);
+        }
+      },
+      _ => {},
+    }
+  }
+  None
+}
+
+// Splits "key(value)key(value)" into its entries. Values may contain nested
+// parentheses.
+fn entries(mut s: &str) -> Result<Vec<(&str, &str)>, Box<StdError>> {
+  let mut entries = Vec::new();
+
+  loop {
+    s = s.trim_start();
+    if s.is_empty() {
+      break;
+    }
+
+    let Some(open) = s.find('(') else {
+      return Err(format!("capabilities entry without a value: '{}'", s).into());
+    };
+    let Some(close) = matching_paren(&s[open..]) else {
+      return Err(format!("unbalanced parentheses in capabilities: '{}'", s).into());
+    };
+
+    entries.push((s[..open].trim(), &s[open + 1..open + close]));
+    s = &s[open + close + 1..];
+  }
+
+  Ok(entries)
+}
+
+// Parses the value of vcp(), like "02 04 14(05 08 0B) 60(0F 11)".
+fn parse_vcp(s: &str) -> Result<BTreeMap<u8, Vec<u8>>, Box<StdError>> {
+  let mut vcp = BTreeMap::new();
+  let mut last = None;
+  let mut rest = s;
+
+  loop {
+    rest = rest.trim_start();
+    if rest.is_empty() {
+      break;
+    }
+
+    if rest.starts_with('(') {
+      let Some(close) = matching_paren(rest) else {
+        return Err(format!("unbalanced parentheses in vcp: '{}'", s).into());
+      };
+      let Some(code) = last else {
+        return Err(format!("vcp values without a code: '{}'", s).into());
+      };
+      // Nested groups only show up in vendor specific codes, so they are
+      // flattened.
+      let values = rest[1..close]
+        .split(|c: char| c.is_whitespace() || c == '(' || c == ')')
+        .map(hex_bytes)
+        .collect::<Result<Vec<_>, _>>()?
+        .concat();
+      vcp.insert(code, values);
+      rest = &rest[close + 1..];
+      continue;
+    }
+
+    let end = rest
+      .find(|c: char| c.is_whitespace() || c == '(')
+      .unwrap_or(rest.len());
+    for code in hex_bytes(&rest[..end])? {
+      vcp.insert(code, Vec::new());
+      last = Some(code);
+    }
+    rest = &rest[end..];
+  }
+
+  Ok(vcp)
+}
+
+// Parses a hex token into bytes. Tokens are normally two characters, but
+// some monitors leave out the spaces between them.
+fn hex_bytes(token: &str) -> Result<Vec<u8>, Box<StdError>> {
+  if !token.is_ascii() || !token.len().is_multiple_of(2) {
+    return Err(format!("invalid hex value in capabilities: '{}'", token).into());
+  }
+
+  (0..token.len())
+    .step_by(2)
+    .map(|i| {
+      u8::from_str_radix(&token[i..i + 2], 16)
+        .map_err(|_| format!("invalid hex value in capabilities: '{}'", token).into())
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // What an MSI monitor answers with, including the vendor specific codes.
+  const MSI: &str = "(prot(monitor)type(LCD)model(MAG274QRF-QD)cmds(01 02 03 07 0C E3 F3)vcp(02 04 05 08 10 12 14(05 06 08 0B) 16 18 1A 52 60(0F 11 12) 62 AC AE B2 B6 C0 C6 C8 C9 CA(01 02) CC(02 03 04 05 07 08 09 0A 0C 0D 14 16 1E) D6(01 05) DF)mswhql(1)asset_eep(40)mccs_ver(2.2))";
+
+  #[test]
+  fn parses_msi_capabilities() {
+    let caps = Capabilities::parse(MSI).unwrap();
+    assert_eq!(caps.protocol.as_deref(), Some("monitor"));
+    assert_eq!(caps.display_type.as_deref(), Some("LCD"));
+    assert_eq!(caps.model.as_deref(), Some("MAG274QRF-QD"));
+    assert_eq!(caps.mccs_version.as_deref(), Some("2.2"));
+    assert!(caps.supports(0x10));
+    assert!(caps.supports(0xdf));
+    assert!(!caps.supports(0x01));
+    assert_eq!(caps.vcp[&0x14], vec![0x05, 0x06, 0x08, 0x0b]);
+    assert_eq!(caps.vcp[&0x10], Vec::<u8>::new());
+    assert_eq!(
+      caps.input_sources(),
+      vec![
+        (0x0f, Some("DisplayPort-1")),
+        (0x11, Some("HDMI-1")),
+        (0x12, Some("HDMI-2")),
+      ]
+    );
+  }
+
+  #[test]
+  fn flattens_nested_groups() {
+    let caps = Capabilities::parse("(vcp(10 60(0F 11 12) E0(00(01 02) 03) 62))").unwrap();
+    assert_eq!(caps.vcp[&0x60], vec![0x0f, 0x11, 0x12]);
+    assert_eq!(caps.vcp[&0xe0], vec![0x00, 0x01, 0x02, 0x03]);
+    assert!(caps.supports(0x62));
+  }
+
+  #[test]
+  fn parses_without_outer_parens() {
+    let caps = Capabilities::parse("prot(monitor)vcp(10 60(0F 11))mccs_ver(2.1)\0").unwrap();
+    assert_eq!(caps.protocol.as_deref(), Some("monitor"));
+    assert_eq!(caps.mccs_version.as_deref(), Some("2.1"));
+    assert_eq!(caps.vcp[&0x60], vec![0x0f, 0x11]);
+  }
+
+  #[test]
+  fn parses_unspaced_hex() {
+    let caps = Capabilities::parse("(vcp(0210 60(0F1112)))").unwrap();
+    assert!(caps.supports(0x02));
+    assert!(caps.supports(0x10));
+    assert_eq!(caps.vcp[&0x60], vec![0x0f, 0x11, 0x12]);
+  }
+
+  #[test]
+  fn rejects_odd_length_hex() {
+    let err = Capabilities::parse("(vcp(10 60(0F 111)))").unwrap_err();
+    assert!(err.to_string().contains("invalid hex value"), "{}", err);
+  }
+
+  #[test]
+  fn rejects_unbalanced_parens() {
+    for caps in [
+      "(prot(monitor)vcp(10 60(0F 11)",
+      "prot(monitor)vcp(10 60(0F 11",
+      "vcp(10))",
+      "vcp(10 60(0F 11)))",
+      ")(",
+    ] {
+      assert!(Capabilities::parse(caps).is_err(), "{}", caps);
+    }
+  }
+}
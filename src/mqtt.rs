@@ -0,0 +1,415 @@
+use std::collections::BTreeMap;
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_channel::Receiver;
+use crossbeam_channel::Sender;
+use rumqttc::Client;
+use rumqttc::LastWill;
+use rumqttc::MqttOptions;
+use rumqttc::Packet;
+use rumqttc::QoS;
+use serde_json::json;
+use tracing::Level;
+use tracing::event;
+
+use super::daemon;
+use super::device;
+use super::errors::StdError;
+
+// Everything we publish lives under this topic.
+const BASE_TOPIC: &str = "msi-monitor-ctrl";
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct MqttArgs {
+  /// Publish the monitors to an MQTT broker at HOST[:PORT] while in the main
+  /// loop, with Home Assistant discovery.
+  #[arg(long)]
+  mqtt: Option<String>,
+  #[arg(long, requires = "mqtt")]
+  mqtt_username: Option<String>,
+  #[arg(long, requires = "mqtt_username")]
+  mqtt_password: Option<String>,
+  /// The Home Assistant discovery prefix.
+  #[arg(long, default_value = "homeassistant")]
+  mqtt_discovery_prefix: String,
+  /// How often to publish the current settings.
+  #[arg(long, default_value_t = 30)]
+  mqtt_poll_secs: u64,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Options {
+  host: String,
+  port: u16,
+  credentials: Option<(String, String)>,
+  discovery_prefix: String,
+  poll: Duration,
+}
+
+impl MqttArgs {
+  pub(crate) fn options(self) -> Result<Option<Options>, Box<StdError>> {
+    let Some(broker) = self.mqtt else {
+      return Ok(None);
+    };
+    let (host, port) = parse_broker(&broker)?;
+    Ok(Some(Options {
+      host,
+      port,
+      credentials: self
+        .mqtt_username
+        .map(|username| (username, self.mqtt_password.unwrap_or_default())),
+      discovery_prefix: self.mqtt_discovery_prefix,
+      poll: Duration::from_secs(self.mqtt_poll_secs),
+    }))
+  }
+}
+
+// Splits HOST[:PORT] where HOST may be an IPv6 address, in brackets when a
+// port follows, like "[::1]:1883".
+fn parse_broker(broker: &str) -> Result<(String, u16), Box<StdError>> {
+  if let Some(rest) = broker.strip_prefix('[') {
+    let (host, rest) = rest
+      .split_once(']')
+      .ok_or_else(|| format!("missing ] in {}", broker))?;
+    let port = match rest.strip_prefix(':') {
+      Some(port) => port.parse()?,
+      None if rest.is_empty() => 1883,
+      None => return Err(format!("unexpected '{}' after the address in {}", rest, broker).into()),
+    };
+    return Ok((host.to_string(), port));
+  }
+  match broker.split_once(':') {
+    // More than one colon is an IPv6 address without a port.
+    Some((_, port)) if port.contains(':') => Ok((broker.to_string(), 1883)),
+    Some((host, port)) => Ok((host.to_string(), port.parse()?)),
+    None => Ok((broker.to_string(), 1883)),
+  }
+}
+
+// What the connection thread passes on to the worker.
+enum Incoming {
+  Connected,
+  Publish { topic: String, payload: Vec<u8> },
+}
+
+// Connects to the broker and keeps publishing until the process exits.
+// Device commands are sent to `tx` for the main loop to run. Returns where
+// to send events.
+pub(crate) fn serve(opts: Options, tx: Sender<daemon::Call>) -> Sender<daemon::Event> {
+  let (events_tx, events_rx) = crossbeam_channel::unbounded();
+
+  let mut mqtt_options = MqttOptions::new(
+    format!("{}-{}", BASE_TOPIC, std::process::id()),
+    opts.host.clone(),
+    opts.port,
+  );
+  mqtt_options.set_keep_alive(Duration::from_secs(30));
+  mqtt_options.set_last_will(LastWill::new(
+    availability_topic(),
+    "offline",
+    QoS::AtLeastOnce,
+    true,
+  ));
+  if let Some((username, password)) = &opts.credentials {
+    mqtt_options.set_credentials(username.clone(), password.clone());
+  }
+
+  let (client, mut connection) = Client::new(mqtt_options, 64);
+  let (incoming_tx, incoming_rx) = crossbeam_channel::unbounded();
+
+  // The connection has to be polled for anything to happen, including
+  // reconnecting after the broker goes away.
+  let host = format!("{}:{}", opts.host, opts.port);
+  thread::spawn(move || {
+    for notification in connection.iter() {
+      let incoming = match notification {
+        Ok(rumqttc::Event::Incoming(Packet::ConnAck(_))) => {
+          event!(Level::INFO, "connected to mqtt broker {}", host);
+          Incoming::Connected
+        },
+        Ok(rumqttc::Event::Incoming(Packet::Publish(publish))) => {
+          Incoming::Publish {
+            topic: publish.topic,
+            payload: publish.payload.to_vec(),
+          }
+        },
+        Ok(_) => continue,
+        Err(err) => {
+          event!(
+            Level::WARN,
+            "mqtt connection to {}: {}, retrying",
+            host,
+            err
+          );
+          thread::sleep(Duration::from_secs(5));
+          continue;
+        },
+      };
+      if incoming_tx.send(incoming).is_err() {
+        return;
+      }
+    }
+  });
+
+  thread::spawn(move || {
+    let mut worker = Worker {
+      opts,
+      client,
+      tx,
+      monitors: BTreeMap::new(),
+    };
+    worker.run(incoming_rx, events_rx);
+  });
+
+  events_tx
+}
+
+fn availability_topic() -> String {
+  format!("{}/status", BASE_TOPIC)
+}
+
+// Identifies a monitor in topics and to Home Assistant. The serial tells
+// monitors of the same model apart wherever they are plugged in. Without one,
+// the USB port has to do.
+fn monitor_id(info: &device::DeviceInfo) -> String {
+  let place = match info.serial.as_deref().map(str::trim) {
+    Some(serial) if !serial.is_empty() => {
+      serial
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+    },
+    _ => {
+      let ports = info.ports.iter().map(u8::to_string).collect::<Vec<_>>();
+      format!("usb{}-{}", info.bus, ports.join("-"))
+    },
+  };
+  format!("{:04x}_{:04x}_{}", info.vendor_id, info.product_id, place)
+}
+
+struct Worker {
+  opts: Options,
+  client: Client,
+  tx: Sender<daemon::Call>,
+  // The monitors we announced, by id.
+  monitors: BTreeMap<String, device::DeviceInfo>,
+}
+
+impl Worker {
+  fn run(&mut self, incoming: Receiver<Incoming>, events: Receiver<daemon::Event>) {
+    let ticker = crossbeam_channel::tick(self.opts.poll);
+    loop {
+      let res = crossbeam_channel::select! {
+        recv(incoming) -> msg => match msg {
+          Ok(Incoming::Connected) => self.connected(),
+          Ok(Incoming::Publish { topic, payload }) => self.command(&topic, &payload),
+          Err(_) => return,
+        },
+        recv(events) -> msg => match msg {
+          Ok(event) => self.event(event),
+          Err(_) => return,
+        },
+        recv(ticker) -> _ => self.publish_states(),
+      };
+      if let Err(err) = res {
+        event!(Level::ERROR, "mqtt: {}", err);
+      }
+    }
+  }
+
+  fn call(&self, request: daemon::Request) -> Result<serde_json::Value, Box<StdError>> {
+    daemon::call(&self.tx, request)
+  }
+
+  // Never blocks, while the broker is away the queue fills up and we drop
+  // what doesn't fit. The next poll publishes the states again.
+  fn publish(&self, topic: String, retain: bool, payload: String) -> Result<(), Box<StdError>> {
+    self
+      .client
+      .try_publish(topic, QoS::AtLeastOnce, retain, payload)?;
+    Ok(())
+  }
+
+  // Subscriptions don't survive a reconnect, so everything is redone each
+  // time we connect.
+  fn connected(&mut self) -> Result<(), Box<StdError>> {
+    self
+      .client
+      .try_subscribe(format!("{}/+/+/set", BASE_TOPIC), QoS::AtLeastOnce)?;
+    self.publish(availability_topic(), true, "online".into())?;
+    self.announce()?;
+    self.publish_states()
+  }
+
+  fn config_topic(&self, id: &str, setting: &str) -> String {
+    format!(
+      "{}/number/{}_{}_{}/config",
+      self.opts.discovery_prefix, BASE_TOPIC, id, setting
+    )
+  }
+
+  // Publishes a Home Assistant discovery config for every setting of every
+  // connected monitor, and removes the ones of monitors that went away.
+  fn announce(&mut self) -> Result<(), Box<StdError>> {
+    let devices: Vec<device::DeviceInfo> =
+      serde_json::from_value(self.call(daemon::Request::ListDevices)?)?;
+
+    let monitors = devices
+      .into_iter()
+      .filter(device::DeviceInfo::is_monitor)
+      .map(|info| (monitor_id(&info), info))
+      .collect::<BTreeMap<_, _>>();
+    let gone = std::mem::replace(&mut self.monitors, monitors);
+    for id in gone.keys().filter(|id| !self.monitors.contains_key(*id)) {
+      // Empty retained messages are how Home Assistant is told to drop the
+      // entities, and clear the states the broker kept for them.
+      for (_, setting) in device::CODE_NAMES {
+        self.publish(self.config_topic(id, setting), true, String::new())?;
+        self.publish(
+          format!("{}/{}/{}/state", BASE_TOPIC, id, setting),
+          true,
+          String::new(),
+        )?;
+      }
+    }
+
+    for (id, info) in &self.monitors {
+      let ha_device = json!({
+        "identifiers": [format!("{}_{}", BASE_TOPIC, id)],
+        "name": info.product.as_deref().unwrap_or("MSI monitor"),
+        "manufacturer": info.manufacturer.as_deref().unwrap_or("MSI"),
+        "model": format!("{:04x}:{:04x}", info.vendor_id, info.product_id),
+        "serial_number": info.serial,
+      });
+      for (_, setting) in device::CODE_NAMES {
+        let topic = format!("{}/{}/{}", BASE_TOPIC, id, setting);
+        let config = json!({
+          "name": setting,
+          "unique_id": format!("{}_{}_{}", BASE_TOPIC, id, setting),
+          "state_topic": format!("{}/state", topic),
+          "command_topic": format!("{}/set", topic),
+          "availability_topic": availability_topic(),
+          "min": 0,
+          "max": if *setting == "volume" { 100 } else { 999 },
+          "mode": "box",
+          "device": ha_device,
+        });
+        self.publish(self.config_topic(id, setting), true, config.to_string())?;
+      }
+    }
+
+    Ok(())
+  }
+
+  fn publish_states(&self) -> Result<(), Box<StdError>> {
+    for (id, info) in &self.monitors {
+      for (code, setting) in device::CODE_NAMES {
+        let value = match self.call(daemon::Request::Get {
+          vendor_id: info.vendor_id,
+          product_id: info.product_id,
+          location: Some((info.bus, info.address)),
+          code: *code,
+        }) {
+          Ok(value) => value,
+          Err(err) => {
+            event!(
+              Level::DEBUG,
+              "mqtt: could not read {} of {}: {}",
+              setting,
+              id,
+              err
+            );
+            continue;
+          },
+        };
+        self.publish(
+          format!("{}/{}/{}/state", BASE_TOPIC, id, setting),
+          true,
+          value.to_string(),
+        )?;
+      }
+    }
+    Ok(())
+  }
+
+  // Handles msi-monitor-ctrl/<id>/<setting>/set.
+  fn command(&self, topic: &str, payload: &[u8]) -> Result<(), Box<StdError>> {
+    let parts = topic.split('/').collect::<Vec<_>>();
+    let [_, id, setting, "set"] = parts.as_slice() else {
+      return Ok(());
+    };
+    let info = self
+      .monitors
+      .get(*id)
+      .ok_or_else(|| format!("command for unknown monitor {}", id))?;
+    let code =
+      device::code_by_name(setting).ok_or_else(|| format!("unknown setting {}", setting))?;
+    let value: u32 = std::str::from_utf8(payload)?.trim().parse()?;
+
+    event!(
+      Level::INFO,
+      "mqtt: setting {} of {} to {}",
+      setting,
+      id,
+      value
+    );
+    self.call(daemon::Request::Set {
+      vendor_id: info.vendor_id,
+      product_id: info.product_id,
+      location: Some((info.bus, info.address)),
+      code,
+      value,
+    })?;
+    // The new state is published when the main loop tells us about the
+    // change, like for changes made anywhere else.
+    Ok(())
+  }
+
+  fn event(&mut self, event: daemon::Event) -> Result<(), Box<StdError>> {
+    match event {
+      daemon::Event::Hotplug {
+        connected,
+        vendor_id,
+        product_id,
+      } => {
+        let payload = json!({
+          "event": if connected { "connected" } else { "disconnected" },
+          "vendor_id": vendor_id,
+          "product_id": product_id,
+        });
+        self.publish(
+          format!("{}/hotplug", BASE_TOPIC),
+          false,
+          payload.to_string(),
+        )?;
+
+        // A monitor came or went, so the entities change.
+        if vendor_id == device::MSI_VENDOR_ID {
+          self.announce()?;
+          self.publish_states()?;
+        }
+        Ok(())
+      },
+      daemon::Event::SettingChanged(change) => {
+        let Some(setting) = device::code_name(change.code) else {
+          return Ok(());
+        };
+        for (id, info) in &self.monitors {
+          if (info.vendor_id, info.product_id) == (change.vendor_id, change.product_id)
+            && change
+              .location
+              .is_none_or(|at| at == (info.bus, info.address))
+          {
+            self.publish(
+              format!("{}/{}/{}/state", BASE_TOPIC, id, setting),
+              true,
+              change.value.to_string(),
+            )?;
+          }
+        }
+        Ok(())
+      },
+    }
+  }
+}
@@ -0,0 +1,295 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use tracing::Level;
+use tracing::event;
+
+use super::errors::StdError;
+
+// LINKTYPE_USBPCAP, which is what Wireshark uses for USBPcap captures on
+// windows. This lets our traces sit next to captures of Gaming Intelligence.
+const LINKTYPE_USBPCAP: u16 = 249;
+// URB_FUNCTION_BULK_OR_INTERRUPT_TRANSFER
+const URB_FUNCTION_BULK_OR_INTERRUPT_TRANSFER: u16 = 0x0009;
+// USBPCAP_TRANSFER_INTERRUPT
+const USBPCAP_TRANSFER_INTERRUPT: u8 = 1;
+// Length of the USBPcap packet header that precedes the data.
+const USBPCAP_HEADER_LEN: u16 = 27;
+
+static TRACE: Mutex<Option<Writer>> = Mutex::new(None);
+
+// Starts recording every interrupt transfer to the monitor into `path`.
+pub(crate) fn start_trace(path: &Path) -> Result<(), Box<StdError>> {
+  let writer = Writer::create(path)?;
+  *TRACE.lock().unwrap() = Some(writer);
+  Ok(())
+}
+
+// Records an interrupt transfer if tracing is enabled. `endpoint` is the
+// endpoint address, including the direction bit.
+pub(crate) fn record(bus: u8, address: u8, endpoint: u8, data: &[u8]) {
+  let mut trace = TRACE.lock().unwrap();
+  if let Some(writer) = trace.as_mut()
+    && let Err(err) = writer.write_transfer(bus, address, endpoint, data)
+  {
+    event!(Level::ERROR, "could not write packet trace: {}", err);
+    *trace = None;
+  }
+}
+
+pub(crate) struct Writer {
+  out: BufWriter<File>,
+  irp_id: u64,
+}
+
+impl Writer {
+  pub(crate) fn create(path: &Path) -> Result<Self, Box<StdError>> {
+    let mut out = BufWriter::new(File::create(path)?);
+
+    // Section header block.
+    write_block(&mut out, 0x0a0d0d0a, &{
+      let mut body = Vec::new();
+      body.extend_from_slice(&0x1a2b3c4du32.to_le_bytes());
+      body.extend_from_slice(&1u16.to_le_bytes());
+      body.extend_from_slice(&0u16.to_le_bytes());
+      body.extend_from_slice(&(-1i64).to_le_bytes());
+      body
+    })?;
+
+    // Interface description block. Timestamps use the default resolution
+    // of microseconds.
+    write_block(&mut out, 0x00000001, &{
+      let mut body = Vec::new();
+      body.extend_from_slice(&LINKTYPE_USBPCAP.to_le_bytes());
+      body.extend_from_slice(&0u16.to_le_bytes());
+      body.extend_from_slice(&0u32.to_le_bytes());
+      body
+    })?;
+
+    out.flush()?;
+
+    Ok(Self {
+      out,
+      irp_id: 0,
+    })
+  }
+
+  pub(crate) fn write_transfer(
+    &mut self,
+    bus: u8,
+    address: u8,
+    endpoint: u8,
+    data: &[u8],
+  ) -> Result<(), Box<StdError>> {
+    self.irp_id += 1;
+
+    // OUT data is captured when the request is submitted, IN data when it
+    // completes.
+    let info: u8 = if endpoint & 0x80 != 0 { 1 } else { 0 };
+
+    let mut packet = Vec::with_capacity(USBPCAP_HEADER_LEN as usize + data.len());
+    packet.extend_from_slice(&USBPCAP_HEADER_LEN.to_le_bytes());
+    packet.extend_from_slice(&self.irp_id.to_le_bytes());
+    packet.extend_from_slice(&0u32.to_le_bytes());
+    packet.extend_from_slice(&URB_FUNCTION_BULK_OR_INTERRUPT_TRANSFER.to_le_bytes());
+    packet.push(info);
+    packet.extend_from_slice(&(bus as u16).to_le_bytes());
+    packet.extend_from_slice(&(address as u16).to_le_bytes());
+    packet.push(endpoint);
+    packet.push(USBPCAP_TRANSFER_INTERRUPT);
+    packet.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    packet.extend_from_slice(data);
+
+    let micros = SystemTime::now().duration_since(UNIX_EPOCH)?.as_micros() as u64;
+
+    // Enhanced packet block.
+    let mut body = Vec::with_capacity(20 + packet.len() + 3);
+    body.extend_from_slice(&0u32.to_le_bytes());
+    body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(micros as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    body.extend_from_slice(&packet);
+    body.resize(body.len().next_multiple_of(4), 0);
+    write_block(&mut self.out, 0x00000006, &body)?;
+
+    // Flush every packet so the trace is usable even if we crash.
+    self.out.flush()?;
+    Ok(())
+  }
+}
+
+fn write_block<W: Write>(out: &mut W, block_type: u32, body: &[u8]) -> std::io::Result<()> {
+  let total_len = (12 + body.len()) as u32;
+  out.write_all(&block_type.to_le_bytes())?;
+  out.write_all(&total_len.to_le_bytes())?;
+  out.write_all(body)?;
+  out.write_all(&total_len.to_le_bytes())?;
+  Ok(())
+}
+
+pub(crate) struct Packet {
+  pub(crate) linktype: u32,
+  // Seconds since the epoch.
+  pub(crate) timestamp: f64,
+  pub(crate) data: Vec<u8>,
+}
+
+// Reads every packet from a pcap or pcapng file.
+pub(crate) fn read_file(path: &Path) -> Result<Vec<Packet>, Box<StdError>> {
+  let mut buf = Vec::new();
+  File::open(path)?.read_to_end(&mut buf)?;
+
+  match buf.get(0..4) {
+    Some([0x0a, 0x0d, 0x0d, 0x0a]) => read_pcapng(&buf),
+    Some(_) => read_pcap(&buf),
+    None => Err("capture is too short".into()),
+  }
+}
+
+fn read_pcap(buf: &[u8]) -> Result<Vec<Packet>, Box<StdError>> {
+  let (le, nanos) = match u32_at(buf, 0, true) {
+    Some(0xa1b2c3d4) => (true, false),
+    Some(0xa1b23c4d) => (true, true),
+    Some(0xd4c3b2a1) => (false, false),
+    Some(0x4d3cb2a1) => (false, true),
+    _ => return Err("not a pcap or pcapng file".into()),
+  };
+  let linktype = u32_at(buf, 20, le).ok_or("truncated pcap header")?;
+
+  let mut packets = Vec::new();
+  let mut off = 24;
+  while off + 16 <= buf.len() {
+    let secs = u32_at(buf, off, le).unwrap() as f64;
+    let frac = u32_at(buf, off + 4, le).unwrap() as f64;
+    let len = u32_at(buf, off + 8, le).unwrap() as usize;
+    let data = buf
+      .get(off + 16..off + 16 + len)
+      .ok_or("truncated pcap record")?;
+    packets.push(Packet {
+      linktype,
+      timestamp: secs + frac / if nanos { 1e9 } else { 1e6 },
+      data: data.to_vec(),
+    });
+    off += 16 + len;
+  }
+
+  Ok(packets)
+}
+
+fn read_pcapng(buf: &[u8]) -> Result<Vec<Packet>, Box<StdError>> {
+  let mut le = true;
+  // Link type and timestamp units per second for each interface.
+  let mut interfaces: Vec<(u32, f64)> = Vec::new();
+  let mut packets = Vec::new();
+
+  let mut off = 0;
+  while off + 12 <= buf.len() {
+    if buf[off..off + 4] == [0x0a, 0x0d, 0x0d, 0x0a] {
+      // A new section resets the byte order and the interfaces.
+      le = match u32_at(buf, off + 8, true) {
+        Some(0x1a2b3c4d) => true,
+        Some(0x4d3c2b1a) => false,
+        _ => return Err("bad pcapng byte order magic".into()),
+      };
+      interfaces.clear();
+    }
+
+    let block_type = u32_at(buf, off, le).unwrap();
+    let block_len = u32_at(buf, off + 4, le).unwrap() as usize;
+    if block_len < 12 || off + block_len > buf.len() {
+      return Err("truncated pcapng block".into());
+    }
+    let body = &buf[off + 8..off + block_len - 4];
+
+    match block_type {
+      // Interface description block.
+      0x00000001 => {
+        let linktype = u16_at(body, 0, le).ok_or("truncated interface block")? as u32;
+        interfaces.push((linktype, tsresol(body.get(8..).unwrap_or(&[]), le)));
+      },
+      // Enhanced packet block.
+      0x00000006 => {
+        let iface = u32_at(body, 0, le).ok_or("truncated packet block")? as usize;
+        let &(linktype, units) = interfaces
+          .get(iface)
+          .ok_or("packet for unknown interface")?;
+        let ts_hi = u32_at(body, 4, le).ok_or("truncated packet block")? as u64;
+        let ts_lo = u32_at(body, 8, le).ok_or("truncated packet block")? as u64;
+        let len = u32_at(body, 12, le).ok_or("truncated packet block")? as usize;
+        let data = body.get(20..20 + len).ok_or("truncated packet block")?;
+        packets.push(Packet {
+          linktype,
+          timestamp: ((ts_hi << 32) | ts_lo) as f64 / units,
+          data: data.to_vec(),
+        });
+      },
+      // Simple packet block. These have no timestamp.
+      0x00000003 => {
+        let &(linktype, _) = interfaces.first().ok_or("packet for unknown interface")?;
+        let len = u32_at(body, 0, le).ok_or("truncated packet block")? as usize;
+        let data = &body[4..];
+        packets.push(Packet {
+          linktype,
+          timestamp: 0.0,
+          data: data[..len.min(data.len())].to_vec(),
+        });
+      },
+      _ => {},
+    }
+
+    off += block_len;
+  }
+
+  Ok(packets)
+}
+
+// Finds the if_tsresol option in the options of an interface description
+// block and returns the number of timestamp units per second.
+fn tsresol(mut options: &[u8], le: bool) -> f64 {
+  while let (Some(code), Some(len)) = (u16_at(options, 0, le), u16_at(options, 2, le)) {
+    let len = len as usize;
+    if code == 0 {
+      break;
+    }
+    if code == 9
+      && let Some(&res) = options.get(4)
+    {
+      return if res & 0x80 == 0 {
+        10f64.powi((res & 0x7f) as i32)
+      } else {
+        2f64.powi((res & 0x7f) as i32)
+      };
+    }
+    let Some(rest) = options.get(4 + len.next_multiple_of(4)..) else {
+      break;
+    };
+    options = rest;
+  }
+
+  1e6
+}
+
+pub(crate) fn u16_at(buf: &[u8], off: usize, le: bool) -> Option<u16> {
+  let bytes = buf.get(off..off + 2)?.try_into().ok()?;
+  Some(if le {
+    u16::from_le_bytes(bytes)
+  } else {
+    u16::from_be_bytes(bytes)
+  })
+}
+
+pub(crate) fn u32_at(buf: &[u8], off: usize, le: bool) -> Option<u32> {
+  let bytes = buf.get(off..off + 4)?.try_into().ok()?;
+  Some(if le {
+    u32::from_le_bytes(bytes)
+  } else {
+    u32::from_be_bytes(bytes)
+  })
+}
@@ -0,0 +1,206 @@
+use std::collections::HashSet;
+
+use mlua::Lua;
+use mlua::Value;
+use rustyline::DefaultEditor;
+use rustyline::error::ReadlineError;
+use serde::Deserialize;
+
+use super::daemon;
+use super::errors::StdError;
+use super::runtime;
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct ReplArgs {
+  /// Run this script first, so its globals are there to play with. Its
+  /// callbacks don't fire.
+  script: Option<String>,
+  /// Evaluate in the Lua state of the running main loop instead.
+  #[arg(long, conflicts_with = "script")]
+  attach: bool,
+}
+
+// Where lines go to be evaluated.
+enum Target {
+  Local(runtime::Runtime),
+  Attached(daemon::Client),
+}
+
+// What the running main loop answers an eval request with.
+#[derive(Deserialize)]
+struct Evaluated {
+  #[serde(default)]
+  incomplete: bool,
+  #[serde(default)]
+  output: String,
+}
+
+impl Target {
+  // Returns None if `code` is an incomplete statement, like the first line
+  // of a function.
+  fn eval(&mut self, code: &str) -> Result<Option<String>, Box<StdError>> {
+    match self {
+      Target::Local(runtime) => Ok(runtime.eval(code)?),
+      Target::Attached(client) => {
+        let evaluated: Evaluated =
+          serde_json::from_value(client.send(&daemon::Request::Eval {
+            code: code.to_string(),
+          })?)?;
+        Ok((!evaluated.incomplete).then_some(evaluated.output))
+      },
+    }
+  }
+}
+
+pub(crate) fn run(args: ReplArgs) -> Result<(), Box<StdError>> {
+  let mut target = if args.attach {
+    let client = daemon::connect().ok_or("no running instance to attach to")?;
+    println!("attached to the running main loop");
+    Target::Attached(client)
+  } else {
+    let ctx = runtime::Context::new(
+      args.script.unwrap_or_default(),
+      None,
+      None,
+      None,
+      false,
+      None,
+    )?;
+    Target::Local(runtime::Runtime::load(&ctx)?)
+  };
+
+  let mut editor = DefaultEditor::new()?;
+  let history = super::project_dirs()?.data_local_dir().join("repl_history");
+  // There is no history the first time.
+  let _ = editor.load_history(&history);
+
+  let mut buffer = String::new();
+  loop {
+    let prompt = if buffer.is_empty() { "> " } else { ">> " };
+    let line = match editor.readline(prompt) {
+      Ok(line) => line,
+      // Ctrl-C drops what was typed so far, like the lua prompt.
+      Err(ReadlineError::Interrupted) => {
+        buffer.clear();
+        continue;
+      },
+      Err(ReadlineError::Eof) => break,
+      Err(err) => return Err(err.into()),
+    };
+
+    if !buffer.is_empty() {
+      buffer.push('\n');
+    }
+    buffer.push_str(&line);
+    if buffer.trim().is_empty() {
+      buffer.clear();
+      continue;
+    }
+
+    match target.eval(&buffer) {
+      Ok(None) => continue,
+      Ok(Some(output)) => {
+        if !output.is_empty() {
+          println!("{}", output);
+        }
+      },
+      Err(err) => eprintln!("{}", err),
+    }
+    let _ = editor.add_history_entry(buffer.as_str());
+    buffer.clear();
+  }
+
+  editor.save_history(&history)?;
+  Ok(())
+}
+
+// Runs a line the way the lua prompt does: as an expression if it is one,
+// otherwise as a statement. Returns the pretty printed results, or None if
+// more input is needed.
+pub(crate) fn eval(lua: &Lua, code: &str) -> Result<Option<String>, mlua::Error> {
+  let function = match lua
+    .load(format!("return {}", code))
+    .set_name("=repl")
+    .into_function()
+  {
+    Ok(function) => function,
+    Err(_) => {
+      match lua.load(code).set_name("=repl").into_function() {
+        Ok(function) => function,
+        Err(mlua::Error::SyntaxError {
+          incomplete_input: true,
+          ..
+        }) => return Ok(None),
+        Err(err) => return Err(err),
+      }
+    },
+  };
+
+  let values = function.call::<mlua::MultiValue>(())?;
+  let output = values
+    .iter()
+    .map(|value| pretty(value, 0, &mut HashSet::new()))
+    .collect::<Vec<_>>()
+    .join("\t");
+  Ok(Some(output))
+}
+
+// Formats a value like Lua source, with tables expanded. Tables we are
+// already inside of are printed as <cycle>.
+fn pretty(value: &Value, indent: usize, seen: &mut HashSet<usize>) -> String {
+  match value {
+    Value::String(s) => format!("{:?}", s.to_string_lossy()),
+    Value::Table(table) => {
+      let ptr = table.to_pointer() as usize;
+      if !seen.insert(ptr) {
+        return "<cycle>".into();
+      }
+
+      let pad = "  ".repeat(indent + 1);
+      let len = table.raw_len();
+      let mut lines = Vec::new();
+      for i in 1..=len {
+        if let Ok(item) = table.raw_get::<Value>(i) {
+          lines.push(format!("{}{},", pad, pretty(&item, indent + 1, seen)));
+        }
+      }
+
+      let mut fields = Vec::new();
+      for (key, item) in table.pairs::<Value, Value>().flatten() {
+        if let Value::Integer(i) = key
+          && i >= 1
+          && i as usize <= len
+        {
+          continue;
+        }
+        let key = match &key {
+          Value::String(s) if is_identifier(&s.to_string_lossy()) => s.to_string_lossy(),
+          key => format!("[{}]", pretty(key, indent + 1, seen)),
+        };
+        fields.push(format!(
+          "{}{} = {},",
+          pad,
+          key,
+          pretty(&item, indent + 1, seen)
+        ));
+      }
+      fields.sort();
+      lines.extend(fields);
+
+      seen.remove(&ptr);
+      if lines.is_empty() {
+        return "{}".into();
+      }
+      format!("{{\n{}\n{}}}", lines.join("\n"), "  ".repeat(indent))
+    },
+    value => value.to_string().unwrap_or_else(|_| format!("{:?}", value)),
+  }
+}
+
+fn is_identifier(s: &str) -> bool {
+  let mut chars = s.chars();
+  chars
+    .next()
+    .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+    && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use tracing::Level;
+use tracing::event;
+
+use super::cli;
+use super::cli::DeviceArgs;
+use super::device;
+use super::errors::StdError;
+use super::pcap;
+
+// LINKTYPE_USB_LINUX, usbmon with a 48 byte header.
+const LINKTYPE_USB_LINUX: u32 = 189;
+// LINKTYPE_USBPCAP, captures from USBPcap on windows.
+const LINKTYPE_USBPCAP: u32 = 249;
+// LINKTYPE_USB_LINUX_MMAPPED, usbmon with a 64 byte header.
+const LINKTYPE_USB_LINUX_MMAPPED: u32 = 220;
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct ReplayArgs {
+  /// A pcap or pcapng capture from usbmon or USBPcap.
+  capture: PathBuf,
+  /// Send the frame with this number to the monitor. Can be repeated.
+  #[arg(long)]
+  send: Vec<usize>,
+  /// How long to wait between sent frames.
+  #[arg(long, default_value_t = 500)]
+  delay_ms: u64,
+  #[command(flatten)]
+  device: DeviceArgs,
+}
+
+#[derive(Debug, PartialEq)]
+enum Transfer {
+  Control,
+  Interrupt,
+  Other,
+}
+
+// The parts of a captured USB transfer we care about.
+struct UsbPacket<'a> {
+  bus: u16,
+  device: u16,
+  endpoint: u8,
+  transfer: Transfer,
+  // Whether this is the completion of a request rather than its submission.
+  completion: bool,
+  data: &'a [u8],
+}
+
+fn decode_usb(linktype: u32, buf: &[u8]) -> Option<UsbPacket<'_>> {
+  match linktype {
+    LINKTYPE_USBPCAP => {
+      let header_len = pcap::u16_at(buf, 0, true)? as usize;
+      let data_len = pcap::u32_at(buf, 23, true)? as usize;
+      Some(UsbPacket {
+        bus: pcap::u16_at(buf, 17, true)?,
+        device: pcap::u16_at(buf, 19, true)?,
+        endpoint: *buf.get(21)?,
+        transfer: match buf.get(22)? {
+          1 => Transfer::Interrupt,
+          2 => Transfer::Control,
+          _ => Transfer::Other,
+        },
+        completion: buf.get(16)? & 0x1 != 0,
+        data: buf.get(header_len..header_len + data_len)?,
+      })
+    },
+    LINKTYPE_USB_LINUX | LINKTYPE_USB_LINUX_MMAPPED => {
+      let header_len = if linktype == LINKTYPE_USB_LINUX {
+        48
+      } else {
+        64
+      };
+      Some(UsbPacket {
+        bus: pcap::u16_at(buf, 12, true)?,
+        device: *buf.get(11)? as u16,
+        endpoint: *buf.get(10)?,
+        transfer: match buf.get(9)? {
+          1 => Transfer::Interrupt,
+          2 => Transfer::Control,
+          _ => Transfer::Other,
+        },
+        completion: *buf.get(8)? == b'C',
+        data: buf.get(header_len..)?,
+      })
+    },
+    _ => None,
+  }
+}
+
+// Lists the interrupt-OUT frames sent to MSI devices in a capture, decoded
+// with the same packet logic we use to talk to the monitor, and optionally
+// sends some of them to the monitor.
+pub(crate) fn run(args: ReplayArgs) -> Result<(), Box<StdError>> {
+  let packets = pcap::read_file(&args.capture)?;
+
+  // Device descriptors in the capture tell us which device is which. If the
+  // capture started after the monitor was plugged in, we won't see them and
+  // fall back to checking whether the frame looks like one of ours.
+  let mut ids: HashMap<(u16, u16), (u16, u16)> = HashMap::new();
+  let mut frames = Vec::new();
+  let mut start = None;
+
+  for packet in &packets {
+    let Some(usb) = decode_usb(packet.linktype, &packet.data) else {
+      continue;
+    };
+
+    if usb.transfer == Transfer::Control
+      && usb.completion
+      && usb.data.len() >= 18
+      && usb.data[0] == 18
+      && usb.data[1] == 1
+    {
+      let vendor_id = u16::from_le_bytes([usb.data[8], usb.data[9]]);
+      let product_id = u16::from_le_bytes([usb.data[10], usb.data[11]]);
+      ids.insert((usb.bus, usb.device), (vendor_id, product_id));
+      continue;
+    }
+
+    if usb.transfer != Transfer::Interrupt
+      || usb.completion
+      || usb.endpoint & 0x80 != 0
+      || usb.data.is_empty()
+    {
+      continue;
+    }
+
+    let id = ids.get(&(usb.bus, usb.device)).copied();
+    let frame = device::Frame::parse(usb.data);
+    match id {
+      Some((vendor_id, _)) if vendor_id != device::MSI_VENDOR_ID => continue,
+      None if frame.is_none() => continue,
+      _ => {},
+    }
+
+    let t0 = *start.get_or_insert(packet.timestamp);
+    frames.push((packet.timestamp - t0, id, usb.data, frame));
+  }
+
+  for (n, (time, id, data, frame)) in frames.iter().enumerate() {
+    let id = match id {
+      Some((vendor_id, product_id)) => format!("{:04x}:{:04x}", vendor_id, product_id),
+      None => "????:????".into(),
+    };
+    let annotation = match frame {
+      Some(frame) => {
+        let name = frame.name().unwrap_or("?");
+        match (&frame.access, frame.value) {
+          (device::Access::Write, Some(value)) => {
+            format!("write {:03x} ({}) = {}", frame.code, name, value)
+          },
+          _ => format!("read {:03x} ({})", frame.code, name),
+        }
+      },
+      None => "unknown".into(),
+    };
+    let end = data.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+    let bytes = data[..end]
+      .iter()
+      .map(|b| format!("{:02x}", b))
+      .collect::<Vec<_>>()
+      .join(" ");
+    println!(
+      "{:>4} {:>10.3}s {} {:<28} {}",
+      n, time, id, annotation, bytes
+    );
+  }
+
+  if args.send.is_empty() {
+    return Ok(());
+  }
+
+  let mut monitor = cli::Monitor::open(&args.device)?;
+  for n in args.send {
+    let Some((_, _, data, _)) = frames.get(n) else {
+      return Err(format!("no frame numbered {}", n).into());
+    };
+    event!(Level::INFO, "sending frame {}", n);
+    match monitor.send_raw(data)? {
+      Some(reply) => event!(Level::INFO, "reply: {:x?}", &reply[..12]),
+      None => event!(Level::INFO, "no reply"),
+    }
+    thread::sleep(Duration::from_millis(args.delay_ms));
+  }
+
+  Ok(())
+}
@@ -0,0 +1,1623 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+
+use crossbeam_channel::Sender;
+use device_query::DeviceQuery;
+use display_info::DisplayInfo;
+use global_hotkey::GlobalHotKeyEvent;
+use global_hotkey::GlobalHotKeyManager;
+use global_hotkey::HotKeyState;
+use global_hotkey::hotkey::HotKey;
+use mlua::ExternalError;
+use mlua::Function;
+use mlua::Lua;
+use mlua::LuaSerdeExt;
+use mouse_position::mouse_position::Mouse;
+use nusb::MaybeFuture;
+use nusb::hotplug::HotplugEvent;
+use rfd::MessageButtons;
+use rfd::MessageDialog;
+use rfd::MessageLevel;
+use rustautogui::RustAutoGui;
+use tao::event_loop::ControlFlow;
+use tao::event_loop::EventLoop;
+use tracing::Level;
+use tracing::event;
+
+use super::cli;
+use super::config;
+use super::daemon;
+#[cfg(target_os = "linux")]
+use super::dbus;
+use super::ddcci;
+use super::device;
+use super::errors;
+use super::errors::StdError;
+use super::http;
+use super::instance;
+use super::log_dry_run;
+use super::lua_input_source;
+use super::mqtt;
+use super::repl;
+use super::sandbox;
+use super::sequence;
+use super::switch;
+use super::tasks;
+use super::watch;
+
+static INTERVAL_COUNTER: AtomicUsize = AtomicUsize::new(1);
+
+fn get_interval_id() -> usize {
+  INTERVAL_COUNTER.fetch_add(1, Ordering::Relaxed)
+}
+
+// Returns "n", "s", "w", "e", "ne", "nw", "se", "sw" if the mouse is at a
+// screen edge/corner where it cannot move further in that direction across
+// any of the displays. Returns None otherwise.
+fn find_screen_edge(displays: &[DisplayInfo], x: i32, y: i32) -> Option<&'static str> {
+  let in_display = |px: i32, py: i32| -> bool {
+    displays.iter().any(|d| {
+      let dw = d.width as i32;
+      let dh = d.height as i32;
+      px >= d.x && px < d.x + dw && py >= d.y && py < d.y + dh
+    })
+  };
+
+  if !in_display(x, y) {
+    return None;
+  }
+
+  let at_left = !in_display(x - 1, y);
+  let at_right = !in_display(x + 1, y);
+  let at_top = !in_display(x, y - 1);
+  let at_bottom = !in_display(x, y + 1);
+
+  match (at_top, at_bottom, at_left, at_right) {
+    (true, _, true, _) => Some("nw"),
+    (true, _, _, true) => Some("ne"),
+    (_, true, true, _) => Some("sw"),
+    (_, true, _, true) => Some("se"),
+    (true, ..) => Some("n"),
+    (_, true, ..) => Some("s"),
+    (_, _, true, _) => Some("w"),
+    (_, _, _, true) => Some("e"),
+    _ => None,
+  }
+}
+
+// We wrap GlobalHotKeyManager so we can send it across threads. This is
+// safe to do for specific windows pointers like HWND since
+// it is unique globally.
+struct WrappedHotKeyManager(GlobalHotKeyManager);
+
+#[cfg(target_os = "windows")]
+unsafe impl Send for WrappedHotKeyManager {}
+#[cfg(target_os = "windows")]
+unsafe impl Sync for WrappedHotKeyManager {}
+
+// We wrap RustAutoGui so we can send it across threads. This is
+// safe to do for specific windows pointers like HWND since
+// it is unique globally.
+struct WrappedRustAutoGui(rustautogui::RustAutoGui);
+
+#[cfg(target_os = "windows")]
+unsafe impl Send for WrappedRustAutoGui {}
+#[cfg(target_os = "windows")]
+unsafe impl Sync for WrappedRustAutoGui {}
+
+static DO_MAIN_LOOP: AtomicBool = AtomicBool::new(false);
+
+// Whether the script called main_loop().
+pub(crate) fn wants_main_loop() -> bool {
+  DO_MAIN_LOOP.load(Ordering::Relaxed)
+}
+
+// The callback, the interval range and when it runs next. Timeouts from
+// set_timeout have no range and run once.
+type Interval = (Function, Option<(u64, u64)>, Instant);
+
+// When a hotkey's callback runs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum HotkeyOn {
+  Press,
+  Release,
+  // Once the keys are held down long enough. Letting go sooner is a tap.
+  Hold,
+}
+
+#[derive(Clone)]
+struct Binding {
+  // Tells this registration apart from later ones of the same keys, see
+  // HotkeyHandle.
+  id: usize,
+  callback: Function,
+  on: HotkeyOn,
+  hold: Duration,
+  // Run again this often while the keys stay down, after press or hold.
+  repeat: Option<Duration>,
+}
+
+impl Binding {
+  // Reads the options table of register_hotkey.
+  fn new(callback: Function, opts: Option<mlua::Table>) -> Result<Self, mlua::Error> {
+    let mut binding = Binding {
+      id: HOTKEY_COUNTER.fetch_add(1, Ordering::Relaxed),
+      callback,
+      on: HotkeyOn::Release,
+      hold: Duration::from_millis(500),
+      repeat: None,
+    };
+    let Some(opts) = opts else {
+      return Ok(binding);
+    };
+
+    binding.on = match opts.get::<Option<String>>("on")?.as_deref() {
+      None | Some("release") => HotkeyOn::Release,
+      Some("press") => HotkeyOn::Press,
+      Some("hold") => HotkeyOn::Hold,
+      Some(on) => {
+        return Err(mlua::Error::external(format!(
+          "unknown hotkey on = \"{}\", expected \"press\", \"release\" or \"hold\"",
+          on
+        )));
+      },
+    };
+    if let Some(ms) = opts.get::<Option<u64>>("hold_ms")? {
+      binding.hold = Duration::from_millis(ms);
+    }
+    binding.repeat = opts
+      .get::<Option<u64>>("repeat_ms")?
+      .map(Duration::from_millis);
+    if binding.repeat.is_some() && binding.on == HotkeyOn::Release {
+      return Err(mlua::Error::external(
+        "repeat_ms needs on = \"press\" or on = \"hold\"",
+      ));
+    }
+    if binding.repeat.is_some_and(|r| r.is_zero()) {
+      return Err(mlua::Error::external("repeat_ms must be > 0"));
+    }
+    Ok(binding)
+  }
+}
+
+static HOTKEY_COUNTER: AtomicUsize = AtomicUsize::new(1);
+
+// The registered hotkeys and their callbacks.
+type Hotkeys = Arc<Mutex<HashMap<HotKey, Binding>>>;
+
+// A hotkey that is down right now.
+struct HeldKey {
+  since: Instant,
+  // Whether a hold binding already ran.
+  held: bool,
+  next_repeat: Option<Instant>,
+}
+
+// The hotkeys and the first chords of key sequences.
+fn global_hotkeys(hotkeys: &Hotkeys, sequences: &Mutex<sequence::Sequences>) -> Vec<HotKey> {
+  let mut hotkeys = hotkeys.lock().unwrap().keys().copied().collect::<Vec<_>>();
+  hotkeys.extend(sequences.lock().unwrap().leaders());
+  hotkeys
+}
+
+fn hotkey_names(hotkeys: &Hotkeys, sequences: &Mutex<sequence::Sequences>) -> Vec<String> {
+  let mut names = global_hotkeys(hotkeys, sequences)
+    .iter()
+    .map(|hk| hk.to_string())
+    .collect::<Vec<_>>();
+  names.sort();
+  names
+}
+
+// Releases a hotkey. With an id, only if that registration is still the
+// current one. Returns false if nothing was released.
+fn remove_hotkey(
+  hk_manager: &Mutex<WrappedHotKeyManager>,
+  hotkeys: &Hotkeys,
+  hotkey: HotKey,
+  id: Option<usize>,
+) -> Result<bool, mlua::Error> {
+  let mut hk = hotkeys.lock().unwrap();
+  let current = hk
+    .get(&hotkey)
+    .is_some_and(|binding| id.is_none_or(|id| id == binding.id));
+  if !current {
+    return Ok(false);
+  }
+  hk.remove(&hotkey);
+  drop(hk);
+  hk_manager
+    .lock()
+    .unwrap()
+    .0
+    .unregister(hotkey)
+    .map_err(mlua::ExternalError::into_lua_err)?;
+  Ok(true)
+}
+
+// What register_hotkey returns, to unregister the hotkey later. Registering
+// the same keys again replaces the callback, after which this handle no
+// longer unregisters anything.
+struct HotkeyHandle {
+  hotkey: HotKey,
+  id: usize,
+  hotkeys: Hotkeys,
+  hk_manager: Arc<Mutex<WrappedHotKeyManager>>,
+}
+
+impl mlua::UserData for HotkeyHandle {
+  fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+    methods.add_method("unregister", |_, this, ()| -> Result<bool, mlua::Error> {
+      remove_hotkey(&this.hk_manager, &this.hotkeys, this.hotkey, Some(this.id))
+    });
+
+    methods.add_meta_method(
+      mlua::MetaMethod::ToString,
+      |_, this, ()| -> Result<String, mlua::Error> { Ok(this.hotkey.to_string()) },
+    );
+  }
+}
+
+// What register_key_sequence returns, to unregister the sequence later.
+struct KeySequenceHandle {
+  text: String,
+  id: usize,
+  sequences: Arc<Mutex<sequence::Sequences>>,
+  hk_manager: Arc<Mutex<WrappedHotKeyManager>>,
+}
+
+impl mlua::UserData for KeySequenceHandle {
+  fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+    methods.add_method("unregister", |_, this, ()| -> Result<bool, mlua::Error> {
+      let mut sequences = this.sequences.lock().unwrap();
+      let Some(sequence) = sequences.remove(this.id) else {
+        return Ok(false);
+      };
+      // The first chord is released once no other sequence starts with it.
+      if let Some(leader) = sequence.leader()
+        && !sequences.leaders().contains(&leader)
+      {
+        this
+          .hk_manager
+          .lock()
+          .unwrap()
+          .0
+          .unregister(leader)
+          .map_err(mlua::ExternalError::into_lua_err)?;
+      }
+      Ok(true)
+    });
+
+    methods.add_meta_method(
+      mlua::MetaMethod::ToString,
+      |_, this, ()| -> Result<String, mlua::Error> { Ok(this.text.clone()) },
+    );
+  }
+}
+
+static HOTPLUG_COUNTER: AtomicUsize = AtomicUsize::new(1);
+
+// Which devices a hotplug callback is for. Leaving out a field matches any
+// device.
+struct HotplugFilter {
+  vendor_id: Option<u16>,
+  product_id: Option<u16>,
+  serial: Option<String>,
+  connected: bool,
+  disconnected: bool,
+}
+
+impl HotplugFilter {
+  fn new(table: mlua::Table) -> Result<Self, mlua::Error> {
+    let (connected, disconnected) = match table.get::<Option<String>>("on")?.as_deref() {
+      None | Some("both") => (true, true),
+      Some("connected") => (true, false),
+      Some("disconnected") => (false, true),
+      Some(on) => {
+        return Err(mlua::Error::external(format!(
+          "unknown hotplug on = \"{}\", expected \"connected\", \"disconnected\" or \"both\"",
+          on
+        )));
+      },
+    };
+    Ok(Self {
+      vendor_id: table.get("vendor_id")?,
+      product_id: table.get("product_id")?,
+      serial: table.get("serial")?,
+      connected,
+      disconnected,
+    })
+  }
+
+  fn matches(&self, change: &HotplugChange) -> bool {
+    (if change.connected {
+      self.connected
+    } else {
+      self.disconnected
+    }) && self.vendor_id.is_none_or(|vid| vid == change.vendor_id)
+      && self.product_id.is_none_or(|pid| pid == change.product_id)
+      && self
+        .serial
+        .as_ref()
+        .is_none_or(|serial| change.serial.as_ref() == Some(serial))
+  }
+}
+
+impl Default for HotplugFilter {
+  fn default() -> Self {
+    Self {
+      vendor_id: None,
+      product_id: None,
+      serial: None,
+      connected: true,
+      disconnected: true,
+    }
+  }
+}
+
+struct HotplugHandler {
+  filter: HotplugFilter,
+  callback: Function,
+}
+
+// The hotplug callbacks by id, which is also the order they were
+// registered in.
+type Hotplugs = Arc<Mutex<BTreeMap<usize, HotplugHandler>>>;
+
+// A USB device that came or went.
+struct HotplugChange {
+  connected: bool,
+  vendor_id: u16,
+  product_id: u16,
+  serial: Option<String>,
+}
+
+// What register_hotplug returns, to unregister the callback later.
+struct HotplugHandle {
+  id: usize,
+  hotplug: Hotplugs,
+}
+
+impl mlua::UserData for HotplugHandle {
+  fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+    methods.add_method("unregister", |_, this, ()| -> Result<bool, mlua::Error> {
+      Ok(this.hotplug.lock().unwrap().remove(&this.id).is_some())
+    });
+  }
+}
+
+// Everything that lives as long as the process. Reloading a script keeps
+// these around.
+pub(crate) struct Context {
+  // The script, config or Lua code we were started with.
+  cmd: String,
+  hk_manager: Arc<Mutex<WrappedHotKeyManager>>,
+  rustautogui: Arc<Mutex<WrappedRustAutoGui>>,
+  devices: Arc<Mutex<HashMap<nusb::DeviceId, nusb::DeviceInfo>>>,
+  hotplug_rx: crossbeam_channel::Receiver<HotplugEvent>,
+  http: Option<http::Options>,
+  mqtt: Option<mqtt::Options>,
+  // The (vendor_id, product_id) of the monitor to expose on the D-Bus
+  // session bus, if any. Only used on linux.
+  dbus: Option<(u16, u16)>,
+  // Reload the script when its files change.
+  watch: bool,
+  // What --sandbox grants the script, see `sandbox::permissions`.
+  sandbox: Option<HashSet<sandbox::Permission>>,
+}
+
+impl Context {
+  pub(crate) fn new(
+    cmd: String,
+    http: Option<http::Options>,
+    mqtt: Option<mqtt::Options>,
+    dbus: Option<(u16, u16)>,
+    watch: bool,
+    sandbox: Option<HashSet<sandbox::Permission>>,
+  ) -> Result<Self, Box<StdError>> {
+    let hotkeys_manager = GlobalHotKeyManager::new()?;
+
+    let (hotplug_tx, hotplug_rx) = crossbeam_channel::unbounded();
+    std::thread::spawn(move || {
+      let watch = nusb::watch_devices().unwrap();
+      for event in futures_lite::stream::block_on(watch) {
+        hotplug_tx.send(event).unwrap();
+      }
+    });
+
+    let devices = nusb::list_devices().wait()?.map(|d| (d.id(), d)).collect();
+
+    Ok(Self {
+      cmd,
+      hk_manager: Arc::new(Mutex::new(WrappedHotKeyManager(hotkeys_manager))),
+      rustautogui: Arc::new(Mutex::new(WrappedRustAutoGui(RustAutoGui::new(false)?))),
+      devices: Arc::new(Mutex::new(devices)),
+      hotplug_rx,
+      http,
+      mqtt,
+      dbus,
+      watch,
+      sandbox,
+    })
+  }
+}
+
+// A Lua state and the callbacks its script registered. Reloading replaces
+// the whole thing.
+pub(crate) struct Runtime {
+  lua: Lua,
+  hotkeys: Hotkeys,
+  sequences: Arc<Mutex<sequence::Sequences>>,
+  hotplug: Hotplugs,
+  screen_edge: Arc<Mutex<Option<Function>>>,
+  interval_callbacks: Arc<Mutex<HashMap<usize, Interval>>>,
+  // Named functions other programs can call, see register_action.
+  actions: Arc<Mutex<HashMap<String, Function>>>,
+  // Callbacks that are waiting for something.
+  tasks: tasks::Tasks,
+}
+
+impl Runtime {
+  // Creates a Lua state with our API and runs the script in it. If the
+  // script fails, the hotkeys it registered are released again.
+  pub(crate) fn load(ctx: &Context) -> Result<Self, Box<StdError>> {
+    let lua = Lua::new();
+
+    let device_open = lua.create_function(
+      |lua, (vendor_id, product_id): (u16, u16)| -> Result<tasks::Job, mlua::Error> {
+        log_dry_run(
+          lua,
+          format_args!("device_open({:#06x}, {:#06x})", vendor_id, product_id),
+        );
+        Ok(tasks::Job::spawn(move || {
+          let dev = device::MSIDevice::open(vendor_id, product_id)?;
+          Ok(tasks::Shared::new(dev))
+        }))
+      },
+    )?;
+
+    let device_is_connected = lua.create_function(
+      |_, (vendor_id, product_id): (u16, u16)| -> Result<bool, mlua::Error> {
+        let connected = device::MSIDevice::is_connected(vendor_id, product_id)
+          .map_err(mlua::ExternalError::into_lua_err)?;
+        Ok(connected)
+      },
+    )?;
+
+    let ddc = lua.create_table()?;
+    ddc.set(
+      "list",
+      lua.create_function(|_, ()| -> Result<tasks::Job, mlua::Error> {
+        Ok(tasks::Job::spawn(|| {
+          let monitors = ddcci::DdcMonitor::list()?;
+          Ok(
+            monitors
+              .into_iter()
+              .map(tasks::Shared::new)
+              .collect::<Vec<_>>(),
+          )
+        }))
+      })?,
+    )?;
+
+    let switch_input = lua.create_function(
+      |lua, opts: mlua::Table| -> Result<tasks::Job, mlua::Error> {
+        let ddc_input = match opts.get::<mlua::Value>("ddc_input")? {
+          mlua::Value::Nil => None,
+          source => Some(lua_input_source(lua, source)?),
+        };
+        let opts = switch::SwitchInput {
+          vendor_id: opts.get("vendor_id")?,
+          product_id: opts.get("product_id")?,
+          input: opts.get("input")?,
+          ddc_input,
+          serial: opts.get("serial")?,
+        };
+        log_dry_run(lua, format_args!("switch_input({})", opts.input));
+        Ok(tasks::Job::spawn(move || {
+          let path = switch::switch_input(&opts)?;
+          Ok(path.as_str())
+        }))
+      },
+    )?;
+
+    let hotkeys: Hotkeys = Arc::new(Mutex::new(HashMap::new()));
+    let sequences: Arc<Mutex<sequence::Sequences>> = Arc::default();
+
+    let hotkeys_clone = hotkeys.clone();
+    let sequences_clone = sequences.clone();
+    let hk_manager = ctx.hk_manager.clone();
+    let register_hotkey = lua.create_function(
+      move |_,
+            (keybind, callback, opts): (String, Function, Option<mlua::Table>)|
+            -> Result<HotkeyHandle, mlua::Error> {
+        let hotkey = HotKey::from_str(&keybind).map_err(mlua::ExternalError::into_lua_err)?;
+        let binding = Binding::new(callback, opts)?;
+        if sequences_clone.lock().unwrap().leaders().contains(&hotkey) {
+          return Err(mlua::Error::external(format!(
+            "{} already starts a key sequence",
+            keybind
+          )));
+        }
+
+        // Registering a key again replaces its callback.
+        let mut hk = hotkeys_clone.lock().unwrap();
+        if !hk.contains_key(&hotkey) {
+          let hk_manager = hk_manager.lock().unwrap();
+          hk_manager
+            .0
+            .register(hotkey)
+            .map_err(mlua::ExternalError::into_lua_err)?;
+        }
+        let id = binding.id;
+        hk.insert(hotkey, binding);
+
+        Ok(HotkeyHandle {
+          hotkey,
+          id,
+          hotkeys: hotkeys_clone.clone(),
+          hk_manager: hk_manager.clone(),
+        })
+      },
+    )?;
+
+    let hotkeys_clone = hotkeys.clone();
+    let hk_manager = ctx.hk_manager.clone();
+    let unregister_hotkey =
+      lua.create_function(move |_, keybind: String| -> Result<bool, mlua::Error> {
+        let hotkey = HotKey::from_str(&keybind).map_err(mlua::ExternalError::into_lua_err)?;
+        remove_hotkey(&hk_manager, &hotkeys_clone, hotkey, None)
+      })?;
+
+    let hotkeys_clone = hotkeys.clone();
+    let sequences_clone = sequences.clone();
+    let hk_manager = ctx.hk_manager.clone();
+    let register_key_sequence = lua.create_function(
+      move |_,
+            (keys, callback, opts): (String, Function, Option<mlua::Table>)|
+            -> Result<KeySequenceHandle, mlua::Error> {
+        let timeout_ms = match &opts {
+          Some(opts) => opts.get::<Option<u64>>("timeout_ms")?.unwrap_or(1000),
+          None => 1000,
+        };
+        let sequence =
+          sequence::Sequence::parse(&keys, callback, Duration::from_millis(timeout_ms))
+            .map_err(mlua::Error::external)?;
+
+        let mut sequences = sequences_clone.lock().unwrap();
+        // Sequences with the same first chord share its global hotkey.
+        if let Some(leader) = sequence.leader()
+          && !sequences.leaders().contains(&leader)
+        {
+          if hotkeys_clone.lock().unwrap().contains_key(&leader) {
+            return Err(mlua::Error::external(format!(
+              "the first chord of '{}' is already a hotkey",
+              keys
+            )));
+          }
+          hk_manager
+            .lock()
+            .unwrap()
+            .0
+            .register(leader)
+            .map_err(mlua::ExternalError::into_lua_err)?;
+        }
+        let id = sequences.push(sequence);
+
+        Ok(KeySequenceHandle {
+          text: keys,
+          id,
+          sequences: sequences_clone.clone(),
+          hk_manager: hk_manager.clone(),
+        })
+      },
+    )?;
+
+    let hotkeys_clone = hotkeys.clone();
+    let sequences_clone = sequences.clone();
+    let list_hotkeys = lua.create_function(move |_, ()| -> Result<Vec<String>, mlua::Error> {
+      Ok(hotkey_names(&hotkeys_clone, &sequences_clone))
+    })?;
+
+    let hotplug: Hotplugs = Arc::new(Mutex::new(BTreeMap::new()));
+    let hotplug_clone = hotplug.clone();
+    let register_hotplug = lua.create_function(
+      move |_,
+            (first, callback): (mlua::Value, Option<Function>)|
+            -> Result<HotplugHandle, mlua::Error> {
+        // Either register_hotplug(fn) for every device,
+        // register_hotplug({ vendor_id = ..., callback = fn }) or
+        // register_hotplug({ vendor_id = ..., ... }, fn).
+        let handler = match (first, callback) {
+          (mlua::Value::Function(callback), None) => {
+            HotplugHandler {
+              filter: HotplugFilter::default(),
+              callback,
+            }
+          },
+          (mlua::Value::Table(filter), None) => {
+            let callback = filter
+              .get::<Option<Function>>("callback")?
+              .ok_or_else(|| mlua::Error::external("register_hotplug needs a callback"))?;
+            HotplugHandler {
+              filter: HotplugFilter::new(filter)?,
+              callback,
+            }
+          },
+          (mlua::Value::Table(filter), Some(callback)) => {
+            HotplugHandler {
+              filter: HotplugFilter::new(filter)?,
+              callback,
+            }
+          },
+          _ => {
+            return Err(mlua::Error::external(
+              "expected register_hotplug(callback), register_hotplug({ ..., callback = callback }) or register_hotplug(filter, callback)",
+            ));
+          },
+        };
+
+        let id = HOTPLUG_COUNTER.fetch_add(1, Ordering::Relaxed);
+        hotplug_clone.lock().unwrap().insert(id, handler);
+        Ok(HotplugHandle {
+          id,
+          hotplug: hotplug_clone.clone(),
+        })
+      },
+    )?;
+
+    let screen_edge: Arc<Mutex<Option<Function>>> = Arc::new(Mutex::new(None));
+    let screen_edge_clone = screen_edge.clone();
+    let register_screen_edge =
+      lua.create_function(move |_, callback: Function| -> Result<(), mlua::Error> {
+        let mut se = screen_edge_clone.lock().unwrap();
+        *se = Some(callback);
+        Ok(())
+      })?;
+
+    let interval_callbacks: Arc<Mutex<HashMap<usize, Interval>>> =
+      Arc::new(Mutex::new(HashMap::new()));
+    let interval_callbacks_clone = interval_callbacks.clone();
+    let register_interval = lua.create_function(
+      move |_,
+            (lo_interval, hi_interval, callback): (u64, u64, Function)|
+            -> Result<usize, mlua::Error> {
+        if lo_interval > hi_interval {
+          return Err(mlua::Error::external("lo_interval must be <= hi_interval"));
+        }
+
+        let mut ic = interval_callbacks_clone.lock().unwrap();
+        let id = get_interval_id();
+        let interval = rand::random_range(lo_interval..=hi_interval);
+        let next = std::time::Instant::now() + Duration::from_millis(interval);
+        ic.insert(id, (callback, Some((lo_interval, hi_interval)), next));
+        Ok(id)
+      },
+    )?;
+
+    let interval_callbacks_clone = interval_callbacks.clone();
+    let set_timeout = lua.create_function(
+      move |_, (ms, callback): (u64, Function)| -> Result<usize, mlua::Error> {
+        let mut ic = interval_callbacks_clone.lock().unwrap();
+        let id = get_interval_id();
+        let next = std::time::Instant::now() + Duration::from_millis(ms);
+        ic.insert(id, (callback, None, next));
+        Ok(id)
+      },
+    )?;
+
+    let interval_callbacks_clone = interval_callbacks.clone();
+    let list_timers = lua.create_function(move |lua, ()| -> Result<mlua::Table, mlua::Error> {
+      let ic = interval_callbacks_clone.lock().unwrap();
+      let mut ids = ic.keys().copied().collect::<Vec<_>>();
+      ids.sort();
+
+      let now = std::time::Instant::now();
+      let timers = lua.create_table()?;
+      for id in ids {
+        let (_, range, next) = &ic[&id];
+        let timer = lua.create_table()?;
+        timer.set("id", id)?;
+        timer.set(
+          "kind",
+          if range.is_some() {
+            "interval"
+          } else {
+            "timeout"
+          },
+        )?;
+        timer.set(
+          "due_in_ms",
+          next.saturating_duration_since(now).as_millis() as u64,
+        )?;
+        if let Some((lo, hi)) = range {
+          timer.set("min_ms", *lo)?;
+          timer.set("max_ms", *hi)?;
+        }
+        timers.push(timer)?;
+      }
+      Ok(timers)
+    })?;
+
+    let interval_callbacks_clone = interval_callbacks.clone();
+    let unregister_interval =
+      lua.create_function(move |_, id: usize| -> Result<(), mlua::Error> {
+        let mut ic = interval_callbacks_clone.lock().unwrap();
+        ic.remove(&id);
+        Ok(())
+      })?;
+
+    let actions: Arc<Mutex<HashMap<String, Function>>> = Arc::new(Mutex::new(HashMap::new()));
+    let actions_clone = actions.clone();
+    let register_action = lua.create_function(
+      move |_, (name, callback): (String, Function)| -> Result<(), mlua::Error> {
+        let mut actions = actions_clone.lock().unwrap();
+        actions.insert(name, callback);
+        Ok(())
+      },
+    )?;
+
+    let msgbox = lua.create_function(
+      move |_,
+            (title, message, level, buttoncfg): (
+        String,
+        String,
+        String,
+        HashMap<String, Vec<String>>,
+      )|
+            -> Result<(), mlua::Error> {
+        let level = match level.to_lowercase().as_str() {
+          "info" | "" => MessageLevel::Info,
+          "warning" => MessageLevel::Warning,
+          "error" => MessageLevel::Error,
+          _ => {
+            return Err(mlua::Error::external(format!(
+              "unknown msgbox level: {}",
+              level
+            )));
+          },
+        };
+
+        let mut buttons = buttoncfg.into_iter();
+        let button_opt = buttons.next().unwrap_or(("Ok".into(), vec![]));
+        let button_opt_len = button_opt.1.len();
+        let mut x = button_opt.1.into_iter();
+        let btns = match (button_opt.0.as_str(), button_opt_len) {
+          ("Ok", 0) => MessageButtons::Ok,
+          ("Ok", 1) => MessageButtons::OkCustom(x.next().unwrap()),
+          ("OkCancel", 0) => MessageButtons::OkCancel,
+          ("OkCancel", 1) => MessageButtons::OkCancelCustom(x.next().unwrap(), "Cancel".into()),
+          ("OkCancel", 2) => MessageButtons::OkCancelCustom(x.next().unwrap(), x.next().unwrap()),
+          ("YesNo", 0) => MessageButtons::YesNo,
+          ("YesNoCancel", 0) => MessageButtons::YesNoCancel,
+          ("YesNoCancel", 1) => {
+            MessageButtons::YesNoCancelCustom(x.next().unwrap(), "No".into(), "Cancel".into())
+          },
+          ("YesNoCancel", 2) => {
+            MessageButtons::YesNoCancelCustom(x.next().unwrap(), x.next().unwrap(), "Cancel".into())
+          },
+          ("YesNoCancel", 3) => {
+            MessageButtons::YesNoCancelCustom(
+              x.next().unwrap(),
+              x.next().unwrap(),
+              x.next().unwrap(),
+            )
+          },
+          (type_, _) => {
+            return Err(mlua::Error::external(format!(
+              "unknown msgbox buttons: {}={}",
+              type_,
+              x.as_slice().join(","),
+            )));
+          },
+        };
+
+        let dialog = MessageDialog::new()
+          .set_title(title)
+          .set_description(message)
+          .set_buttons(btns)
+          .set_level(level);
+        dialog.show();
+        Ok(())
+      },
+    )?;
+
+    let autorun = lua.create_function(
+      |_, (app_path, args): (Option<String>, Option<Vec<String>>)| -> Result<(), mlua::Error> {
+        let mut autolaunch = auto_launch::AutoLaunchBuilder::new();
+
+        autolaunch
+          .set_app_name(env!("CARGO_CRATE_NAME"))
+          .set_macos_launch_mode(auto_launch::MacOSLaunchMode::LaunchAgent);
+
+        match (app_path, args) {
+          (Some(app_path), Some(args)) => {
+            autolaunch
+              .set_app_path(app_path.as_ref())
+              .set_args(args.as_ref());
+          },
+          (Some(app_path), None) => {
+            autolaunch.set_app_path(app_path.as_ref()).set_args(
+              &std::env::args()
+                .skip(1)
+                .map(|v| format!(r#""{}""#, v))
+                .collect::<Vec<_>>(),
+            );
+          },
+          (None, Some(args)) => {
+            autolaunch
+              .set_app_path(std::env::current_exe()?.to_str().unwrap())
+              .set_args(args.as_ref());
+          },
+          (None, None) => {
+            autolaunch
+              .set_app_path(std::env::current_exe()?.to_str().unwrap())
+              .set_args(
+                &std::env::args()
+                  .skip(1)
+                  .map(|v| format!(r#""{}""#, v))
+                  .collect::<Vec<_>>(),
+              );
+          },
+        };
+
+        let autolaunch = autolaunch.build().map_err(|e| e.into_lua_err())?;
+        autolaunch.enable().map_err(|e| e.into_lua_err())?;
+
+        Ok(())
+      },
+    )?;
+
+    let rustautogui_clone = ctx.rustautogui.clone();
+    let screen_size = lua.create_function(move |_, ()| -> Result<(i32, i32), mlua::Error> {
+      let mut rag = rustautogui_clone.lock().unwrap();
+      Ok(rag.0.get_screen_size())
+    })?;
+    let rustautogui_clone = ctx.rustautogui.clone();
+    let move_mouse = lua.create_function(
+      move |_, (x, y, moving_time, mode): (i64, i64, f32, String)| -> Result<(), mlua::Error> {
+        // Anything > 10.0 is VERY slow and can lock your computer.
+        let moving_time = if moving_time > 10.0 {
+          10.0
+        } else {
+          moving_time
+        };
+
+        let rag = rustautogui_clone.lock().unwrap();
+        match mode.as_str() {
+          "rel" => {
+            rag
+              .0
+              .move_mouse(
+                i32::try_from(x).map_err(|e| e.into_lua_err())?,
+                i32::try_from(y).map_err(|e| e.into_lua_err())?,
+                moving_time,
+              )
+              .map_err(|e| e.into_lua_err())?;
+          },
+          "abs" => {
+            rag
+              .0
+              .move_mouse_to_pos(
+                u32::try_from(x).map_err(|e| e.into_lua_err())?,
+                u32::try_from(y).map_err(|e| e.into_lua_err())?,
+                moving_time,
+              )
+              .map_err(|e| e.into_lua_err())?;
+          },
+          _ => {
+            return Err(mlua::Error::external(format!(
+              "unknown move_mouse mode: {}",
+              mode
+            )));
+          },
+        };
+        Ok(())
+      },
+    )?;
+
+    let main_loop = lua.create_function(move |_, ()| -> Result<(), mlua::Error> {
+      DO_MAIN_LOOP.swap(true, Ordering::Relaxed);
+      Ok(())
+    })?;
+
+    let globals = lua.globals();
+    globals.set("device_open", &device_open)?;
+    globals.set("device_is_connected", &device_is_connected)?;
+    globals.set("ddc", &ddc)?;
+    globals.set("switch_input", &switch_input)?;
+    globals.set("msgbox", &msgbox)?;
+    globals.set("register_hotkey", &register_hotkey)?;
+    globals.set("unregister_hotkey", &unregister_hotkey)?;
+    globals.set("list_hotkeys", &list_hotkeys)?;
+    globals.set("register_key_sequence", &register_key_sequence)?;
+    globals.set("register_hotplug", &register_hotplug)?;
+    globals.set("register_screen_edge", &register_screen_edge)?;
+    globals.set("main_loop", &main_loop)?;
+    globals.set("host_os", std::env::consts::OS)?;
+    globals.set("host_arch", std::env::consts::ARCH)?;
+    globals.set("host_family", std::env::consts::FAMILY)?;
+    globals.set("autorun", autorun)?;
+    globals.set("register_interval", &register_interval)?;
+    globals.set("register_action", &register_action)?;
+
+    globals.set("unregister_interval", &unregister_interval)?;
+    globals.set("set_timeout", &set_timeout)?;
+    globals.set("clear_timeout", &unregister_interval)?;
+    globals.set("list_timers", &list_timers)?;
+    globals.set("move_mouse", &move_mouse)?;
+    globals.set("screen_size", &screen_size)?;
+    let tasks = tasks::Tasks::install(&lua)?;
+
+    let runtime = Self {
+      lua,
+      hotkeys,
+      sequences,
+      hotplug,
+      screen_edge,
+      interval_callbacks,
+      actions,
+      tasks,
+    };
+    if let Err(err) = runtime.exec(ctx) {
+      runtime.unregister(ctx);
+      return Err(err);
+    }
+    Ok(runtime)
+  }
+
+  fn exec(&self, ctx: &Context) -> Result<(), Box<StdError>> {
+    let cmd_path = std::path::Path::new(&ctx.cmd);
+
+    // Let `require` find modules next to the script and in the config
+    // directory, whatever the cwd is.
+    let mut search_dirs = Vec::new();
+    if cmd_path.is_file()
+      && let Some(dir) = cmd_path.parent()
+    {
+      search_dirs.push(std::path::absolute(dir)?);
+    }
+    search_dirs.push(super::project_dirs()?.config_dir().to_path_buf());
+    add_package_path(&self.lua, &search_dirs)?;
+
+    let is_config = cmd_path
+      .extension()
+      .is_some_and(|ext| ext.eq_ignore_ascii_case("toml"));
+    if cmd_path.is_file() && is_config {
+      let config = config::load(cmd_path)?;
+      if let Some(permissions) = sandbox::permissions(config.sandbox()?, ctx.sandbox.as_ref()) {
+        sandbox::apply(&self.lua, permissions)?;
+      }
+      config::install(&self.lua, config)?;
+    } else if cmd_path.is_file() {
+      let source = std::fs::read_to_string(cmd_path)
+        .map_err(|e| mlua::Error::RuntimeError(format!("could not read '{}': {}", ctx.cmd, e)))?;
+      if let Some(permissions) =
+        sandbox::permissions(sandbox::header(&source)?, ctx.sandbox.as_ref())
+      {
+        sandbox::apply(&self.lua, permissions)?;
+      }
+      self.lua.load(&source).set_name(&ctx.cmd).exec()?;
+    } else {
+      if let Some(permissions) =
+        sandbox::permissions(sandbox::header(&ctx.cmd)?, ctx.sandbox.as_ref())
+      {
+        sandbox::apply(&self.lua, permissions)?;
+      }
+      self.lua.load(&ctx.cmd).exec()?;
+    }
+
+    Ok(())
+  }
+
+  // The script and the modules it required, for --watch. Lua code passed
+  // on the command line has no files.
+  fn files(&self, ctx: &Context) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let cmd_path = std::path::Path::new(&ctx.cmd);
+    if cmd_path.is_file()
+      && let Ok(path) = std::path::absolute(cmd_path)
+    {
+      files.push(path);
+    }
+    match self.module_files() {
+      Ok(modules) => files.extend(modules),
+      Err(err) => event!(Level::ERROR, "could not find the required modules: {}", err),
+    }
+    files
+  }
+
+  // Looks up every loaded module on package.path, which skips the standard
+  // library and anything that isn't a file.
+  fn module_files(&self) -> Result<Vec<PathBuf>, mlua::Error> {
+    let package: mlua::Table = self.lua.globals().get("package")?;
+    let path: String = package.get("path")?;
+    let searchpath: Function = package.get("searchpath")?;
+    let loaded: mlua::Table = package.get("loaded")?;
+
+    let mut files = Vec::new();
+    for pair in loaded.pairs::<String, mlua::Value>() {
+      let (name, _) = pair?;
+      if let Some(file) = searchpath.call::<Option<String>>((name, path.as_str()))? {
+        files.push(std::path::absolute(file)?);
+      }
+    }
+    Ok(files)
+  }
+
+  fn global_hotkeys(&self) -> Vec<HotKey> {
+    global_hotkeys(&self.hotkeys, &self.sequences)
+  }
+
+  // Releases our global hotkeys so another Lua state can take them.
+  fn unregister(&self, ctx: &Context) {
+    let hk_manager = ctx.hk_manager.lock().unwrap();
+    for hotkey in self.global_hotkeys() {
+      if let Err(err) = hk_manager.0.unregister(hotkey) {
+        event!(
+          Level::ERROR,
+          "could not unregister hotkey {}: {}",
+          hotkey,
+          err
+        );
+      }
+    }
+  }
+
+  // Takes our global hotkeys back after unregister().
+  fn register(&self, ctx: &Context) {
+    let hk_manager = ctx.hk_manager.lock().unwrap();
+    for hotkey in self.global_hotkeys() {
+      if let Err(err) = hk_manager.0.register(hotkey) {
+        event!(
+          Level::ERROR,
+          "could not register hotkey {}: {}",
+          hotkey,
+          err
+        );
+      }
+    }
+  }
+
+  // Runs a hotkey's callback with the keys and what they did.
+  fn fire_hotkey(&mut self, name: &str, binding: &Binding, state: &'static str) {
+    self.tasks.spawn(
+      "hotkey",
+      binding.callback.clone(),
+      (name.to_string(), state),
+    );
+    self.tasks.emit("hotkey", (name.to_string(), state));
+  }
+
+  pub(crate) fn hotkey_names(&self) -> Vec<String> {
+    hotkey_names(&self.hotkeys, &self.sequences)
+  }
+
+  fn action_names(&self) -> Vec<String> {
+    let mut names = self
+      .actions
+      .lock()
+      .unwrap()
+      .keys()
+      .cloned()
+      .collect::<Vec<_>>();
+    names.sort();
+    names
+  }
+
+  // Evaluates a line from the REPL. Returns None if more input is needed.
+  pub(crate) fn eval(&self, code: &str) -> Result<Option<String>, mlua::Error> {
+    repl::eval(&self.lua, code)
+  }
+
+  // Starts a registered action with a JSON argument, as a task like any
+  // callback, so it can sleep or wait without holding up the main loop.
+  // What it returns is sent to `reply` as JSON once it finishes.
+  fn run_action(
+    &mut self,
+    name: &str,
+    arg: serde_json::Value,
+    reply: Sender<daemon::Response>,
+  ) -> Result<(), Box<StdError>> {
+    let action = self
+      .actions
+      .lock()
+      .unwrap()
+      .get(name)
+      .cloned()
+      .ok_or_else(|| {
+        errors::RequestError::new(
+          errors::ErrorKind::NotFound,
+          format!("no action named '{}'", name),
+        )
+      })?;
+    let arg = match arg {
+      serde_json::Value::Null => mlua::Value::Nil,
+      arg => {
+        self.lua.to_value(&arg).map_err(|err| {
+          errors::RequestError::new(errors::ErrorKind::InvalidArgument, err.to_string())
+        })?
+      },
+    };
+    let lua = self.lua.clone();
+    let name = name.to_string();
+    self.tasks.spawn_then("action", action, arg, move |result| {
+      let value = result.and_then(|values| {
+        lua.from_value::<serde_json::Value>(values.into_iter().next().unwrap_or(mlua::Value::Nil))
+      });
+      let response = match value {
+        Ok(value) => daemon::Response::Ok(value),
+        Err(err) => {
+          event!(Level::ERROR, "action {}: {}", name, err);
+          daemon::Response::error(&err)
+        },
+      };
+      let _ = reply.send(response);
+    });
+    Ok(())
+  }
+}
+
+// Runs the script again in a fresh Lua state. If that fails, the previous
+// state stays in place.
+pub(crate) fn reload(ctx: &Context, runtime: &mut Runtime) -> Result<(), Box<StdError>> {
+  event!(Level::INFO, "reloading {}", ctx.cmd);
+  runtime.unregister(ctx);
+  match Runtime::load(ctx) {
+    Ok(new) => {
+      // The old timers go away with the old state, say so in case a
+      // timeout was about to fire.
+      let pending = runtime.interval_callbacks.lock().unwrap().len();
+      if pending > 0 {
+        event!(Level::DEBUG, "cancelled {} timers", pending);
+      }
+      *runtime = new;
+      Ok(())
+    },
+    Err(err) => {
+      runtime.register(ctx);
+      Err(err)
+    },
+  }
+}
+
+// Answers a request. Actions answer through `reply` themselves once they
+// finish, so there is nothing to answer for them yet.
+fn handle_request(
+  ctx: &Context,
+  runtime: &mut Runtime,
+  request: daemon::Request,
+  reply: &Sender<daemon::Response>,
+) -> Result<Option<serde_json::Value>, Box<StdError>> {
+  let value = match request {
+    daemon::Request::Switch {
+      vendor_id,
+      product_id,
+      input,
+      kvm,
+      ddc_input,
+      serial,
+    } => {
+      let host = switch::Host {
+        input,
+        kvm,
+      };
+      let path = switch::switch_host(vendor_id, product_id, host, ddc_input, serial)?;
+      Ok(path.as_str().into())
+    },
+    daemon::Request::Get {
+      vendor_id,
+      product_id,
+      location,
+      code,
+    } => {
+      let mut dev = device::MSIDevice::open_at(vendor_id, product_id, location)?;
+      Ok(dev.get(code)?.into())
+    },
+    daemon::Request::Set {
+      vendor_id,
+      product_id,
+      location,
+      code,
+      value,
+    } => {
+      let mut dev = device::MSIDevice::open_at(vendor_id, product_id, location)?;
+      dev.set(code, value)?;
+      Ok(serde_json::Value::Null)
+    },
+    daemon::Request::Info {
+      vendor_id,
+      product_id,
+    } => {
+      let mut dev = device::MSIDevice::open(vendor_id, product_id)?;
+      Ok(serde_json::to_value(cli::read_info(&mut dev))?)
+    },
+    daemon::Request::Query {
+      vendor_id,
+      product_id,
+      code,
+      timeout_ms,
+    } => {
+      let mut dev = device::MSIDevice::open(vendor_id, product_id)?;
+      let reply = dev.query_raw(code, Duration::from_millis(timeout_ms))?;
+      Ok(reply.to_vec().into())
+    },
+    daemon::Request::SendRaw {
+      vendor_id,
+      product_id,
+      packet,
+    } => {
+      let mut dev = device::MSIDevice::open(vendor_id, product_id)?;
+      let reply = dev.send_raw(device::make_packet(&packet))?;
+      Ok(serde_json::to_value(reply.map(|reply| reply.to_vec()))?)
+    },
+    daemon::Request::ListDevices => {
+      let devices = device::list_devices(device::MSI_VENDOR_ID)?;
+      Ok(serde_json::to_value(devices)?)
+    },
+    daemon::Request::ListHotkeys => Ok(runtime.hotkey_names().into()),
+    daemon::Request::ListActions => Ok(runtime.action_names().into()),
+    daemon::Request::RunAction {
+      name,
+      arg,
+    } => {
+      runtime.run_action(&name, arg, reply.clone())?;
+      return Ok(None);
+    },
+    daemon::Request::Status => {
+      Ok(serde_json::json!({
+        "script": ctx.cmd,
+        "key": instance::key(&ctx.cmd),
+      }))
+    },
+    daemon::Request::Reload => {
+      reload(ctx, runtime)?;
+      Ok(serde_json::Value::Null)
+    },
+    daemon::Request::Eval {
+      code,
+    } => {
+      Ok(match runtime.eval(&code)? {
+        Some(output) => serde_json::json!({ "output": output }),
+        None => serde_json::json!({ "incomplete": true }),
+      })
+    },
+  }?;
+  Ok(Some(value))
+}
+
+// Prepends `dirs` to package.path.
+fn add_package_path(lua: &Lua, dirs: &[PathBuf]) -> Result<(), mlua::Error> {
+  let package: mlua::Table = lua.globals().get("package")?;
+  let mut path = String::new();
+  for dir in dirs {
+    let dir = dir.to_string_lossy();
+    path.push_str(&format!("{dir}/?.lua;{dir}/?/init.lua;", dir = dir));
+  }
+  path.push_str(&package.get::<String>("path")?);
+  package.set("path", path)
+}
+
+pub(crate) fn run_main_loop(event_loop: EventLoop<()>, ctx: Context, mut runtime: Runtime) -> ! {
+  event!(Level::INFO, "starting main loop");
+  let global_hotkey_channel = GlobalHotKeyEvent::receiver();
+  let (requests_tx, requests) = crossbeam_channel::unbounded();
+  daemon::serve(requests_tx.clone());
+  if let Some(opts) = &ctx.http {
+    http::serve(opts.clone(), requests_tx.clone());
+  }
+  // Services that want to hear about hotplug events.
+  let mut listeners = Vec::new();
+  let setting_changes = device::watch_changes();
+  if let Some(opts) = &ctx.mqtt {
+    listeners.push(mqtt::serve(opts.clone(), requests_tx.clone()));
+  }
+  #[cfg(target_os = "linux")]
+  if let Some(monitor) = ctx.dbus
+    && let Some(events) = dbus::serve(requests_tx.clone(), monitor)
+  {
+    listeners.push(events);
+  }
+  let mut watcher = ctx.watch.then(|| watch::Watcher::new(runtime.files(&ctx)));
+  let mut held_keys: HashMap<u32, HeldKey> = HashMap::new();
+  let mut device_state: Option<device_query::DeviceState> = None;
+  let mut last_keys_check = Instant::now();
+  let mut last_screen_edge: Option<&'static str> = None;
+  let mut last_edge_check = std::time::Instant::now();
+  let mut cached_displays: Vec<DisplayInfo> = Vec::new();
+  let mut last_displays_refresh: Option<std::time::Instant> = None;
+  event_loop.run(move |_, _, control_flow| {
+    *control_flow = ControlFlow::Poll;
+    // *control_flow = ControlFlow::WaitUntil(Instant::now() + Duration::from_millis(100));
+
+    if runtime.screen_edge.lock().unwrap().is_some()
+      && last_edge_check.elapsed() >= Duration::from_millis(50)
+    {
+      last_edge_check = std::time::Instant::now();
+
+      let needs_refresh =
+        last_displays_refresh.map_or(true, |t| t.elapsed() >= Duration::from_secs(5));
+      if needs_refresh {
+        if let Ok(d) = DisplayInfo::all() {
+          cached_displays = d;
+        }
+        last_displays_refresh = Some(std::time::Instant::now());
+      }
+
+      let edge = match Mouse::get_mouse_position() {
+        Mouse::Position {
+          x,
+          y,
+        } => find_screen_edge(&cached_displays, x, y),
+        Mouse::Error => None,
+      };
+      if edge != last_screen_edge {
+        if let Some(e) = edge {
+          let cb = runtime.screen_edge.lock().unwrap().clone();
+          if let Some(cb) = cb {
+            runtime.tasks.spawn("screen_edge", cb, e);
+          }
+          runtime.tasks.emit("screen_edge", e);
+        }
+        last_screen_edge = edge;
+      }
+    }
+
+    runtime.tasks.poll();
+
+    // Collect what is due first, a callback may register or unregister
+    // intervals.
+    let mut due = Vec::new();
+    if let Ok(mut ic) = runtime.interval_callbacks.lock() {
+      ic.retain(|_k, v| {
+        if std::time::Instant::now() < v.2 {
+          return true;
+        }
+        let Some((lo, hi)) = v.1 else {
+          due.push(("timeout", v.0.clone()));
+          return false;
+        };
+        due.push(("interval", v.0.clone()));
+        let interval = rand::random_range(lo..=hi);
+        v.2 = std::time::Instant::now() + std::time::Duration::from_millis(interval);
+        true
+      });
+    }
+    for (what, callback) in due {
+      runtime.tasks.spawn(what, callback, ());
+    }
+
+    if let Ok(hk_event) = global_hotkey_channel.try_recv() {
+      let found = runtime
+        .hotkeys
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(hk, _)| hk.id() == hk_event.id())
+        .map(|(hk, binding)| (hk.to_string(), binding.clone()));
+      if found.is_none() && hk_event.state == HotKeyState::Pressed {
+        runtime
+          .sequences
+          .lock()
+          .unwrap()
+          .leader_pressed(hk_event.id());
+      }
+      if let Some((name, binding)) = found {
+        let now = Instant::now();
+        match hk_event.state {
+          // Some platforms send presses again while the keys stay down.
+          HotKeyState::Pressed if held_keys.contains_key(&hk_event.id()) => {},
+          HotKeyState::Pressed => {
+            let mut key = HeldKey {
+              since: now,
+              held: false,
+              next_repeat: None,
+            };
+            if binding.on == HotkeyOn::Press {
+              runtime.fire_hotkey(&name, &binding, "pressed");
+              key.next_repeat = binding.repeat.map(|repeat| now + repeat);
+            }
+            held_keys.insert(hk_event.id(), key);
+          },
+          HotKeyState::Released => {
+            let key = held_keys.remove(&hk_event.id());
+            match binding.on {
+              HotkeyOn::Release => runtime.fire_hotkey(&name, &binding, "released"),
+              HotkeyOn::Hold if key.is_some_and(|key| !key.held) => {
+                runtime.fire_hotkey(&name, &binding, "tap")
+              },
+              _ => {},
+            }
+          },
+        }
+      }
+    }
+
+    // The keyboard is only polled while there are sequences to follow.
+    let mut completed = Vec::new();
+    if last_keys_check.elapsed() >= Duration::from_millis(10) {
+      last_keys_check = Instant::now();
+      let mut sequences = runtime.sequences.lock().unwrap();
+      if !sequences.is_empty() {
+        let keys = device_state
+          .get_or_insert_with(device_query::DeviceState::new)
+          .get_keys()
+          .into_iter()
+          .collect();
+        completed = sequences.poll(keys);
+      }
+    }
+    for (keys, callback) in completed {
+      runtime.tasks.spawn("key sequence", callback, keys);
+    }
+
+    // Holds and repeats, for the keys that are down.
+    if !held_keys.is_empty() {
+      let now = Instant::now();
+      let bindings = runtime
+        .hotkeys
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(hk, binding)| (hk.id(), (hk.to_string(), binding.clone())))
+        .collect::<HashMap<_, _>>();
+      // Forget keys that were unregistered while down.
+      held_keys.retain(|id, _| bindings.contains_key(id));
+      for (id, key) in held_keys.iter_mut() {
+        let (name, binding) = &bindings[id];
+        if binding.on == HotkeyOn::Hold && !key.held && now >= key.since + binding.hold {
+          key.held = true;
+          key.next_repeat = binding.repeat.map(|repeat| now + repeat);
+          runtime.fire_hotkey(name, binding, "held");
+        } else if let Some(next) = key.next_repeat
+          && now >= next
+          && let Some(repeat) = binding.repeat
+        {
+          key.next_repeat = Some(next + repeat);
+          runtime.fire_hotkey(name, binding, "repeat");
+        }
+      }
+    }
+
+    if let Ok(hotplug_event) = ctx.hotplug_rx.try_recv() {
+      if let HotplugEvent::Connected(_) = hotplug_event {
+        // When we connect again, make sure all our modifier keys are not pressed down.
+        let device_state = device_query::DeviceState::new();
+        let keys = device_state.get_keys();
+        if keys.contains(&device_query::Keycode::LAlt) {
+          rdev::simulate(&rdev::EventType::KeyRelease(rdev::Key::Alt)).unwrap();
+        }
+        if keys.contains(&device_query::Keycode::RAlt) {
+          rdev::simulate(&rdev::EventType::KeyRelease(rdev::Key::AltGr)).unwrap();
+        }
+        if keys.contains(&device_query::Keycode::LControl) {
+          rdev::simulate(&rdev::EventType::KeyRelease(rdev::Key::ControlLeft)).unwrap();
+        }
+        if keys.contains(&device_query::Keycode::RControl) {
+          rdev::simulate(&rdev::EventType::KeyRelease(rdev::Key::ControlRight)).unwrap();
+        }
+        if keys.contains(&device_query::Keycode::LShift) {
+          rdev::simulate(&rdev::EventType::KeyRelease(rdev::Key::ShiftLeft)).unwrap();
+        }
+        if keys.contains(&device_query::Keycode::RShift) {
+          rdev::simulate(&rdev::EventType::KeyRelease(rdev::Key::ShiftRight)).unwrap();
+        }
+        if keys.contains(&device_query::Keycode::LMeta) {
+          rdev::simulate(&rdev::EventType::KeyRelease(rdev::Key::MetaLeft)).unwrap();
+        }
+        if keys.contains(&device_query::Keycode::RMeta) {
+          rdev::simulate(&rdev::EventType::KeyRelease(rdev::Key::MetaRight)).unwrap();
+        }
+      }
+
+      let change = match hotplug_event {
+        HotplugEvent::Connected(d) => {
+          let change = HotplugChange {
+            connected: true,
+            vendor_id: d.vendor_id(),
+            product_id: d.product_id(),
+            serial: d.serial_number().map(String::from),
+          };
+          ctx.devices.lock().unwrap().insert(d.id(), d);
+          Some(change)
+        },
+        HotplugEvent::Disconnected(id) => {
+          ctx.devices.lock().unwrap().remove(&id).map(|d| {
+            HotplugChange {
+              connected: false,
+              vendor_id: d.vendor_id(),
+              product_id: d.product_id(),
+              serial: d.serial_number().map(String::from),
+            }
+          })
+        },
+      };
+
+      if let Some(change) = change {
+        let name = if change.connected {
+          "connected"
+        } else {
+          "disconnected"
+        };
+        let callbacks = runtime
+          .hotplug
+          .lock()
+          .unwrap()
+          .values()
+          .filter(|handler| handler.filter.matches(&change))
+          .map(|handler| handler.callback.clone())
+          .collect::<Vec<_>>();
+        for cb in callbacks {
+          runtime.tasks.spawn(
+            "hotplug",
+            cb,
+            (
+              name,
+              change.vendor_id,
+              change.product_id,
+              change.serial.clone(),
+            ),
+          );
+        }
+        let HotplugChange {
+          connected,
+          vendor_id,
+          product_id,
+          ..
+        } = change;
+        runtime.tasks.hotplug(connected, vendor_id, product_id);
+        for listener in &listeners {
+          let _ = listener.send(daemon::Event::Hotplug {
+            connected,
+            vendor_id,
+            product_id,
+          });
+        }
+      }
+    }
+
+    for change in setting_changes.try_iter() {
+      for listener in &listeners {
+        let _ = listener.send(daemon::Event::SettingChanged(change.clone()));
+      }
+    }
+
+    if let Some(watcher) = &mut watcher
+      && watcher.poll()
+    {
+      // A failed reload keeps the old callbacks, so all we do is tell.
+      if let Err(err) = reload(&ctx, &mut runtime) {
+        event!(Level::ERROR, "could not reload {}: {}", ctx.cmd, err);
+      }
+      watcher.set_files(runtime.files(&ctx));
+    }
+
+    if let Ok(call) = requests.try_recv() {
+      let reloads = matches!(call.request, daemon::Request::Reload);
+      let response = match handle_request(&ctx, &mut runtime, call.request, &call.reply) {
+        Ok(Some(value)) => Some(daemon::Response::Ok(value)),
+        Ok(None) => None,
+        Err(err) => {
+          event!(Level::ERROR, "daemon request: {}", err);
+          Some(daemon::Response::error(&*err))
+        },
+      };
+      if let Some(response) = response {
+        let _ = call.reply.send(response);
+      }
+      if reloads && let Some(watcher) = &mut watcher {
+        watcher.set_files(runtime.files(&ctx));
+      }
+    }
+  })
+}
@@ -0,0 +1,321 @@
+use std::collections::HashSet;
+
+use mlua::Function;
+use mlua::Lua;
+use mlua::Table;
+use mlua::Value;
+use tracing::Level;
+use tracing::event;
+
+use super::errors::StdError;
+
+// What a sandboxed script is allowed to do. Scripts opt in by declaring the
+// permissions they need, with a `-- sandbox: device, filesystem` line at the
+// top, or `sandbox = ["device"]` in a config. Users can force it with
+// `--sandbox`, see `permissions`. Everything else in the list below is
+// replaced with a function that raises an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Permission {
+  // Talking to monitors over USB or DDC/CI.
+  Device,
+  // Moving the mouse and pressing keys.
+  InputInjection,
+  // Running programs, including at login.
+  ProcessSpawn,
+  // Reading and writing files, including loading modules with `require`.
+  Filesystem,
+  // Taking calls from other machines, through actions served over HTTP.
+  Network,
+}
+
+const PERMISSIONS: &[(Permission, &str)] = &[
+  (Permission::Device, "device"),
+  (Permission::InputInjection, "input-injection"),
+  (Permission::ProcessSpawn, "process-spawn"),
+  (Permission::Filesystem, "filesystem"),
+  (Permission::Network, "network"),
+];
+
+impl Permission {
+  fn name(self) -> &'static str {
+    PERMISSIONS
+      .iter()
+      .find(|(p, _)| *p == self)
+      .map(|(_, name)| *name)
+      .unwrap()
+  }
+
+  fn from_name(name: &str) -> Result<Self, String> {
+    PERMISSIONS
+      .iter()
+      .find(|(_, n)| *n == name)
+      .map(|(p, _)| *p)
+      .ok_or_else(|| {
+        let names = PERMISSIONS.iter().map(|(_, n)| *n).collect::<Vec<_>>();
+        format!(
+          "unknown permission '{}', expected one of: {}",
+          name,
+          names.join(", ")
+        )
+      })
+  }
+}
+
+// The globals that need a permission, by their path from _G. Tables have
+// each of their functions replaced, strings are emptied.
+const GUARDED: &[(&str, Permission)] = &[
+  ("device_open", Permission::Device),
+  ("device_is_connected", Permission::Device),
+  ("switch_input", Permission::Device),
+  ("ddc.list", Permission::Device),
+  ("move_mouse", Permission::InputInjection),
+  ("autorun", Permission::ProcessSpawn),
+  ("os.execute", Permission::ProcessSpawn),
+  ("os.exit", Permission::ProcessSpawn),
+  ("io.popen", Permission::ProcessSpawn),
+  ("os.remove", Permission::Filesystem),
+  ("os.rename", Permission::Filesystem),
+  ("os.tmpname", Permission::Filesystem),
+  ("io.open", Permission::Filesystem),
+  ("io.lines", Permission::Filesystem),
+  ("io.input", Permission::Filesystem),
+  ("io.output", Permission::Filesystem),
+  ("io.read", Permission::Filesystem),
+  ("io.write", Permission::Filesystem),
+  ("io.close", Permission::Filesystem),
+  ("io.tmpfile", Permission::Filesystem),
+  ("loadfile", Permission::Filesystem),
+  ("dofile", Permission::Filesystem),
+  ("require", Permission::Filesystem),
+  ("package.searchpath", Permission::Filesystem),
+  ("package.searchers", Permission::Filesystem),
+  ("package.path", Permission::Filesystem),
+  ("package.cpath", Permission::Filesystem),
+  ("package.loadlib", Permission::Filesystem),
+  ("register_action", Permission::Network),
+];
+
+// Kept in the Lua app data of sandboxed states.
+struct Sandbox {
+  permissions: HashSet<Permission>,
+}
+
+// Parses permission names, as written in a header or config.
+pub(crate) fn parse(names: &[String]) -> Result<HashSet<Permission>, Box<StdError>> {
+  Ok(
+    names
+      .iter()
+      .map(|name| Permission::from_name(name.trim()))
+      .collect::<Result<_, _>>()?,
+  )
+}
+
+// Parses a comma separated list of permission names, as given to --sandbox.
+pub(crate) fn parse_list(list: &str) -> Result<HashSet<Permission>, Box<StdError>> {
+  let names = list
+    .split(',')
+    .map(str::trim)
+    .filter(|name| !name.is_empty())
+    .map(String::from)
+    .collect::<Vec<_>>();
+  parse(&names)
+}
+
+// Looks for `-- sandbox: PERMISSION, ...` in the comments at the top of a
+// script. An empty list sandboxes the script with no permissions.
+pub(crate) fn header(source: &str) -> Result<Option<HashSet<Permission>>, Box<StdError>> {
+  for line in source.lines() {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with("#!") {
+      continue;
+    }
+    let Some(comment) = line.strip_prefix("--") else {
+      break;
+    };
+    if let Some(names) = comment.trim().strip_prefix("sandbox:") {
+      return Ok(Some(parse_list(names)?));
+    }
+  }
+  Ok(None)
+}
+
+// What a script gets to do, or None to not sandbox it. Without a grant from
+// the user, scripts only end up in the sandbox by declaring permissions. With
+// one, they always do, and what they declare can only narrow the grant. A
+// script that declares nothing gets nothing.
+pub(crate) fn permissions(
+  declared: Option<HashSet<Permission>>,
+  grant: Option<&HashSet<Permission>>,
+) -> Option<HashSet<Permission>> {
+  let Some(grant) = grant else {
+    return declared;
+  };
+  let declared = declared.unwrap_or_default();
+  for permission in declared.difference(grant) {
+    event!(
+      Level::WARN,
+      "the script asks for the '{}' permission, which --sandbox doesn't grant",
+      permission.name()
+    );
+  }
+  Some(declared.intersection(grant).copied().collect())
+}
+
+// Takes away everything `permissions` doesn't allow. Call before running the
+// script.
+pub(crate) fn apply(lua: &Lua, permissions: HashSet<Permission>) -> Result<(), Box<StdError>> {
+  for (path, permission) in GUARDED {
+    if !permissions.contains(permission) {
+      deny(lua, path, *permission)?;
+    }
+  }
+
+  // The debug library would get around everything above, but Lua::new
+  // already leaves it out. Precompiled chunks can crash the interpreter.
+  lua
+    .load(
+      r#"
+      local load = load
+      _G.load = function(chunk, name, _, env)
+        if env == nil then
+          return load(chunk, name, "t")
+        end
+        return load(chunk, name, "t", env)
+      end
+      "#,
+    )
+    .set_name("=sandbox")
+    .exec()?;
+
+  lua.set_app_data(Sandbox {
+    permissions,
+  });
+  Ok(())
+}
+
+// For things the script triggers without calling a guarded global, like
+// the actions of a config.
+pub(crate) fn check(lua: &Lua, what: &str, permission: Permission) -> Result<(), mlua::Error> {
+  match lua.app_data_ref::<Sandbox>() {
+    Some(sandbox) if !sandbox.permissions.contains(&permission) => {
+      Err(mlua::Error::external(denied(what, permission)))
+    },
+    _ => Ok(()),
+  }
+}
+
+fn denied(what: &str, permission: Permission) -> String {
+  format!(
+    "{} is not allowed in the sandbox, it needs the '{}' permission",
+    what,
+    permission.name()
+  )
+}
+
+// Replaces the value at `path` with one that can't be used without the
+// permission.
+fn deny(lua: &Lua, path: &str, permission: Permission) -> Result<(), mlua::Error> {
+  let (table, name) = match path.rsplit_once('.') {
+    Some((parent, name)) => (lua.globals().get::<Table>(parent)?, name),
+    None => (lua.globals(), path),
+  };
+  match table.get::<Value>(name)? {
+    // A new table, the old one may still be reachable from elsewhere.
+    Value::Table(old) => {
+      let new = lua.create_table()?;
+      for pair in old.pairs::<Value, Value>() {
+        let (key, value) = pair?;
+        let value = match value {
+          Value::Function(_) => Value::Function(stub(lua, path, permission)?),
+          value => value,
+        };
+        new.raw_set(key, value)?;
+      }
+      table.set(name, new)
+    },
+    Value::String(_) => table.set(name, ""),
+    _ => table.set(name, stub(lua, path, permission)?),
+  }
+}
+
+// A function that raises an error.
+fn stub(lua: &Lua, path: &str, permission: Permission) -> Result<Function, mlua::Error> {
+  let message = denied(path, permission);
+  lua.create_function(move |_, _: mlua::MultiValue| -> Result<(), mlua::Error> {
+    Err(mlua::Error::external(message.clone()))
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // With stand-ins for the globals runtime.rs would set.
+  fn sandboxed(permissions: &[Permission]) -> Lua {
+    let lua = Lua::new();
+    lua
+      .load("ddc = {}\nregister_action = function() end")
+      .exec()
+      .unwrap();
+    apply(&lua, permissions.iter().copied().collect()).unwrap();
+    lua
+  }
+
+  #[test]
+  fn parses_the_network_permission() {
+    let permissions = header("-- sandbox: device, network\nprint('hi')\n").unwrap();
+    assert_eq!(
+      permissions,
+      Some(HashSet::from([Permission::Device, Permission::Network]))
+    );
+  }
+
+  #[test]
+  fn guards_actions_with_network() {
+    let lua = sandboxed(&[]);
+    let err = lua
+      .load("register_action('work', print)")
+      .exec()
+      .unwrap_err();
+    assert!(err.to_string().contains("'network' permission"), "{}", err);
+
+    let lua = sandboxed(&[Permission::Network]);
+    lua.load("register_action('work', print)").exec().unwrap();
+  }
+
+  #[test]
+  fn keeps_package_from_loading_files() {
+    let dir = std::env::temp_dir().join(format!("msi-monitor-ctrl-sandbox-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("module.lua"), "return 42").unwrap();
+    let load = format!(
+      "package.path = {:?}\nlocal loader = package.searchers[2]('module')\nreturn loader()",
+      dir.join("?.lua").to_string_lossy()
+    );
+
+    let lua = sandboxed(&[]);
+    assert_eq!(
+      lua.load("return package.path").eval::<String>().unwrap(),
+      ""
+    );
+    let err = lua.load(&load).eval::<i64>().unwrap_err();
+    assert!(
+      err.to_string().contains("'filesystem' permission"),
+      "{}",
+      err
+    );
+    let err = lua
+      .load("package.loadlib('lib.so', '*')")
+      .exec()
+      .unwrap_err();
+    assert!(
+      err.to_string().contains("'filesystem' permission"),
+      "{}",
+      err
+    );
+
+    let lua = sandboxed(&[Permission::Filesystem]);
+    assert_eq!(lua.load(&load).eval::<i64>().unwrap(), 42);
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+}
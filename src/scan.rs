@@ -0,0 +1,103 @@
+use std::fmt::Write as _;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use tracing::Level;
+use tracing::event;
+
+use super::cli;
+use super::cli::DeviceArgs;
+use super::cli::parse_u16;
+use super::device;
+use super::errors::StdError;
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct ScanArgs {
+  #[command(flatten)]
+  device: DeviceArgs,
+  /// First command code to query.
+  #[arg(long, default_value = "0x000", value_parser = parse_u16)]
+  from: u16,
+  /// Last command code to query.
+  #[arg(long, default_value = "0xfff", value_parser = parse_u16)]
+  to: u16,
+  /// How long to wait for a reply to each request.
+  #[arg(long, default_value_t = 250)]
+  timeout_ms: u64,
+  /// How long to wait between requests.
+  #[arg(long, default_value_t = 20)]
+  delay_ms: u64,
+  /// File to write the report to.
+  #[arg(short, long, default_value = "scan-report.txt")]
+  output: PathBuf,
+}
+
+// Queries every command code in the range with read requests only and writes
+// the codes that answered with a well-formed reply to a report file.
+pub(crate) fn run(args: ScanArgs) -> Result<(), Box<StdError>> {
+  if args.from > args.to {
+    return Err("--from must be <= --to".into());
+  }
+  if args.to > 0xfff {
+    return Err("command codes only go up to 0xfff".into());
+  }
+
+  let mut monitor = cli::Monitor::open(&args.device)?;
+  let timeout = Duration::from_millis(args.timeout_ms);
+
+  let mut report = String::new();
+  writeln!(
+    report,
+    "# {} {} scan",
+    env!("CARGO_PKG_NAME"),
+    env!("CARGO_PKG_VERSION")
+  )?;
+  writeln!(
+    report,
+    "# device: {:04x}:{:04x}",
+    args.device.vendor_id, args.device.product_id
+  )?;
+  writeln!(report, "# range: {:03x}-{:03x}", args.from, args.to)?;
+  writeln!(report, "# code\tvalue\treply")?;
+
+  let mut found = 0;
+  for code in args.from..=args.to {
+    match monitor.query_raw(code, timeout) {
+      Ok(buf) => {
+        // Some codes answer with something other than digits. They are still
+        // supported, so report the bytes of the value as they are.
+        let value = match device::reply_value(&buf) {
+          Ok(value) => value.to_string(),
+          Err(_) => format!("raw {}", hex(&buf[8..11])),
+        };
+        event!(Level::INFO, "code {:03x} = {}", code, value);
+        writeln!(report, "{:03x}\t{}\t{}", code, value, hex(&buf[..12]))?;
+        found += 1;
+      },
+      Err(err) => {
+        event!(Level::DEBUG, "code {:03x}: {}", code, err);
+      },
+    }
+
+    thread::sleep(Duration::from_millis(args.delay_ms));
+  }
+
+  std::fs::write(&args.output, report)?;
+  event!(
+    Level::INFO,
+    "{} codes answered, report written to {}",
+    found,
+    args.output.display()
+  );
+
+  Ok(())
+}
+
+fn hex(bytes: &[u8]) -> String {
+  bytes
+    .iter()
+    .map(|b| format!("{:02x}", b))
+    .collect::<Vec<_>>()
+    .join(" ")
+}
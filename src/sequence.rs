@@ -0,0 +1,439 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::time::Duration;
+use std::time::Instant;
+
+use device_query::Keycode;
+use global_hotkey::hotkey::HotKey;
+use mlua::Function;
+
+// Key sequences like "ctrl+alt+m, 1" or "rctrl, rctrl". A first step that
+// works as a global hotkey is registered as one, so nothing else sees it,
+// and the rest is followed by polling the keyboard from the main loop. A
+// first step made of modifiers alone, like a double tap, is polled too.
+// Polling only watches, so the keys after the leader still reach the
+// focused application.
+pub(crate) struct Sequence {
+  text: String,
+  steps: Vec<Step>,
+  leader: Option<HotKey>,
+  callback: Function,
+  timeout: Duration,
+}
+
+// Keys that are down together. Each key lists the keycodes that count, so
+// "ctrl" matches either control key.
+struct Step(Vec<Vec<Keycode>>);
+
+impl Step {
+  fn is_down(&self, keys: &HashSet<Keycode>) -> bool {
+    self
+      .0
+      .iter()
+      .all(|alternatives| alternatives.iter().any(|k| keys.contains(k)))
+  }
+
+  fn contains(&self, key: &Keycode) -> bool {
+    self.0.iter().any(|alternatives| alternatives.contains(key))
+  }
+}
+
+fn is_modifier(key: &Keycode) -> bool {
+  use Keycode::*;
+  matches!(
+    key,
+    LControl | RControl | LAlt | RAlt | LShift | RShift | LMeta | RMeta
+  )
+}
+
+fn parse_key(name: &str) -> Result<Vec<Keycode>, String> {
+  use Keycode::*;
+  let name = name.trim().to_lowercase();
+  let keys = match name.as_str() {
+    "ctrl" | "control" => vec![LControl, RControl],
+    "lctrl" => vec![LControl],
+    "rctrl" => vec![RControl],
+    "alt" | "option" => vec![LAlt, RAlt],
+    "lalt" => vec![LAlt],
+    "ralt" => vec![RAlt],
+    "shift" => vec![LShift, RShift],
+    "lshift" => vec![LShift],
+    "rshift" => vec![RShift],
+    "super" | "meta" | "cmd" | "command" | "win" => vec![LMeta, RMeta],
+    "lsuper" | "lmeta" | "lcmd" => vec![LMeta],
+    "rsuper" | "rmeta" | "rcmd" => vec![RMeta],
+    "space" => vec![Space],
+    "enter" | "return" => vec![Enter],
+    "tab" => vec![Tab],
+    "esc" | "escape" => vec![Escape],
+    "backspace" => vec![Backspace],
+    "up" | "arrowup" => vec![Up],
+    "down" | "arrowdown" => vec![Down],
+    "left" | "arrowleft" => vec![Left],
+    "right" | "arrowright" => vec![Right],
+    "home" => vec![Home],
+    "end" => vec![End],
+    "pageup" => vec![PageUp],
+    "pagedown" => vec![PageDown],
+    "insert" => vec![Insert],
+    "delete" => vec![Delete],
+    "capslock" => vec![CapsLock],
+    "backquote" | "`" => vec![Grave],
+    "minus" | "-" => vec![Minus],
+    "equal" | "=" => vec![Equal],
+    "bracketleft" | "[" => vec![LeftBracket],
+    "bracketright" | "]" => vec![RightBracket],
+    "backslash" | "\\" => vec![BackSlash],
+    "semicolon" | ";" => vec![Semicolon],
+    "quote" | "'" => vec![Apostrophe],
+    "period" | "." => vec![Dot],
+    "slash" | "/" => vec![Slash],
+    _ => {
+      // Letters, digits, f1 to f12 and numpad digits, also by the names
+      // register_hotkey takes, like "KeyA", "Digit1" and "Numpad1".
+      let bare = ["key", "digit"]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .filter(|rest| rest.len() == 1)
+        .unwrap_or(&name);
+      let is_digit = |s: &str| s.len() == 1 && s.as_bytes()[0].is_ascii_digit();
+      let keycode = match name.strip_prefix("numpad") {
+        Some(digit) if is_digit(digit) => format!("Numpad{}", digit),
+        _ if is_digit(bare) => format!("Key{}", bare),
+        _ => bare.to_uppercase(),
+      };
+      vec![Keycode::from_str(&keycode).map_err(|_| format!("unknown key '{}'", name))?]
+    },
+  };
+  Ok(keys)
+}
+
+impl Sequence {
+  pub(crate) fn parse(text: &str, callback: Function, timeout: Duration) -> Result<Self, String> {
+    let steps = text
+      .split(',')
+      .map(|step| {
+        let keys = step
+          .split('+')
+          .map(parse_key)
+          .collect::<Result<Vec<_>, _>>()?;
+        Ok(Step(keys))
+      })
+      .collect::<Result<Vec<_>, String>>()?;
+    if steps.len() < 2 {
+      return Err(format!(
+        "'{}' is a single chord, use register_hotkey for it",
+        text
+      ));
+    }
+
+    // Global hotkeys need a key besides the modifiers.
+    let first = text.split(',').next().unwrap_or_default();
+    let leader = if steps[0].0.iter().flatten().all(is_modifier) {
+      None
+    } else {
+      Some(HotKey::from_str(first.trim()).map_err(|e| e.to_string())?)
+    };
+
+    Ok(Self {
+      text: text.to_string(),
+      steps,
+      leader,
+      callback,
+      timeout,
+    })
+  }
+
+  pub(crate) fn leader(&self) -> Option<HotKey> {
+    self.leader
+  }
+}
+
+// The registered sequences and how far along each one is.
+#[derive(Default)]
+pub(crate) struct Sequences {
+  // With the ids push handed out.
+  list: Vec<(usize, Sequence)>,
+  // The next step and when the sequence gives up, by id.
+  progress: HashMap<usize, (usize, Instant)>,
+  last_keys: HashSet<Keycode>,
+  last_id: usize,
+}
+
+impl Sequences {
+  // Returns an id to remove the sequence with.
+  pub(crate) fn push(&mut self, sequence: Sequence) -> usize {
+    self.last_id += 1;
+    self.list.push((self.last_id, sequence));
+    self.last_id
+  }
+
+  // Returns the sequence, or None if it was already removed.
+  pub(crate) fn remove(&mut self, id: usize) -> Option<Sequence> {
+    let i = self.list.iter().position(|(i, _)| *i == id)?;
+    self.progress.remove(&id);
+    Some(self.list.remove(i).1)
+  }
+
+  pub(crate) fn is_empty(&self) -> bool {
+    self.list.is_empty()
+  }
+
+  // The global hotkeys that start sequences, without duplicates.
+  pub(crate) fn leaders(&self) -> Vec<HotKey> {
+    let mut leaders = Vec::new();
+    for leader in self
+      .list
+      .iter()
+      .filter_map(|(_, sequence)| sequence.leader())
+    {
+      if !leaders.contains(&leader) {
+        leaders.push(leader);
+      }
+    }
+    leaders
+  }
+
+  // Starts the sequences led by this hotkey.
+  pub(crate) fn leader_pressed(&mut self, id: u32) {
+    let now = Instant::now();
+    for (i, sequence) in &self.list {
+      if sequence.leader.is_some_and(|leader| leader.id() == id) {
+        self.progress.insert(*i, (1, now + sequence.timeout));
+      }
+    }
+  }
+
+  // Looks at which of `keys`, the keys down right now, went down since the
+  // last call. Returns the sequences that completed, with their callbacks.
+  pub(crate) fn poll(&mut self, keys: HashSet<Keycode>) -> Vec<(String, Function)> {
+    let pressed = keys
+      .difference(&self.last_keys)
+      .cloned()
+      .collect::<Vec<_>>();
+    let now = Instant::now();
+    let mut done = Vec::new();
+
+    for &(i, ref sequence) in &self.list {
+      let triggered = |step: &Step| step.is_down(&keys) && !step.is_down(&self.last_keys);
+
+      match self.progress.get(&i).copied() {
+        Some((_, deadline)) if now >= deadline => {
+          self.progress.remove(&i);
+        },
+        Some((next, _)) => {
+          let step = &sequence.steps[next];
+          if triggered(step) {
+            if next + 1 == sequence.steps.len() {
+              self.progress.remove(&i);
+              done.push((sequence.text.clone(), sequence.callback.clone()));
+            } else {
+              self.progress.insert(i, (next + 1, now + sequence.timeout));
+            }
+          } else {
+            // Any other key gives up, except modifiers on their way to a
+            // chord and keys of the last step still being let go of.
+            let previous = &sequence.steps[next - 1];
+            let other = pressed
+              .iter()
+              .any(|k| !is_modifier(k) && !step.contains(k) && !previous.contains(k));
+            if other {
+              self.progress.remove(&i);
+            }
+          }
+        },
+        None => {
+          if sequence.leader.is_none() && triggered(&sequence.steps[0]) {
+            self.progress.insert(i, (1, now + sequence.timeout));
+          }
+        },
+      }
+    }
+
+    self.last_keys = keys;
+    done
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use mlua::Lua;
+
+  use super::*;
+
+  fn sequence(lua: &Lua, text: &str, timeout: Duration) -> Sequence {
+    let callback = lua.create_function(|_, ()| Ok(())).unwrap();
+    Sequence::parse(text, callback, timeout).unwrap()
+  }
+
+  fn sequences(lua: &Lua, text: &str, timeout: Duration) -> Sequences {
+    let mut sequences = Sequences::default();
+    sequences.push(sequence(lua, text, timeout));
+    sequences
+  }
+
+  // Feeds the keyboard states in order and returns the completed sequences.
+  fn press(sequences: &mut Sequences, states: &[&[Keycode]]) -> Vec<String> {
+    states
+      .iter()
+      .flat_map(|keys| sequences.poll(keys.iter().cloned().collect()))
+      .map(|(text, _)| text)
+      .collect()
+  }
+
+  const TIMEOUT: Duration = Duration::from_secs(60);
+
+  #[test]
+  fn parses_keys() {
+    assert_eq!(
+      parse_key("ctrl").unwrap(),
+      vec![Keycode::LControl, Keycode::RControl]
+    );
+    assert_eq!(parse_key("a").unwrap(), vec![Keycode::A]);
+    assert_eq!(parse_key("KeyA").unwrap(), vec![Keycode::A]);
+    assert_eq!(parse_key("1").unwrap(), vec![Keycode::Key1]);
+    assert_eq!(parse_key("Digit1").unwrap(), vec![Keycode::Key1]);
+    assert_eq!(parse_key("Numpad1").unwrap(), vec![Keycode::Numpad1]);
+    assert_eq!(parse_key("F5").unwrap(), vec![Keycode::F5]);
+    assert_eq!(parse_key("ArrowRight").unwrap(), vec![Keycode::Right]);
+    assert_eq!(parse_key("arrowright").unwrap(), vec![Keycode::Right]);
+    assert_eq!(parse_key("right").unwrap(), vec![Keycode::Right]);
+    assert_eq!(parse_key("Escape").unwrap(), vec![Keycode::Escape]);
+    assert!(parse_key("bogus").is_err());
+  }
+
+  #[test]
+  fn parses_sequences() {
+    let lua = Lua::new();
+    let callback = lua.create_function(|_, ()| Ok(())).unwrap();
+    assert!(Sequence::parse("ctrl+alt+m", callback.clone(), TIMEOUT).is_err());
+    assert!(Sequence::parse("ctrl+bogus, 1", callback, TIMEOUT).is_err());
+
+    let led = sequence(&lua, "ctrl+alt+m, 1", TIMEOUT);
+    assert_eq!(led.leader(), Some(HotKey::from_str("ctrl+alt+m").unwrap()));
+    assert_eq!(led.steps.len(), 2);
+
+    let arrows = sequence(&lua, "ctrl+ArrowRight, arrowright", TIMEOUT);
+    assert_eq!(
+      arrows.leader(),
+      Some(HotKey::from_str("ctrl+ArrowRight").unwrap())
+    );
+
+    let tap = sequence(&lua, "rctrl, rctrl", TIMEOUT);
+    assert_eq!(tap.leader(), None);
+  }
+
+  #[test]
+  fn completes_a_double_tap() {
+    let lua = Lua::new();
+    let mut sequences = sequences(&lua, "rctrl, rctrl", TIMEOUT);
+    let done = press(
+      &mut sequences,
+      &[&[Keycode::RControl], &[], &[Keycode::RControl]],
+    );
+    assert_eq!(done, vec!["rctrl, rctrl"]);
+
+    // Holding the key down is a single tap.
+    let done = press(
+      &mut sequences,
+      &[&[], &[Keycode::RControl], &[Keycode::RControl]],
+    );
+    assert!(done.is_empty());
+  }
+
+  #[test]
+  fn gives_up_after_the_timeout() {
+    let lua = Lua::new();
+    let mut sequences = sequences(&lua, "rctrl, rctrl", Duration::ZERO);
+    let done = press(
+      &mut sequences,
+      &[&[Keycode::RControl], &[], &[Keycode::RControl]],
+    );
+    assert!(done.is_empty());
+  }
+
+  #[test]
+  fn gives_up_on_another_key() {
+    let lua = Lua::new();
+    let mut sequences = sequences(&lua, "rctrl, rctrl", TIMEOUT);
+    let done = press(
+      &mut sequences,
+      &[
+        &[Keycode::RControl],
+        &[],
+        &[Keycode::A],
+        &[],
+        &[Keycode::RControl],
+      ],
+    );
+    assert!(done.is_empty());
+  }
+
+  #[test]
+  fn waits_for_modifiers_on_the_way_to_a_chord() {
+    let lua = Lua::new();
+    let mut sequences = sequences(&lua, "lalt, ctrl+1", TIMEOUT);
+    let done = press(
+      &mut sequences,
+      &[
+        &[Keycode::LAlt],
+        &[],
+        &[Keycode::LControl],
+        &[Keycode::LControl, Keycode::Key1],
+      ],
+    );
+    assert_eq!(done, vec!["lalt, ctrl+1"]);
+  }
+
+  #[test]
+  fn follows_a_leader_hotkey() {
+    let lua = Lua::new();
+    let mut sequences = sequences(&lua, "ctrl+alt+m, shift+1", TIMEOUT);
+    let leader = sequences.leaders()[0];
+
+    // Polling alone never starts it.
+    let done = press(
+      &mut sequences,
+      &[&[Keycode::LControl, Keycode::LAlt, Keycode::M], &[]],
+    );
+    assert!(done.is_empty());
+
+    // The leader's keys being let go of don't count as other keys.
+    sequences.leader_pressed(leader.id());
+    let done = press(
+      &mut sequences,
+      &[
+        &[Keycode::LControl, Keycode::LAlt, Keycode::M],
+        &[],
+        &[Keycode::LShift],
+        &[Keycode::LShift, Keycode::Key1],
+      ],
+    );
+    assert_eq!(done, vec!["ctrl+alt+m, shift+1"]);
+  }
+
+  #[test]
+  fn removes_sequences() {
+    let lua = Lua::new();
+    let mut sequences = Sequences::default();
+    let first = sequences.push(sequence(&lua, "ctrl+alt+m, 1", TIMEOUT));
+    let second = sequences.push(sequence(&lua, "ctrl+alt+m, 2", TIMEOUT));
+    let leader = sequences.leaders()[0];
+
+    // A removed sequence stops, even halfway through.
+    sequences.leader_pressed(leader.id());
+    assert!(sequences.remove(first).is_some());
+    assert!(sequences.remove(first).is_none());
+    assert!(press(&mut sequences, &[&[Keycode::Key1], &[]]).is_empty());
+    sequences.leader_pressed(leader.id());
+    let done = press(&mut sequences, &[&[Keycode::Key2]]);
+    assert_eq!(done, vec!["ctrl+alt+m, 2"]);
+
+    // The leader stays as long as a sequence starts with it.
+    assert_eq!(sequences.leaders(), vec![leader]);
+    sequences.remove(second);
+    assert!(sequences.leaders().is_empty());
+    assert!(sequences.is_empty());
+  }
+}
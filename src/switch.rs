@@ -0,0 +1,132 @@
+use tracing::Level;
+use tracing::event;
+
+use super::ddcci;
+use super::device;
+use super::errors::StdError;
+
+// How the input was switched.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum SwitchPath {
+  Usb,
+  Ddc,
+}
+
+impl SwitchPath {
+  pub(crate) fn as_str(&self) -> &'static str {
+    match self {
+      SwitchPath::Usb => "usb",
+      SwitchPath::Ddc => "ddc",
+    }
+  }
+}
+
+// A host is the input to switch to, and optionally the KVM position that
+// goes with it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Host {
+  pub(crate) input: u8,
+  pub(crate) kvm: Option<u8>,
+}
+
+// Parses a host written as INPUT:KVM, like 3:2, or just INPUT.
+pub(crate) fn parse_host(s: &str) -> Result<Host, String> {
+  let parse = |v: &str| {
+    v.trim()
+      .parse::<u8>()
+      .map_err(|e| format!("invalid host '{}': {}", s, e))
+  };
+  match s.split_once(':') {
+    Some((input, kvm)) => {
+      Ok(Host {
+        input: parse(input)?,
+        kvm: Some(parse(kvm)?),
+      })
+    },
+    None => {
+      Ok(Host {
+        input: parse(s)?,
+        kvm: None,
+      })
+    },
+  }
+}
+
+pub(crate) struct SwitchInput {
+  pub(crate) vendor_id: u16,
+  pub(crate) product_id: u16,
+  // The input position used over USB.
+  pub(crate) input: u8,
+  // The VCP 0x60 value to use over DDC/CI if USB is unavailable. Without it
+  // there is no fallback.
+  pub(crate) ddc_input: Option<u8>,
+  // The EDID serial of the monitor, to find it over DDC/CI.
+  pub(crate) serial: Option<String>,
+}
+
+// Switches the input over USB, falling back to DDC/CI when the monitor can't
+// be reached over USB (no upstream cable, or no permissions).
+pub(crate) fn switch_input(opts: &SwitchInput) -> Result<SwitchPath, Box<StdError>> {
+  let mut dev = match device::MSIDevice::open(opts.vendor_id, opts.product_id) {
+    Ok(dev) => dev,
+    Err(err) => return switch_input_ddc(opts, err),
+  };
+
+  // A write that fails once the monitor is open isn't retried over DDC/CI,
+  // part of it may already have reached the monitor.
+  dev.set_input(opts.input)?;
+  Ok(SwitchPath::Usb)
+}
+
+fn switch_input_ddc(opts: &SwitchInput, err: Box<StdError>) -> Result<SwitchPath, Box<StdError>> {
+  let Some(ddc_input) = opts.ddc_input else {
+    return Err(err);
+  };
+
+  event!(
+    Level::WARN,
+    "could not open the monitor over USB ({}), falling back to DDC/CI",
+    err
+  );
+
+  let mut monitor = ddcci::find_monitor(opts.serial.as_deref())
+    .map_err(|ddc_err| format!("{} (DDC/CI fallback: {})", err, ddc_err))?;
+  monitor.set_input(ddc_input)?;
+  device::setting_changed(device::SettingChange {
+    vendor_id: opts.vendor_id,
+    product_id: opts.product_id,
+    location: None,
+    code: device::CODE_INPUT,
+    value: opts.input as u32,
+  });
+
+  Ok(SwitchPath::Ddc)
+}
+
+// Switches the input and then the KVM. The input has to be set first, if we
+// switch the KVM first we lose USB access to the monitor.
+pub(crate) fn switch_host(
+  vendor_id: u16,
+  product_id: u16,
+  host: Host,
+  ddc_input: Option<u8>,
+  serial: Option<String>,
+) -> Result<SwitchPath, Box<StdError>> {
+  // The KVM can only be switched over USB. Switching just the input over
+  // DDC/CI would leave the monitor half switched, so a host with a KVM
+  // position has no fallback.
+  let path = switch_input(&SwitchInput {
+    vendor_id,
+    product_id,
+    input: host.input,
+    ddc_input: ddc_input.filter(|_| host.kvm.is_none()),
+    serial,
+  })?;
+
+  if let Some(kvm) = host.kvm {
+    let mut dev = device::MSIDevice::open(vendor_id, product_id)?;
+    dev.set_kvm(kvm)?;
+  }
+
+  Ok(path)
+}
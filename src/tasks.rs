@@ -0,0 +1,557 @@
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use crossbeam_channel::Receiver;
+use crossbeam_channel::TryRecvError;
+use mlua::Function;
+use mlua::IntoLuaMulti;
+use mlua::Lua;
+use mlua::MultiValue;
+use mlua::Table;
+use mlua::Thread;
+use mlua::ThreadStatus;
+use mlua::UserDataRef;
+use mlua::Value;
+use tracing::Level;
+use tracing::event;
+
+use super::errors::StdError;
+
+// The name PRELUDE runs under, which shows up in tracebacks.
+pub(crate) const CHUNK_NAME: &str = "=tasks";
+
+// Callbacks run as coroutines, so sleep_ms and the wait_for_* helpers can
+// yield back to the main loop instead of holding it up. The main loop
+// resumes them once what they wait for happens. Outside of a callback, like
+// at the top of a script, sleep_ms still blocks. So do device calls, which
+// run on a worker thread and hand back a Job to wait for.
+const PRELUDE: &str = r#"
+local threads, block_sleep = ...
+
+-- Whether we are in a coroutine the main loop runs, and not one the script
+-- made itself.
+local function in_task()
+  local co, main = coroutine.running()
+  return not main and threads[co] ~= nil
+end
+
+function sleep_ms(ms)
+  if in_task() then
+    coroutine.yield("sleep", ms)
+  else
+    block_sleep(ms)
+  end
+end
+
+function wait_for_hotplug(vendor_id, product_id, timeout_ms)
+  if not in_task() then
+    error("wait_for_hotplug can only be called from a callback", 2)
+  end
+  return coroutine.yield("hotplug", vendor_id, product_id, timeout_ms)
+end
+
+function wait_for_event(name, timeout_ms)
+  if not in_task() then
+    error("wait_for_event can only be called from a callback", 2)
+  end
+  return coroutine.yield("event", name, timeout_ms)
+end
+
+local function checked(ok, ...)
+  if not ok then
+    error(..., 0)
+  end
+  return ...
+end
+
+local function await(job)
+  if in_task() then
+    return checked(coroutine.yield("job", job))
+  end
+  return job:wait()
+end
+
+-- An open device whose methods wait for their jobs.
+local function awaiting(device)
+  local methods = {}
+  return setmetatable({}, {
+    __index = function(_, key)
+      local value = device[key]
+      if type(value) ~= "function" then
+        return value
+      end
+      methods[key] = methods[key] or function(_, ...)
+        return await(value(device, ...))
+      end
+      return methods[key]
+    end,
+  })
+end
+
+local open, list, switch = device_open, ddc.list, switch_input
+
+function device_open(...)
+  return awaiting(await(open(...)))
+end
+
+function ddc.list()
+  local monitors = await(list())
+  for i, monitor in ipairs(monitors) do
+    monitors[i] = awaiting(monitor)
+  end
+  return monitors
+end
+
+function switch_input(opts)
+  return await(switch(opts))
+end
+"#;
+
+// A job's result, turned into Lua values back on the main thread.
+type Finish = Box<dyn FnOnce(&Lua) -> Result<MultiValue, mlua::Error> + Send>;
+
+// A blocking call, like one to a monitor, running on a worker thread.
+#[derive(Clone)]
+pub(crate) struct Job(Receiver<Finish>);
+
+impl Job {
+  pub(crate) fn spawn<T: IntoLuaMulti + Send + 'static>(
+    work: impl FnOnce() -> Result<T, Box<StdError>> + Send + 'static,
+  ) -> Self {
+    Self::spawn_map(work, |_, value| Ok(value))
+  }
+
+  // Like spawn, with `map` turning the result into Lua values once it is
+  // back on the main thread.
+  pub(crate) fn spawn_map<T: Send + 'static, R: IntoLuaMulti>(
+    work: impl FnOnce() -> Result<T, Box<StdError>> + Send + 'static,
+    map: impl FnOnce(&Lua, T) -> Result<R, mlua::Error> + Send + 'static,
+  ) -> Self {
+    let (tx, rx) = crossbeam_channel::bounded(1);
+    thread::spawn(move || {
+      let result = work();
+      let finish: Finish = Box::new(move |lua| {
+        let value = result.map_err(mlua::Error::external)?;
+        map(lua, value)?.into_lua_multi(lua)
+      });
+      let _ = tx.send(finish);
+    });
+    Self(rx)
+  }
+
+  // What the job returned, or None while it still runs.
+  fn try_result(&self, lua: &Lua) -> Option<Result<MultiValue, mlua::Error>> {
+    match self.0.try_recv() {
+      Ok(finish) => Some(finish(lua)),
+      Err(TryRecvError::Empty) => None,
+      Err(TryRecvError::Disconnected) => Some(Err(stopped())),
+    }
+  }
+}
+
+impl mlua::UserData for Job {
+  fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+    // Blocks until the job is done, for calls made outside of a callback.
+    methods.add_method("wait", |lua, this, ()| -> Result<MultiValue, mlua::Error> {
+      let finish = this.0.recv().map_err(|_| stopped())?;
+      finish(lua)
+    });
+  }
+}
+
+fn stopped() -> mlua::Error {
+  mlua::Error::external("the device call stopped without an answer")
+}
+
+// A device the jobs of its calls share. They run one at a time.
+pub(crate) struct Shared<T>(Arc<Mutex<T>>);
+
+impl<T: Send + 'static> Shared<T> {
+  pub(crate) fn new(value: T) -> Self {
+    Self(Arc::new(Mutex::new(value)))
+  }
+
+  // For what is quick to read, like a monitor's description.
+  pub(crate) fn lock(&self) -> MutexGuard<'_, T> {
+    self.0.lock().unwrap()
+  }
+
+  pub(crate) fn job<R: IntoLuaMulti + Send + 'static>(
+    &self,
+    work: impl FnOnce(&mut T) -> Result<R, Box<StdError>> + Send + 'static,
+  ) -> Job {
+    self.job_map(work, |_, value| Ok(value))
+  }
+
+  pub(crate) fn job_map<R: Send + 'static, V: IntoLuaMulti>(
+    &self,
+    work: impl FnOnce(&mut T) -> Result<R, Box<StdError>> + Send + 'static,
+    map: impl FnOnce(&Lua, R) -> Result<V, mlua::Error> + Send + 'static,
+  ) -> Job {
+    let shared = self.0.clone();
+    Job::spawn_map(move || work(&mut shared.lock().unwrap()), map)
+  }
+}
+
+// What a suspended callback is waiting for.
+enum Wait {
+  Sleep(Instant),
+  // A device to connect. Leaving out an id matches any device.
+  Hotplug {
+    vendor_id: Option<u16>,
+    product_id: Option<u16>,
+    deadline: Option<Instant>,
+  },
+  Event {
+    name: String,
+    deadline: Option<Instant>,
+  },
+  Job(Job),
+}
+
+// Gets what a callback returned, or the error it raised, once it finishes.
+type Done = Box<dyn FnOnce(Result<MultiValue, mlua::Error>)>;
+
+struct Task {
+  // What kind of callback this is, for errors.
+  what: String,
+  thread: Thread,
+  wait: Wait,
+  done: Option<Done>,
+}
+
+pub(crate) struct Tasks {
+  lua: Lua,
+  waiting: Vec<Task>,
+  // The coroutines we run, with weak keys so finished ones go away.
+  threads: Table,
+  // Raised with emit_event, delivered on the next tick.
+  emitted: Arc<Mutex<Vec<(String, MultiValue)>>>,
+}
+
+impl Tasks {
+  // Adds sleep_ms, wait_for_hotplug, wait_for_event and emit_event, and
+  // makes device_open, ddc.list and switch_input wait for their jobs. Call
+  // after setting those.
+  pub(crate) fn install(lua: &Lua) -> Result<Self, mlua::Error> {
+    let threads = lua.create_table()?;
+    let weak_keys = lua.create_table()?;
+    weak_keys.set("__mode", "k")?;
+    threads.set_metatable(Some(weak_keys))?;
+
+    let block_sleep = lua.create_function(|_, duration: u64| -> Result<(), mlua::Error> {
+      thread::sleep(Duration::from_millis(duration));
+      Ok(())
+    })?;
+    lua
+      .load(PRELUDE)
+      .set_name(CHUNK_NAME)
+      .call::<()>((&threads, block_sleep))?;
+
+    let emitted: Arc<Mutex<Vec<(String, MultiValue)>>> = Arc::new(Mutex::new(Vec::new()));
+    let emitted_clone = emitted.clone();
+    let emit_event = lua.create_function(
+      move |_, (name, args): (String, MultiValue)| -> Result<(), mlua::Error> {
+        emitted_clone.lock().unwrap().push((name, args));
+        Ok(())
+      },
+    )?;
+    lua.globals().set("emit_event", emit_event)?;
+
+    Ok(Self {
+      lua: lua.clone(),
+      waiting: Vec::new(),
+      threads,
+      emitted,
+    })
+  }
+
+  // Starts a callback. It runs until it finishes or waits for something.
+  pub(crate) fn spawn(&mut self, what: &str, callback: Function, args: impl IntoLuaMulti) {
+    self.start(what, callback, args, None);
+  }
+
+  // Like spawn, and hands `done` the result once the callback finishes,
+  // instead of logging errors.
+  pub(crate) fn spawn_then(
+    &mut self,
+    what: &str,
+    callback: Function,
+    args: impl IntoLuaMulti,
+    done: impl FnOnce(Result<MultiValue, mlua::Error>) + 'static,
+  ) {
+    self.start(what, callback, args, Some(Box::new(done)));
+  }
+
+  fn start(&mut self, what: &str, callback: Function, args: impl IntoLuaMulti, done: Option<Done>) {
+    let thread = match self.lua.create_thread(callback) {
+      Ok(thread) => thread,
+      Err(err) => return finish(what, done, Err(err)),
+    };
+    if let Err(err) = self.threads.set(thread.clone(), true) {
+      return finish(what, done, Err(err));
+    }
+    self.resume(what.to_string(), thread, args, done);
+  }
+
+  fn resume(&mut self, what: String, thread: Thread, args: impl IntoLuaMulti, done: Option<Done>) {
+    let yielded = match thread.resume::<MultiValue>(args) {
+      Ok(yielded) => yielded,
+      Err(err) => return finish(&what, done, Err(err)),
+    };
+    if thread.status() != ThreadStatus::Resumable {
+      return finish(&what, done, Ok(yielded));
+    }
+    match self.wait(yielded) {
+      Ok(wait) => {
+        self.waiting.push(Task {
+          what,
+          thread,
+          wait,
+          done,
+        })
+      },
+      Err(err) => finish(&what, done, Err(err)),
+    }
+  }
+
+  // Works out what a coroutine yielded for. A plain coroutine.yield() waits
+  // for the next tick.
+  fn wait(&self, yielded: MultiValue) -> Result<Wait, mlua::Error> {
+    let deadline = |ms: Option<u64>| ms.map(|ms| Instant::now() + Duration::from_millis(ms));
+    let (kind, args): (Option<String>, MultiValue) = self.lua.unpack_multi(yielded)?;
+    let wait = match kind.as_deref() {
+      Some("sleep") => {
+        let ms: u64 = self.lua.unpack_multi(args)?;
+        Wait::Sleep(Instant::now() + Duration::from_millis(ms))
+      },
+      Some("hotplug") => {
+        let (vendor_id, product_id, timeout_ms) = self.lua.unpack_multi(args)?;
+        Wait::Hotplug {
+          vendor_id,
+          product_id,
+          deadline: deadline(timeout_ms),
+        }
+      },
+      Some("event") => {
+        let (name, timeout_ms) = self.lua.unpack_multi(args)?;
+        Wait::Event {
+          name,
+          deadline: deadline(timeout_ms),
+        }
+      },
+      Some("job") => {
+        let job: UserDataRef<Job> = self.lua.unpack_multi(args)?;
+        Wait::Job(job.clone())
+      },
+      _ => Wait::Sleep(Instant::now()),
+    };
+    Ok(wait)
+  }
+
+  // Resumes the callbacks that are done sleeping, timed out or got what
+  // their job returned, and delivers the events the script emitted. Called
+  // on every tick.
+  pub(crate) fn poll(&mut self) {
+    let emitted = std::mem::take(&mut *self.emitted.lock().unwrap());
+    for (name, args) in emitted {
+      self.event(&name, args);
+    }
+
+    let now = Instant::now();
+    for task in std::mem::take(&mut self.waiting) {
+      match task.wait {
+        Wait::Sleep(until) if now >= until => self.resume(task.what, task.thread, (), task.done),
+        Wait::Hotplug {
+          deadline: Some(deadline),
+          ..
+        } if now >= deadline => self.resume(task.what, task.thread, false, task.done),
+        Wait::Event {
+          deadline: Some(deadline),
+          ..
+        } if now >= deadline => self.resume(task.what, task.thread, (), task.done),
+        // The prelude raises the error in the callback.
+        Wait::Job(ref job) => {
+          match job.try_result(&self.lua) {
+            Some(Ok(mut values)) => {
+              values.push_front(Value::Boolean(true));
+              self.resume(task.what, task.thread, values, task.done)
+            },
+            Some(Err(err)) => self.resume(task.what, task.thread, (false, err), task.done),
+            None => self.waiting.push(task),
+          }
+        },
+        _ => self.waiting.push(task),
+      }
+    }
+  }
+
+  // Resumes what waits for this device, and what waits for the "hotplug"
+  // event.
+  pub(crate) fn hotplug(&mut self, connected: bool, vendor_id: u16, product_id: u16) {
+    if connected {
+      for task in std::mem::take(&mut self.waiting) {
+        match task.wait {
+          Wait::Hotplug {
+            vendor_id: vid,
+            product_id: pid,
+            ..
+          } if vid.is_none_or(|vid| vid == vendor_id)
+            && pid.is_none_or(|pid| pid == product_id) =>
+          {
+            self.resume(task.what, task.thread, true, task.done)
+          },
+          _ => self.waiting.push(task),
+        }
+      }
+    }
+
+    let name = if connected {
+      "connected"
+    } else {
+      "disconnected"
+    };
+    self.emit("hotplug", (name, vendor_id, product_id));
+  }
+
+  // Resumes everything waiting for `name` with the event's arguments.
+  pub(crate) fn emit(&mut self, name: &str, args: impl IntoLuaMulti) {
+    match args.into_lua_multi(&self.lua) {
+      Ok(args) => self.event(name, args),
+      Err(err) => event!(Level::ERROR, "{} event: {}", name, err),
+    }
+  }
+
+  fn event(&mut self, name: &str, args: MultiValue) {
+    for task in std::mem::take(&mut self.waiting) {
+      match &task.wait {
+        Wait::Event {
+          name: waits_for, ..
+        } if waits_for == name => self.resume(task.what, task.thread, args.clone(), task.done),
+        _ => self.waiting.push(task),
+      }
+    }
+  }
+}
+
+// Hands a finished callback's result to whoever waits for it, or logs the
+// error.
+fn finish(what: &str, done: Option<Done>, result: Result<MultiValue, mlua::Error>) {
+  match (done, result) {
+    (Some(done), result) => done(result),
+    (None, Err(err)) => event!(Level::ERROR, "{} callback: {}", what, err),
+    (None, Ok(_)) => {},
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Stands in for a device.
+  impl mlua::UserData for Shared<u32> {
+    fn add_fields<F: mlua::UserDataFields<Self>>(fields: &mut F) {
+      fields.add_field_method_get("value", |_, this| Ok(*this.lock()));
+    }
+
+    fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+      methods.add_method("add", |_, this, n: u32| -> Result<Job, mlua::Error> {
+        Ok(this.job(move |value| {
+          *value += n;
+          Ok(*value)
+        }))
+      });
+    }
+  }
+
+  // A Lua state whose switch_input runs `work` as its job, with the results
+  // going into `log`.
+  fn lua_with_switch(
+    work: impl Fn() -> Result<&'static str, Box<StdError>> + Clone + Send + 'static,
+  ) -> (Lua, Tasks) {
+    let lua = Lua::new();
+    let switch_input = lua
+      .create_function(move |_, _: Table| -> Result<Job, mlua::Error> {
+        Ok(Job::spawn(work.clone()))
+      })
+      .unwrap();
+    let device_open = lua
+      .create_function(|_, ()| -> Result<Job, mlua::Error> {
+        Ok(Job::spawn(|| Ok(Shared::new(0u32))))
+      })
+      .unwrap();
+    lua.globals().set("switch_input", switch_input).unwrap();
+    lua.globals().set("device_open", device_open).unwrap();
+    lua
+      .load("ddc = { list = function() end }\nlog = {}")
+      .exec()
+      .unwrap();
+    let tasks = Tasks::install(&lua).unwrap();
+    (lua, tasks)
+  }
+
+  fn spawn(lua: &Lua, tasks: &mut Tasks, source: &str) {
+    tasks.spawn(source, lua.load(source).into_function().unwrap(), ());
+  }
+
+  // Polls until `log` has `len` entries.
+  fn poll_until(lua: &Lua, tasks: &mut Tasks, len: usize) -> Vec<String> {
+    let log: Table = lua.globals().get("log").unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while log.raw_len() < len {
+      assert!(Instant::now() < deadline, "the device call never finished");
+      tasks.poll();
+      thread::sleep(Duration::from_millis(10));
+    }
+    log.sequence_values().collect::<Result<_, _>>().unwrap()
+  }
+
+  #[test]
+  fn runs_other_callbacks_during_a_device_call() {
+    let (release, released) = crossbeam_channel::bounded::<()>(1);
+    let (lua, mut tasks) = lua_with_switch(move || {
+      released.recv()?;
+      Ok("usb")
+    });
+
+    spawn(&lua, &mut tasks, "table.insert(log, switch_input({}))");
+    spawn(&lua, &mut tasks, "table.insert(log, 'second')");
+    assert_eq!(poll_until(&lua, &mut tasks, 1), ["second"]);
+
+    release.send(()).unwrap();
+    assert_eq!(poll_until(&lua, &mut tasks, 2), ["second", "usb"]);
+  }
+
+  #[test]
+  fn raises_device_errors_in_the_callback() {
+    let (lua, mut tasks) = lua_with_switch(|| Err("no monitor".into()));
+    spawn(
+      &lua,
+      &mut tasks,
+      "local ok, err = pcall(switch_input, {})\ntable.insert(log, tostring(ok) .. ' ' .. tostring(err))",
+    );
+    assert_eq!(poll_until(&lua, &mut tasks, 1), ["false no monitor"]);
+  }
+
+  #[test]
+  fn waits_for_device_methods() {
+    let (lua, mut tasks) = lua_with_switch(|| Ok("usb"));
+    spawn(
+      &lua,
+      &mut tasks,
+      "local dev = device_open()\ndev:add(2)\ntable.insert(log, dev:add(3) .. ' ' .. dev.value)",
+    );
+    assert_eq!(poll_until(&lua, &mut tasks, 1), ["5 5"]);
+  }
+
+  #[test]
+  fn blocks_outside_of_a_callback() {
+    let (lua, _tasks) = lua_with_switch(|| Ok("usb"));
+    let path: String = lua.load("return switch_input({})").eval().unwrap();
+    assert_eq!(path, "usb");
+  }
+}
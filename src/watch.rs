@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+// How often the files are checked.
+const CHECK_EVERY: Duration = Duration::from_millis(500);
+
+// Watches the script and the modules it required by polling their
+// modification times from the main loop, which is already polling anyway.
+pub(crate) struct Watcher {
+  files: HashMap<PathBuf, Option<SystemTime>>,
+  last_check: Instant,
+  // A file changed at the last check. We wait for a check without changes
+  // before reloading, so we don't load a file the editor is still writing.
+  pending: bool,
+}
+
+impl Watcher {
+  pub(crate) fn new(files: Vec<PathBuf>) -> Self {
+    let mut watcher = Self {
+      files: HashMap::new(),
+      last_check: Instant::now(),
+      pending: false,
+    };
+    watcher.set_files(files);
+    watcher
+  }
+
+  // Replaces what we watch, e.g. after a reload required other modules.
+  pub(crate) fn set_files(&mut self, files: Vec<PathBuf>) {
+    self.files = files
+      .into_iter()
+      .map(|path| {
+        let modified = modified(&path);
+        (path, modified)
+      })
+      .collect();
+    self.pending = false;
+  }
+
+  // Returns true once something changed and has settled.
+  pub(crate) fn poll(&mut self) -> bool {
+    if self.last_check.elapsed() < CHECK_EVERY {
+      return false;
+    }
+    self.last_check = Instant::now();
+
+    let mut changed = false;
+    for (path, last) in &mut self.files {
+      let now = modified(path);
+      if now != *last {
+        *last = now;
+        changed = true;
+      }
+    }
+
+    if changed {
+      self.pending = true;
+      return false;
+    }
+    std::mem::take(&mut self.pending)
+  }
+}
+
+fn modified(path: &PathBuf) -> Option<SystemTime> {
+  std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
@@ -0,0 +1,107 @@
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Child;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+const BIN: &str = env!("CARGO_BIN_EXE_msi-monitor-ctrl");
+
+// The main loop needs a display to start.
+fn has_display() -> bool {
+  !cfg!(target_os = "linux")
+    || std::env::var_os("DISPLAY").is_some()
+    || std::env::var_os("WAYLAND_DISPLAY").is_some()
+}
+
+fn command(dir: &Path) -> Command {
+  let mut cmd = Command::new(BIN);
+  // Keeps the lock, the socket and the log out of the user's own dirs, on
+  // linux at least.
+  cmd
+    .env("XDG_DATA_HOME", dir)
+    .env("XDG_RUNTIME_DIR", dir)
+    .arg("--no-dbus");
+  cmd
+}
+
+fn wait_for(what: &str, mut done: impl FnMut() -> bool) {
+  let deadline = Instant::now() + Duration::from_secs(20);
+  while !done() {
+    assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+    thread::sleep(Duration::from_millis(100));
+  }
+}
+
+// Stops the instance and cleans up after it, even when the test fails.
+struct Running {
+  child: Child,
+  dir: PathBuf,
+}
+
+impl Drop for Running {
+  fn drop(&mut self) {
+    let _ = self.child.kill();
+    let _ = self.child.wait();
+    let _ = fs::remove_dir_all(&self.dir);
+  }
+}
+
+// A second launch of a running script must not run any of it, only ask the
+// running copy to reload.
+#[test]
+fn starts_a_script_once() {
+  if !has_display() {
+    eprintln!("skipped, the main loop needs a display");
+    return;
+  }
+
+  let dir = std::env::temp_dir().join(format!("msi-monitor-ctrl-instance-{}", std::process::id()));
+  fs::create_dir_all(&dir).unwrap();
+  let script = dir.join("init.lua");
+  let runs = dir.join("runs");
+  fs::write(
+    &script,
+    format!(
+      "local file = io.open({:?}, 'a')\nfile:write(os.getenv('LAUNCH'), '\\n')\nfile:close()\nmain_loop()\n",
+      runs.to_string_lossy()
+    ),
+  )
+  .unwrap();
+
+  let first = command(&dir)
+    .arg("--cmd")
+    .arg(&script)
+    .env("LAUNCH", "first")
+    .spawn()
+    .unwrap();
+  let _running = Running {
+    child: first,
+    dir: dir.clone(),
+  };
+  wait_for("the main loop", || {
+    command(&dir)
+      .arg("hotkeys")
+      .output()
+      .is_ok_and(|out| out.status.success())
+  });
+
+  let second = command(&dir)
+    .args(["--if-running", "reload", "--cmd"])
+    .arg(&script)
+    .env("LAUNCH", "second")
+    .output()
+    .unwrap();
+  assert!(
+    second.status.success(),
+    "{}",
+    String::from_utf8_lossy(&second.stderr)
+  );
+
+  // The reload runs the script again, in the first launch.
+  let read_runs = || fs::read_to_string(&runs).unwrap_or_default();
+  wait_for("the reload", || read_runs().lines().count() >= 2);
+  assert_eq!(read_runs(), "first\nfirst\n");
+}